@@ -1,77 +1,431 @@
 use std::path::{Path, PathBuf};
-use crate::toc::TOC;
-use std::fs::OpenOptions;
-use std::io::{Seek, SeekFrom};
+use crate::toc::{TOC, TOC_MAGIC_NUMBER};
+use std::io::{Read, SeekFrom};
+use std::sync::{Mutex, MutexGuard, PoisonError};
 use crate::builder::Builder;
 use crate::entries::Entries;
 use crate::entry::Entry;
-use crate::header::Metadata;
-use crate::error::{Result, Error};
+use crate::handle::EntryHandle;
+use crate::header::{EntryHeader, Content};
+use crate::toc::Blob;
+use std::collections::{HashMap, BTreeSet};
+use crate::crypto::{Encryption, KeyProvider, Recipient, KeyBlock, DataKey, ContentHasher, HASH_SIZE};
+use crate::storage::Storage;
+use crate::volume::Volumes;
+use crate::parity;
+use crate::safepath::SafePathBuf;
+use std::convert::TryFrom;
+use crate::error::{Result, Error, ErrorKind, TocEntryNotFoundReason};
 
-const MAGIC_NUMBER: u128 = 0x169f57e6bbb98f2d139ee9a294f9cd3c;
+pub (in crate) const MAGIC_NUMBER: u128 = 0x169f57e6bbb98f2d139ee9a294f9cd3c;
+
+///Used in place of [`MAGIC_NUMBER`] by encrypted archives, so they are not mistaken for plaintext archives
+pub (in crate) const ENCRYPTED_MAGIC_NUMBER: u128 = 0x7c2e91b04fd35a86e1b9c07d24a6f358;
+
+///Size of the space reserved for the key block after the toc offset of an encrypted archive
+pub (in crate) const KEY_BLOCK_SIZE: u64 = 4096;
+
+///Size of the magic number and toc offset
+const PREAMBLE_SIZE: u64 = 32;
+
+///Stored in place of the toc offset by archives that were written without seeking, see [`crate::stream::StreamBuilder`].
+/// The real toc offset is stored in the trailer at the end of the archive
+pub (in crate) const STREAMED_TOC_OFFSET: u128 = u128::MAX;
+
+///Identifies the trailer at the end of a streamed archive
+pub (in crate) const TRAILER_MAGIC_NUMBER: u128 = 0x5b1e8a0f2c7d4e93a6b0d2c4e8f1a357;
+
+///Size of the trailer at the end of a streamed archive (16 bytes of toc offset, 16 bytes of trailer magic number)
+pub (in crate) const TRAILER_SIZE: u64 = 32;
 
 ///Archive represents an existing tarpdate archive
 ///
 /// With this archive users can append, remove, obtain a list of, remove, read and get the metadata for files.
+///
+/// Archives are usually stored in files on disk, which can be split into several volumes (see [`Volumes`]), but any [`Storage`] can be
+/// used, see [`Archive::create_with`] and [`Archive::open_with`].
+///
+/// Archives can be encrypted, see [`Archive::create_encrypted`], [`Archive::open_encrypted`] and [`Archive::open_keyed`].
 #[derive(Debug)]
-pub struct Archive {
-    path: PathBuf,
+pub struct Archive<S: Storage = Volumes> {
+    pub(in crate) storage: Mutex<S>,
+    path: Option<PathBuf>,
     pub(in crate) toc: TOC,
     pub(in crate) toc_offset: u128,
+    pub(in crate) key: Option<DataKey>,
+    encrypted_metadata: bool,
+
+    //Where new data and tocs are written, which is the end of the toc unless files are being appended. Anything after it (such
+    //as the trailer of a streamed archive) is removed by the next change
+    pub(in crate) data_end: u128,
 }
 
-impl Archive {
+impl Archive<Volumes> {
 
     ///Create a new empty archive
     pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
 
-        let toc = TOC::new();
+        let archive_file = Volumes::create(&path).map_err(|e| Error::from(e).with_archive(path.as_ref()))?;
+
+        let mut archive = Self::create_with(archive_file).map_err(|e| e.with_archive(path.as_ref()))?;
+
+        archive.path = Some(PathBuf::from(path.as_ref()));
+
+        Ok(archive)
+    }
+
+    ///Open an existing archive
+    ///
+    /// The file is opened for reading and writing if possible, otherwise it is opened read only, and any attempt to modify the archive will fail.
+    ///
+    /// Fails with [`ErrorKind::BadMagicNumber`], [`ErrorKind::Truncated`], [`ErrorKind::TocEntryNotFound`] or
+    /// [`ErrorKind::DataPastToc`] if the file is not a valid archive
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+
+        let archive_file = Volumes::open(&path).map_err(|e| Error::from(e).with_archive(path.as_ref()))?;
+
+        let mut archive = Self::open_with(archive_file).map_err(|e| e.with_archive(path.as_ref()))?;
+
+        archive.path = Some(PathBuf::from(path.as_ref()));
+
+        archive.check_volumes().map_err(|e| e.with_archive(path.as_ref()))?;
+
+        Ok(archive)
+
+    }
+
+    ///Create a new empty encrypted archive
+    ///
+    /// See [`Archive::create_encrypted_with`] for more information
+    pub fn create_encrypted<P: AsRef<Path>>(path: P, encryption: &Encryption) -> Result<Self> {
+
+        let archive_file = Volumes::create(&path).map_err(|e| Error::from(e).with_archive(path.as_ref()))?;
 
-        let archive_file = OpenOptions::new().write(true).create(true).open(&path)?;
+        let mut archive = Self::create_encrypted_with(archive_file, encryption).map_err(|e| e.with_archive(path.as_ref()))?;
+
+        archive.path = Some(PathBuf::from(path.as_ref()));
+
+        Ok(archive)
+    }
+
+    ///Open an existing encrypted archive, unlocking it with any of the keys from `keys`
+    ///
+    /// See [`Archive::open_encrypted_with`] for more information
+    pub fn open_encrypted<P: AsRef<Path>, K: KeyProvider + ?Sized>(path: P, keys: &K) -> Result<Self> {
+
+        let archive_file = Volumes::open(&path).map_err(|e| Error::from(e).with_archive(path.as_ref()))?;
+
+        let mut archive = Self::open_encrypted_with(archive_file, keys).map_err(|e| e.with_archive(path.as_ref()))?;
+
+        archive.path = Some(PathBuf::from(path.as_ref()));
+
+        archive.check_volumes().map_err(|e| e.with_archive(path.as_ref()))?;
+
+        Ok(archive)
+    }
+
+    ///Open an existing archive whether or not it is encrypted, asking `keys` for keys only if it is
+    ///
+    /// See [`Archive::open_keyed_with`] for more information
+    pub fn open_keyed<P: AsRef<Path>, K: KeyProvider + ?Sized>(path: P, keys: &K) -> Result<Self> {
+
+        let archive_file = Volumes::open(&path).map_err(|e| Error::from(e).with_archive(path.as_ref()))?;
+
+        let mut archive = Self::open_keyed_with(archive_file, keys).map_err(|e| e.with_archive(path.as_ref()))?;
+
+        archive.path = Some(PathBuf::from(path.as_ref()));
+
+        archive.check_volumes().map_err(|e| e.with_archive(path.as_ref()))?;
+
+        Ok(archive)
+    }
+
+}
+
+impl<S: Storage> Archive<S> {
+
+    ///Create a new empty archive in `storage`, overwriting any existing contents
+    pub fn create_with(mut storage: S) -> Result<Self> {
 
         let toc_offset = 32u128; //The position of the TOC in an empty archive, which is 32 bytes in (16 bytes of magic number, 16 bytes for the stored offset itself)
 
+        storage.set_len(0)?;
+        storage.seek(SeekFrom::Start(0))?;
+
         // Write the magic number to the first 16 bytes
-        bincode::serialize_into(&archive_file, &MAGIC_NUMBER)?;
+        bincode::serialize_into(& mut storage, &MAGIC_NUMBER)?;
+
+        let mut archive = Archive {
+            storage: Mutex::new(storage),
+            path: None,
+            toc: TOC::new(),
+            toc_offset,
+            key: None,
+            encrypted_metadata: false,
+            data_end: toc_offset,
+        };
+
+        //Write the TOC, and the offset for the TOC to the next 16 bytes
+        archive.write_toc_at(toc_offset)?;
 
-        //Write the offset for the TOC to the next 16 bytes
-        bincode::serialize_into(&archive_file, &toc_offset)?;
+        Ok(archive)
+    }
+
+    ///Create a new empty encrypted archive in `storage`, overwriting any existing contents
+    ///
+    /// The data of every file added to the archive is sealed with the cipher chosen in `encryption`, using a random data key
+    /// that can be unlocked with any of the keys in `encryption`. Each file is sealed in chunks with its own key, derived from a
+    /// random salt stored in the header, so files can still be read from any position. The toc and headers are only encrypted
+    /// if [`Encryption::with_encrypted_metadata`] is set.
+    ///
+    /// Fails with [`ErrorKind::KeyBlockFull`] if there are too many keys to store
+    pub fn create_encrypted_with(mut storage: S, encryption: &Encryption) -> Result<Self> {
+
+        let (key_block, key) = KeyBlock::new(encryption)?;
+
+        //Check the keys fit before anything is overwritten
+        let size = bincode::serialized_size(&key_block)?;
+
+        if size > KEY_BLOCK_SIZE {
+            return Err(Error::new(ErrorKind::KeyBlockFull(size as u128), format!("Keys ({} bytes) do not fit in the space reserved for them ({} bytes)", size, KEY_BLOCK_SIZE)));
+        }
+
+        let toc_offset = (PREAMBLE_SIZE + KEY_BLOCK_SIZE) as u128;
+
+        storage.set_len(0)?;
+        storage.seek(SeekFrom::Start(0))?;
+
+        bincode::serialize_into(& mut storage, &ENCRYPTED_MAGIC_NUMBER)?;
+
+        bincode::serialize_into(& mut storage, &toc_offset)?;
+
+        Self::write_key_block(& mut storage, &key_block)?;
+
+        let mut archive = Archive {
+            storage: Mutex::new(storage),
+            path: None,
+            toc: TOC::new(),
+            toc_offset,
+            key: Some(key),
+            encrypted_metadata: encryption.encrypted_metadata(),
+            data_end: toc_offset,
+        };
+
+        //The toc may need to be sealed, so it is written the same way as any other toc
+        archive.write_toc_at(toc_offset)?;
+
+        Ok(archive)
+    }
+
+    ///Open an existing archive stored in `storage`
+    ///
+    /// See [`Archive::open`] for possible errors. Fails with [`ErrorKind::KeyRequired`] if the archive is encrypted
+    pub fn open_with(mut storage: S) -> Result<Self> {
+
+        if Self::read_magic_number(& mut storage)? {
+            return Err(Error::new(ErrorKind::KeyRequired, String::from("Archive is encrypted, a key is required to open it")));
+        }
 
-        //Write the TOC
-        bincode::serialize_into(&archive_file, &toc)?;
+        let (toc, toc_offset, data_end) = Self::fetch_toc(& mut storage, None)?;
 
         Ok(Archive {
-            path: PathBuf::from(path.as_ref()),
+            storage: Mutex::new(storage),
+            path: None,
             toc,
             toc_offset,
+            key: None,
+            encrypted_metadata: false,
+            data_end,
         })
-    }
 
-    ///Open an existing archive
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
-
-        let mut archive_file = OpenOptions::new().read(true).open(path.as_ref())?;
+    }
 
-        let length = archive_file.metadata()?.len();
+    ///Open an existing encrypted archive stored in `storage`, unlocking it with any of the keys from `keys`
+    ///
+    /// Fails with [`ErrorKind::WrongKey`] if the archive cannot be unlocked with any of the keys, or [`ErrorKind::NotEncrypted`] if the
+    /// archive is not encrypted. See [`Archive::open`] for other possible errors.
+    ///
+    /// Reading a file whose data has been modified fails with [`ErrorKind::AuthenticationFailed`], as does opening an archive
+    /// with encrypted metadata whose toc has been modified
+    pub fn open_encrypted_with<K: KeyProvider + ?Sized>(mut storage: S, keys: &K) -> Result<Self> {
 
-        let (toc, toc_offset) = Self::fetch_toc(path.as_ref())?;
+        let (key, encrypted_metadata) = Self::unlock(& mut storage, keys)?;
 
-        if archive_file.stream_position()? < length {
-            //There is data past the end of the contents table, help! This is not an error, it should just be a warning
-            panic!("Data past end of TOC")
-        }
+        let (toc, toc_offset, data_end) = Self::fetch_toc(& mut storage, if encrypted_metadata { Some(&key) } else { None })?;
 
         Ok(Archive {
-            path: PathBuf::from(path.as_ref()),
+            storage: Mutex::new(storage),
+            path: None,
             toc,
             toc_offset,
+            key: Some(key),
+            encrypted_metadata,
+            data_end,
         })
+    }
+
+    ///Open an existing archive stored in `storage` whether or not it is encrypted, asking `keys` for keys only if it is
+    ///
+    /// See [`Archive::open_with`] and [`Archive::open_encrypted_with`] for possible errors
+    pub fn open_keyed_with<K: KeyProvider + ?Sized>(mut storage: S, keys: &K) -> Result<Self> {
+
+        if Self::read_magic_number(& mut storage)? {
+            Self::open_encrypted_with(storage, keys)
+        } else {
+            Self::open_with(storage)
+        }
+    }
+
+    ///Read the key block of an encrypted archive and unlock the data key with `keys`, returning the data key and whether the metadata is encrypted
+    fn unlock<K: KeyProvider + ?Sized>(storage: & mut S, keys: &K) -> Result<(DataKey, bool)> {
 
+        let key_block = Self::read_key_block(storage)?;
+
+        Ok((key_block.unlock(&keys.keys()?)?, key_block.encrypted_metadata))
     }
 
-    ///Return the path of the archive
-    pub fn path(&self) -> &Path {
-        self.path.as_path()
+    ///Read the key block, failing with [`ErrorKind::NotEncrypted`] if the archive is not encrypted
+    fn read_key_block(storage: & mut S) -> Result<KeyBlock> {
+
+        if !Self::read_magic_number(& mut *storage)? {
+            return Err(Error::new(ErrorKind::NotEncrypted, String::from("Archive is not encrypted")));
+        }
+
+        storage.seek(SeekFrom::Start(PREAMBLE_SIZE))?;
+
+        bincode::deserialize_from(& mut *storage).map_err(|e| Error::from(e).with_offset(PREAMBLE_SIZE as u128))
+    }
+
+    ///Write the key block after the toc offset, padded to the reserved size
+    ///
+    /// Fails with [`ErrorKind::KeyBlockFull`] if it does not fit, in which case nothing is written
+    fn write_key_block(storage: & mut S, key_block: &KeyBlock) -> Result<()> {
+
+        let key_block = bincode::serialize(key_block)?;
+
+        if key_block.len() as u64 > KEY_BLOCK_SIZE {
+            return Err(Error::new(ErrorKind::KeyBlockFull(key_block.len() as u128), format!("Keys ({} bytes) do not fit in the space reserved for them ({} bytes)", key_block.len(), KEY_BLOCK_SIZE)));
+        }
+
+        storage.seek(SeekFrom::Start(PREAMBLE_SIZE))?;
+
+        storage.write_all(&key_block)?;
+        storage.write_all(&vec![0; KEY_BLOCK_SIZE as usize - key_block.len()])?;
+
+        storage.flush()?;
+
+        Ok(())
+    }
+
+    ///Get every recipient whose identity can unlock the archive
+    ///
+    /// Fails with [`ErrorKind::NotEncrypted`] if the archive is not encrypted
+    pub fn recipients(&self) -> Result<Vec<Recipient>> {
+        Self::read_key_block(& mut *self.storage()).map(|key_block| key_block.recipients()).map_err(|e| self.error_context(e))
+    }
+
+    ///Allow the identity of `recipient` to unlock the archive, without encrypting the data again
+    ///
+    /// Nothing changes if the recipient can already unlock the archive. Fails with [`ErrorKind::NotEncrypted`] if the archive
+    /// is not encrypted, or [`ErrorKind::KeyBlockFull`] if there is no space left for another key.
+    pub fn add_recipient(& mut self, recipient: &Recipient) -> Result<()> {
+        self.update_key_block(|key_block, data_key| key_block.add_recipient(recipient, data_key)).map_err(|e| self.error_context(e))
+    }
+
+    ///Stop the identity of `recipient` from unlocking the archive, without encrypting the data again
+    ///
+    /// Anyone who could unlock the archive before may have kept the data key, so this only keeps the recipient out of future copies
+    /// of the archive. Fails with [`ErrorKind::RecipientNotFound`] if the recipient cannot unlock the archive, or [`ErrorKind::LastKey`]
+    /// if nothing else could unlock it.
+    pub fn remove_recipient(& mut self, recipient: &Recipient) -> Result<()> {
+        self.update_key_block(|key_block, _| key_block.remove_recipient(recipient)).map_err(|e| self.error_context(e))
+    }
+
+    ///Read the key block, change it with `f` and write it back in place
+    fn update_key_block<F: FnOnce(& mut KeyBlock, &DataKey) -> Result<()>>(& mut self, f: F) -> Result<()> {
+
+        let data_key = match &self.key {
+            Some(key) => key.clone(),
+            None => return Err(Error::new(ErrorKind::NotEncrypted, String::from("Archive is not encrypted"))),
+        };
+
+        let mut key_block = Self::read_key_block(self.storage_mut())?;
+
+        f(& mut key_block, &data_key)?;
+
+        self.drop_parity()?;
+
+        Self::write_key_block(self.storage_mut(), &key_block)
+    }
+
+    ///Check the magic number at the start of the archive, returning true if the archive is encrypted
+    fn read_magic_number(storage: & mut S) -> Result<bool> {
+
+        let length = storage.seek(SeekFrom::End(0))?;
+
+        if length < PREAMBLE_SIZE {
+            return Err(Error::new(ErrorKind::Truncated(length as u128), format!("Archive ({} bytes) is too small to contain a magic number and toc offset", length)));
+        }
+
+        storage.seek(SeekFrom::Start(0))?;
+
+        let magic_number: u128 = bincode::deserialize_from(& mut *storage)?;
+
+        match magic_number {
+            MAGIC_NUMBER => Ok(false),
+            ENCRYPTED_MAGIC_NUMBER if length < PREAMBLE_SIZE + KEY_BLOCK_SIZE => Err(Error::new(ErrorKind::Truncated(length as u128), format!("Encrypted archive ({} bytes) is too small to contain a key block", length))),
+            ENCRYPTED_MAGIC_NUMBER => Ok(true),
+            _ => Err(Error::new(ErrorKind::BadMagicNumber(magic_number), format!("Magic number ({:#x}) does not match, file is not a tarpdate archive", magic_number))),
+        }
+    }
+
+    ///The position of the first header, after the key block if the archive is encrypted
+    pub fn data_start(&self) -> u128 {
+        data_start(self.key.is_some())
+    }
+
+    ///Returns true if the archive is encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.key.is_some()
+    }
+
+    ///Returns true if the toc and headers are encrypted as well as the file data
+    pub fn is_metadata_encrypted(&self) -> bool {
+        self.encrypted_metadata
+    }
+
+    ///The key that the toc and headers are sealed with, if the metadata is encrypted
+    pub (in crate) fn metadata_key(&self) -> Option<&DataKey> {
+        self.key.as_ref().filter(|_| self.encrypted_metadata)
+    }
+
+    ///Consume the archive, returning the underlying storage
+    pub fn into_inner(self) -> S {
+        self.storage.into_inner().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    ///Return the path of the archive, if it is stored in a file opened with [`Archive::create`] or [`Archive::open`]
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    ///Lock the storage for reading
+    ///
+    /// Every user of the storage seeks before reading, so the storage is still usable if a previous holder panicked.
+    pub (in crate) fn storage(&self) -> MutexGuard<'_, S> {
+        self.storage.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub (in crate) fn storage_mut(& mut self) -> & mut S {
+        self.storage.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    ///Attach the archive path (if known) to an error
+    pub (in crate) fn error_context(&self, e: Error) -> Error {
+        match &self.path {
+            Some(path) => e.with_archive(path),
+            None => e,
+        }
     }
 
     ///Return the location of the TOC
@@ -81,14 +435,15 @@ impl Archive {
 
     ///Return a builder object for the current archive that adds files to the archive.
     ///
-    /// Since the toc is removed from the file and stored in memory by this function, and not returned to the file until [`Builder::finalise`] is called,
-    /// if the application panics or otherwise fails during this time, the toc will be lost.
+    /// Files are appended after the toc, and the new toc is not written until [`Builder::finalise`] is called. If the application panics
+    /// or otherwise fails during this time, the archive cannot be opened, as there is data after the toc. The toc of the previous
+    /// generation is still in place, so the archive can be recovered with [`Archive::repair`].
     ///
     /// To avoid this, from the moment this function is called, to the moment that  [`Builder::finalise`] is called, as little should be done as possible
     /// to minimise the chances of a panic.
     ///
     /// See [`Builder`] for more information
-    pub fn builder(& mut self) -> Result<Builder> {
+    pub fn builder(& mut self) -> Result<Builder<'_, S>> {
 
         Builder::new(self)
 
@@ -104,121 +459,763 @@ impl Archive {
     ///Return an iterator over all the active entries in the archive
     ///
     /// See [`Entries`] for more information
-    pub fn iter(&self) -> Entries {
+    pub fn iter(&self) -> Entries<'_, S> {
         Entries::new(self)
     }
 
+    ///Returns true if the archive contains an entry with the given path
+    pub fn contains<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.table()._table.contains_key(path.as_ref())
+    }
+
     ///Get a specific entry in the archive by path
-    pub fn get<'b>(&self, path: & 'b Path) -> Entry<'b> {
+    ///
+    /// Returns `Ok(None)` if no entry exists with the given path
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Result<Option<Entry<'_, S>>> {
 
-        let header_offset = self.toc._table.get(path).unwrap();
+        match self.toc._table.get_key_value(path.as_ref()) {
+            Some((path, header_offset)) => Ok(Some(Entry::new(path.as_path(), *header_offset, self)?)),
+            None => Ok(None),
+        }
+    }
 
-        Entry::new(path, *header_offset, self)
+    ///Get an owned handle to a specific entry in the archive by path
+    ///
+    /// Returns `Ok(None)` if no entry exists with the given path. See [`EntryHandle`] for more information
+    pub fn handle<P: AsRef<Path>>(&self, path: P) -> Result<Option<EntryHandle>> {
+        Ok(self.get(path)?.map(|entry| entry.handle()))
     }
 
-    fn fetch_toc<P: AsRef<Path>>(path: P) -> Result<(TOC, u128)> {
-        let mut archive_file = OpenOptions::new().read(true).open(&path)?;
+    ///Read the toc offset and the toc, opening the toc with `metadata_key` if it is sealed. Returns the toc, its offset and its end
+    fn fetch_toc(storage: & mut S, metadata_key: Option<&DataKey>) -> Result<(TOC, u128, u128)> {
 
-        let magic_number: u128 = bincode::deserialize_from(&archive_file)?;
+        //The magic number has already been checked
+        let length = storage.seek(SeekFrom::End(0))?;
 
-        if magic_number != MAGIC_NUMBER {
-            panic!("Bad magic number");
+        storage.seek(SeekFrom::Start(16))?;
+
+        let mut toc_offset: u128 = bincode::deserialize_from(& mut *storage)?;
+
+        //The end of the toc, which is the end of the archive unless there is a trailer or embedded parity
+        let mut toc_end = length;
+
+        if let Some(parity_offset) = parity::embedded_offset(storage, length)? {
+            toc_end = parity_offset;
         }
 
-        let toc_offset: u128 = bincode::deserialize_from(&archive_file)?;
+        if toc_offset == STREAMED_TOC_OFFSET {
+
+            if length < PREAMBLE_SIZE + TRAILER_SIZE {
+                return Err(Error::new(ErrorKind::Truncated(length as u128), format!("Streamed archive ({} bytes) is too small to contain a trailer", length)));
+            }
+
+            toc_end = length - TRAILER_SIZE;
 
-        archive_file.seek(SeekFrom::Start(toc_offset as u64))?;
+            storage.seek(SeekFrom::Start(toc_end))?;
 
-        Ok((bincode::deserialize_from(&archive_file)?, toc_offset))
+            toc_offset = bincode::deserialize_from(& mut *storage)?;
+
+            let trailer_magic_number: u128 = bincode::deserialize_from(& mut *storage)?;
+
+            if trailer_magic_number != TRAILER_MAGIC_NUMBER {
+                return Err(Error::new(ErrorKind::TocEntryNotFound(TocEntryNotFoundReason::BadTrailer(trailer_magic_number)), format!("Trailer magic number ({:#x}) does not match, streamed archive may be incomplete", trailer_magic_number)).with_offset(toc_end as u128 + 16));
+            }
+        }
+
+        if toc_offset >= toc_end as u128 {
+            return Err(Error::new(ErrorKind::TocEntryNotFound(TocEntryNotFoundReason::TocOffsetPastEOF(toc_offset, length as u128)), format!("Toc offset ({}) is past the end of the archive ({} bytes)", toc_offset, length)).with_offset(16));
+        }
+
+        storage.seek(SeekFrom::Start(toc_offset as u64))?;
+
+        let toc = TOC::read_from(& mut *storage, metadata_key, toc_end - toc_offset as u64).map_err(|e| e.with_offset(toc_offset))?;
+
+        let end = storage.stream_position()?;
+
+        if end < toc_end {
+            return Err(Error::new(ErrorKind::DataPastToc(end as u128, length as u128), format!("Found {} bytes of data past the end of the toc", toc_end - end)).with_offset(end as u128));
+        }
+
+        Ok((toc, toc_offset, end as u128))
+    }
+
+    ///Read the toc at `offset`, such as the toc of an earlier generation
+    pub (in crate) fn read_toc_at(&self, offset: u128) -> Result<TOC> {
+
+        let mut storage = self.storage();
+
+        let length = storage.seek(SeekFrom::End(0))?;
+
+        storage.seek(SeekFrom::Start(offset as u64))?;
+
+        TOC::read_from(& mut *storage, self.metadata_key(), length.saturating_sub(offset as u64)).map_err(|e| e.with_offset(offset))
+    }
+
+    ///Create an archive that reads from `storage` with a different toc, sharing the keys of this archive
+    pub (in crate) fn view<V: Storage>(&self, storage: V, toc: TOC, toc_offset: u128) -> Archive<V> {
+        Archive {
+            storage: Mutex::new(storage),
+            path: self.path.clone(),
+            toc,
+            toc_offset,
+            key: self.key.clone(),
+            encrypted_metadata: self.encrypted_metadata,
+            data_end: toc_offset,
+        }
+    }
+
+    ///Get the content hash of the file `handle`, keyed like the hashes in the toc if the archive is encrypted
+    ///
+    /// The hash is stored in the header of files that are not empty or chunked, so only the data of those files is read
+    pub (in crate) fn content_hash(&self, handle: &EntryHandle) -> Result<[u8; HASH_SIZE]> {
+        match handle.content() {
+            Content::Blob(hash) | Content::Shared(hash) => Ok(*hash),
+            _ => ContentHasher::hash_reader(self.key.as_ref(), & mut handle.open(self)?),
+        }
     }
 
     ///Remove an entry from the toc
     ///
     /// This function will only remove the entry from the toc, it will not remove the file data from the archive.
-    /// To do this, call [`Archive::defrag`]
+    /// To do this, call [`Archive::defrag`]. Any signature is removed, see [`Archive::sign`]
+    ///
+    /// Fails with [`ErrorKind::NotFound`] if no entry exists with the given path
     pub fn remove<P: AsRef<Path>>(& mut self, path: P) -> Result<()> {
 
-        self.toc._table.remove(path.as_ref()).unwrap();
+        self.remove_entry(path.as_ref()).map_err(|e| self.error_context(e))?;
+
+        self.write_toc()
+    }
 
-        {
-            let archive_file = OpenOptions::new().write(true).open(&self.path)?;
+    ///Remove `path` from the in-memory toc, without writing a new generation
+    pub (in crate) fn remove_entry(& mut self, path: &Path) -> Result<()> {
 
-            archive_file.set_len(self.toc_offset as u64)?;
+        if !self.release(path) {
+            return Err(Error::new(ErrorKind::NotFound(PathBuf::from(path)), format!("Could not remove entry ({}), as path does not exist in TOC", path.display())));
         }
 
-        let archive_file = OpenOptions::new().write(true).append(true).open(&self.path)?;
+        Ok(())
+    }
+
+    ///Remove `path` from the in-memory toc, dropping its reference to any shared data. Returns false if the path does not exist
+    pub (in crate) fn release(& mut self, path: &Path) -> bool {
+
+        let offset = match self.toc._table.remove(path) {
+            Some(offset) => offset,
+            None => return false,
+        };
 
-        bincode::serialize_into(&archive_file, &self.toc)?;
+        self.release_header(offset);
+
+        true
+    }
+
+    ///Drop the references that the header at `offset` holds to shared data, once its path has been taken out of the in-memory toc
+    pub (in crate) fn release_header(& mut self, offset: u128) {
+
+        let metadata_key = self.metadata_key().cloned();
+
+        //If the header cannot be read, the reference is kept, so shared data is never dropped by mistake
+        let uses = read_header_at(self.storage.get_mut().unwrap_or_else(PoisonError::into_inner), offset, metadata_key.as_ref()).map(|header| header.content.uses()).unwrap_or_default();
+
+        for hash in uses {
+            if let Some(blob) = self.toc.blobs.get_mut(&hash) {
+                blob.refs = blob.refs.saturating_sub(1);
+
+                if blob.refs == 0 {
+                    self.toc.blobs.remove(&hash);
+                }
+            }
+        }
+
+        //The signature no longer matches the toc
+        self.toc.signature = None;
+    }
+
+    ///Write the in-memory toc as a new generation after any data that has been appended, and point the stored toc offset at it
+    ///
+    /// The toc of the previous generation is left in place, and linked from the new toc, see [`crate::generation::Generation`]
+    pub (in crate) fn write_toc(& mut self) -> Result<()> {
+
+        self.toc.previous = Some(self.toc_offset);
+        self.toc.generation = self.toc.generation.next();
+
+        self.write_toc_at(self.data_end).map_err(|e| self.error_context(e))
+    }
+
+    ///Overwrite the toc of the current generation with the in-memory toc, without creating a new generation
+    pub (in crate) fn rewrite_toc(& mut self) -> Result<()> {
+        self.write_toc_at(self.toc_offset).map_err(|e| self.error_context(e))
+    }
+
+    ///Write the in-memory toc at `offset`, and point the stored toc offset at it
+    ///
+    /// Anything after `offset` (such as the trailer of a streamed archive) is removed
+    fn write_toc_at(& mut self, offset: u128) -> Result<()> {
+
+        let metadata_key = self.metadata_key().cloned();
+
+        let storage = self.storage.get_mut().unwrap_or_else(PoisonError::into_inner);
+
+        storage.set_len(offset as u64)?;
+        storage.seek(SeekFrom::Start(offset as u64))?;
+
+        self.toc.record_locations();
+
+        self.toc.write_to(& mut *storage, metadata_key.as_ref())?;
+
+        self.data_end = storage.stream_position()? as u128;
+
+        //Write offset to toc offset at beginning
+        storage.seek(SeekFrom::Start(16))?;
+
+        bincode::serialize_into(& mut *storage, &offset)?;
+
+        storage.flush()?;
+
+        self.toc_offset = offset;
+
+        Ok(())
+    }
+
+    ///Rename an entry
+    ///
+    /// Since the path is also stored in the header, the header and data are copied to the end of the archive with the new path,
+    /// and the old copy is left in place until [`Archive::defrag`] is called. Any signature is removed, see [`Archive::sign`]
+    ///
+    /// Fails with [`ErrorKind::NotFound`] if `from` does not exist, or [`ErrorKind::PathConflict`] if `to` already exists
+    pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(& mut self, from: P, to: Q) -> Result<()> {
+
+        self.rename_entry(from.as_ref(), to.as_ref()).map_err(|e| self.error_context(e.with_entry(from)))?;
+
+        self.write_toc()
+    }
+
+    ///Copy the entry `from` to the end of the archive with the path `to`, and update the in-memory toc, without writing a new generation
+    pub (in crate) fn rename_entry(& mut self, from: &Path, to: &Path) -> Result<()> {
+
+        if self.contains(to) {
+            return Err(Error::new(ErrorKind::PathConflict(PathBuf::from(to)), format!("Could not rename entry to the chosen path ({}), as path already exists in TOC", to.display())));
+        }
+
+        let to = SafePathBuf::try_from(to)?;
+
+        let handle = match self.handle(from)? {
+            Some(handle) => handle,
+            None => return Err(Error::new(ErrorKind::NotFound(PathBuf::from(from)), format!("Could not rename entry ({}), as path does not exist in TOC", from.display()))),
+        };
+
+        //Shared and chunked data stays where it is, so only the header is copied
+        let length = if handle.is_shared() { 0 } else { handle.data_len() as u64 };
+
+        let file_offset = handle.file_offset();
+
+        let header_offset = self.data_end as u64;
+
+        let metadata_key = self.metadata_key().cloned();
+
+        let storage = self.storage.get_mut().unwrap_or_else(PoisonError::into_inner);
+
+        //Write the new header after the toc, followed by a copy of the data
+        storage.set_len(header_offset)?;
+        storage.seek(SeekFrom::Start(header_offset))?;
+
+        //The data is copied as is, so encrypted data keeps its salt
+        EntryHeader::new(to.clone(), handle.header().clone(), handle.salt(), handle.content().clone()).write_to(& mut *storage, metadata_key.as_ref())?;
+
+        let data_offset = storage.stream_position()?;
+
+        copy_within(storage, file_offset as u64, data_offset, length)?;
+
+        self.toc._table.remove(from);
+        self.toc._table.insert(to, header_offset as u128);
+
+        //Files that share the data now find it after the new header
+        if let Content::Blob(hash) = handle.content() {
+            if let Some(blob) = self.toc.blobs.get_mut(hash).filter(|blob| blob.header == handle.header_offset()) {
+                blob.header = header_offset as u128;
+            }
+        }
+
+        self.toc.signature = None;
+
+        self.data_end = (data_offset + length) as u128;
+
+        Ok(())
+    }
+
+    ///Move the data in the archive forward to fill the gaps left by deleted and renamed files
+    ///
+    /// Data shared by files with the same contents is kept as long as any file in the toc uses it, even if the file it was
+    /// first stored with has been removed.
+    ///
+    /// The history of the archive is discarded, keeping only the current generation, so earlier generations can no longer be
+    /// opened and removed files can no longer be undeleted. Defragmenting does not create a new generation. To keep some of the
+    /// history, see [`Archive::prune`].
+    ///
+    /// Any [`EntryHandle`] obtained before defragmenting is invalidated. If the application fails while defragmenting, the archive
+    /// can be recovered with [`Archive::repair`].
+    pub fn defrag(& mut self) -> Result<()> {
+        self.defrag_entries().map_err(|e| self.error_context(e))
+    }
+
+    fn defrag_entries(& mut self) -> Result<()> {
+
+        let compaction = self.compaction(Vec::new())?;
+
+        self.compact(compaction)
+    }
+
+    ///Plan how to move the data in the archive forward, keeping the tocs of the earlier generations in `history`
+    ///
+    /// `history` is in the order returned by [`Archive::generations`], newest first, and the current toc is always kept
+    pub (in crate) fn compaction(&self, mut history: Vec<TOC>) -> Result<Compaction> {
+
+        let blocks = self.live_blocks(&history)?;
+
+        let mut position = self.data_start() as u64;
+
+        //The new location of each block, by its old location
+        let mut moved = HashMap::new();
+
+        let mut moves = Vec::new();
+
+        for (start, end) in blocks {
+            moved.insert(start as u128, position as u128);
+            moves.push((start, end, position));
+
+            position += end - start;
+        }
+
+        let mut position = position as u128;
+
+        let mut previous = None;
+
+        let mut tocs = Vec::new();
+
+        //The tocs follow the data, oldest first, each linked to the one before it
+        history.reverse();
+
+        for mut toc in history.into_iter().chain(std::iter::once(self.toc.clone())) {
+            for offset in toc._table.values_mut().chain(toc.blobs.values_mut().map(|blob| & mut blob.header)) {
+                *offset = moved[offset];
+            }
+
+            toc.previous = previous;
+
+            toc.record_locations();
+
+            let mut bytes = Vec::new();
+
+            toc.write_to(& mut bytes, self.metadata_key())?;
+
+            let length = bytes.len() as u128;
+
+            tocs.push((position, toc, bytes));
+
+            previous = Some(position);
+            position += length;
+        }
+
+        //The current toc is written last, as it was chained onto the end
+        let (toc_offset, toc, _) = tocs.pop().expect("the current toc is always kept");
+
+        let history = tocs.into_iter().map(|(offset, _, bytes)| (offset, bytes)).collect();
+
+        Ok(Compaction {
+            blocks: moves,
+            history,
+            toc,
+            toc_offset,
+            end: position,
+        })
+    }
+
+    ///Move the data and write the tocs as planned by [`Archive::compaction`]
+    pub (in crate) fn compact(& mut self, compaction: Compaction) -> Result<()> {
+
+        let storage = self.storage.get_mut().unwrap_or_else(PoisonError::into_inner);
+
+        for (start, end, destination) in compaction.blocks {
+
+            //Each block only ever moves towards the start of the archive, so copying from the front is safe
+            if start != destination {
+                copy_within(storage, start, destination, end - start)?;
+            }
+        }
+
+        //The earlier tocs are never larger than they were, so they cannot overwrite the current toc before it is replaced
+        for (offset, toc) in compaction.history {
+            storage.seek(SeekFrom::Start(offset as u64))?;
+            storage.write_all(&toc)?;
+        }
+
+        self.toc = compaction.toc;
+
+        self.write_toc_at(compaction.toc_offset)
+    }
+
+    ///Get the number of bytes that [`Archive::defrag`] would reclaim, including the tocs of earlier generations
+    pub fn reclaimable(&self) -> Result<u128> {
+
+        let used = self.live_blocks(&[]).map_err(|e| self.error_context(e))?.iter().map(|(start, end)| (end - start) as u128).sum::<u128>();
+
+        Ok(self.toc_offset - self.data_start() - used)
+    }
+
+    ///Find the start and end of every header (and the data that follows it) that is still needed by the current toc or any toc
+    /// in `history`, in the order they appear in the archive
+    ///
+    /// As well as every file in the tocs, this includes the headers that shared data follows
+    fn live_blocks(&self, history: &[TOC]) -> Result<Vec<(u64, u64)>> {
+
+        let starts = std::iter::once(&self.toc).chain(history)
+            .flat_map(|toc| toc._table.values().chain(toc.blobs.values().map(|blob| &blob.header)))
+            .copied()
+            .collect::<BTreeSet<_>>();
+
+        let mut storage = self.storage();
+
+        let mut blocks = Vec::new();
+
+        for start in starts {
+            let header = read_header_at(& mut *storage, start, self.metadata_key()).map_err(|e| e.with_offset(start))?;
+
+            let end = storage.stream_position()? + header.data_len() as u64;
+
+            blocks.push((start as u64, end));
+        }
+
+        Ok(blocks)
+    }
+
+    ///Check the integrity of every entry in the archive
+    ///
+    /// Each header is read and checked against the toc, and the data for each file is read in full. The data of each file (or each
+    /// chunk of a chunked file) is hashed and checked against the content hash in its header, failing with
+    /// [`ErrorKind::ContentHashMismatch`] if it does not match.
+    pub fn verify(&self) -> Result<()> {
+
+        for entry in self.iter() {
+            let mut entry = entry?;
+
+            self.verify_entry(& mut entry).map_err(|e| self.error_context(e.with_entry(entry.path())))?;
+        }
 
         Ok(())
     }
 
-    ///Move the data in the archive forward to fill the gaps left by deleted files
-    pub fn defrag(&self) {
+    fn verify_entry(&self, entry: & mut Entry<'_, S>) -> Result<()> {
+
+        //The length of data each hash covers, in order
+        let hashes = match entry.content() {
+            Content::Blob(hash) | Content::Shared(hash) => vec![(*hash, u64::MAX)],
+            Content::Chunked(chunks) => chunks.iter().map(|chunk| (chunk.hash, chunk.len)).collect(),
+            Content::Inline | Content::Chunk(_) => Vec::new(),
+        };
+
+        for (hash, len) in hashes {
+            if ContentHasher::hash_reader(self.key.as_ref(), & mut (& mut *entry).take(len))? != hash {
+                return Err(Error::new(ErrorKind::ContentHashMismatch(hash), String::from("File data does not match the content hash in its header")));
+            }
+        }
+
+        std::io::copy(entry, & mut std::io::sink())?;
 
+        Ok(())
     }
 
-    ///Open the archive at the given path, and see if the toc can be read. If it cant (either because the offset is past EOF or deserialisation of toc fails)
-    fn test() {
+    ///Walk the archive and create a new toc from the paths stored in each header
+    ///
+    /// Used to recover an archive whose toc is missing or corrupt. Since removing files only changes the toc, files that were removed
+    /// but not defragmented will reappear. If two headers contain the same path, the later one is used.
+    ///
+    /// If parity is embedded in the archive (see [`Archive::embed_parity`]), damaged byte ranges are reconstructed from it first, failing
+    /// with [`ErrorKind::Unrepairable`] if there is too much damage.
+    ///
+    /// Fails with [`ErrorKind::KeyRequired`] if the archive is encrypted, see [`Archive::repair_encrypted_with`]
+    pub fn repair_with(mut storage: S) -> Result<Self> {
+
+        parity::repair_embedded(& mut storage)?;
 
+        if Self::read_magic_number(& mut storage)? {
+            return Err(Error::new(ErrorKind::KeyRequired, String::from("Archive is encrypted, a key is required to repair it")));
+        }
+
+        Self::rebuild_toc(storage, None, false)
     }
 
-    ///Walk the archive and create a new toc (with dummy paths)
-    pub fn repair(&self) {
+    ///Walk an encrypted archive and create a new toc from the paths stored in each header, unlocking it with any of the keys from `keys`
+    ///
+    /// See [`Archive::repair_with`] for more information
+    pub fn repair_encrypted_with<K: KeyProvider + ?Sized>(mut storage: S, keys: &K) -> Result<Self> {
+
+        parity::repair_embedded(& mut storage)?;
 
+        let (key, encrypted_metadata) = Self::unlock(& mut storage, keys)?;
+
+        Self::rebuild_toc(storage, Some(key), encrypted_metadata)
+    }
+
+    fn rebuild_toc(mut storage: S, key: Option<DataKey>, encrypted_metadata: bool) -> Result<Self> {
+
+        let length = storage.seek(SeekFrom::End(0))?;
+
+        let mut toc = TOC::new();
+
+        let metadata_key = key.as_ref().filter(|_| encrypted_metadata);
+
+        let mut contents = HashMap::new();
+
+        let walked = Self::walk_storage(& mut storage, data_start(key.is_some()), length, metadata_key)?;
+
+        //The new toc follows the last toc that was found, keeping the history of the archive
+        if let Some((offset, last)) = walked.last_toc {
+            toc.generation = last.generation.next();
+            toc.previous = Some(offset);
+        }
+
+        for (header_offset, header, _) in walked.headers {
+            if let Content::Blob(hash) | Content::Chunk(hash) = header.content {
+                toc.blobs.insert(hash, Blob {
+                    header: header_offset,
+                    refs: 0,
+                });
+            }
+
+            //Chunks are only found through the files that use them
+            if let Content::Chunk(_) = header.content {
+                continue;
+            }
+
+            contents.insert(header.path.clone(), header.content);
+
+            toc._table.insert(header.path, header_offset);
+        }
+
+        //Count the files that use each piece of shared data, and drop any file whose shared data was not found
+        for (path, content) in contents {
+            let uses = content.uses();
+
+            if uses.iter().any(|hash| !toc.blobs.contains_key(hash)) {
+                toc._table.remove(&path);
+
+                continue;
+            }
+
+            for hash in uses {
+                if let Some(blob) = toc.blobs.get_mut(&hash) {
+                    blob.refs += 1;
+                }
+            }
+        }
+
+        toc.blobs.retain(|_, blob| blob.refs > 0);
+
+        let mut archive = Archive {
+            storage: Mutex::new(storage),
+            path: None,
+            toc,
+            toc_offset: walked.end,
+            key,
+            encrypted_metadata,
+            data_end: walked.end,
+        };
+
+        archive.write_toc_at(walked.end)?;
+
+        Ok(archive)
     }
 
     ///Walk the archive the old fashioned way
+    ///
+    /// Returns the offset of every header in the archive, including files that have been removed from the toc
     pub fn walk(&self) -> Result<Vec<u128>> {
-        let mut offsets = Vec::new();
 
-        let mut archive_file = OpenOptions::new().read(true).open(&self.path)?;
+        let mut storage = self.storage();
 
-        let archive_length = archive_file.metadata()?.len();
+        let archive_length = storage.seek(SeekFrom::End(0)).map_err(|e| self.error_context(Error::from(e)))?;
 
-        archive_file.seek(SeekFrom::Start(32))?;
+        let walked = Self::walk_storage(& mut *storage, self.data_start(), archive_length, self.metadata_key()).map_err(|e| self.error_context(e))?;
 
-        //Iterate over each header until we cannot serialise anymore, or we serialise a file length outside the archive
-        loop {
+        Ok(walked.headers.into_iter().map(|(offset, _, _)| offset).collect())
+    }
+
+    ///Read every header from the start of the archive, returning the offset of each header, the header and the end of the file data
+    ///
+    /// The tocs of earlier generations are skipped, and the walk stops at the first thing that is neither a header nor a toc. Sealed
+    /// headers have no magic number, so if the metadata is encrypted the walk stops at the first thing that cannot be opened with `metadata_key`
+    fn walk_storage(storage: & mut S, data_start: u128, archive_length: u64, metadata_key: Option<&DataKey>) -> Result<Walked> {
+        let mut headers = Vec::new();
+
+        let mut last_toc = None;
 
-            let header_offset = archive_file.stream_position()?;
+        storage.seek(SeekFrom::Start(data_start as u64))?;
 
+        let mut end = data_start;
 
-            let header: bincode::Result<Metadata> = bincode::deserialize_from(&archive_file);
+        //Iterate over each header until we reach something that is not a header or toc, or we find a file length outside the archive
+        loop {
 
-            let header = match header {
+            let header_offset = storage.stream_position()?;
+
+            let header = match EntryHeader::read_from(& mut *storage, metadata_key) {
                 Ok(h) => {
                     h
                 }
                 Err(e) => {
-                    println!("e: {:?}", e.as_ref());
+                    match e.kind() {
+                        ErrorKind::BadHeaderMagicNumber(TOC_MAGIC_NUMBER) | ErrorKind::AuthenticationFailed => {
+                            //Read the same bytes again as a toc
+                            storage.seek(SeekFrom::Start(header_offset))?;
 
-                    if let bincode::ErrorKind::SizeLimit = e.as_ref() {
-                        break;
-                    }
+                            match TOC::read_from(& mut *storage, metadata_key, archive_length - header_offset) {
+                                Ok(toc) => {
+                                    last_toc = Some((header_offset as u128, toc));
 
-                    if let bincode::ErrorKind::Io(d) = e.as_ref() {
-                        if let std::io::ErrorKind::UnexpectedEof = d.kind() {
-                            break;
+                                    end = storage.stream_position()? as u128;
+
+                                    continue;
+                                }
+                                Err(_) => break,
+                            }
                         }
+                        ErrorKind::BadHeaderMagicNumber(_) => break,
+                        ErrorKind::Bincode(b) => match b.as_ref() {
+                            bincode::ErrorKind::SizeLimit => break,
+                            bincode::ErrorKind::Io(d) if d.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                            //If we get here, then there was an unrecoverable serde error
+                            _ => return Err(e.with_offset(header_offset as u128)),
+                        },
+                        _ => return Err(e.with_offset(header_offset as u128)),
                     }
-
-                    //If we get here, then there was an unrecoverable serde error
-                    return Err(Error::from(e));
                 }
             };
 
-            archive_file.seek(SeekFrom::Current(header.len() as i64))?;
+            let data_end = storage.seek(SeekFrom::Current(header.data_len() as i64))?;
 
-            if archive_file.stream_position()? > archive_length {
+            if data_end > archive_length {
                 break;
             }
 
-            offsets.push(header_offset as u128);
+            end = data_end as u128;
+
+            headers.push((header_offset as u128, header, end));
 
         }
 
-        Ok(offsets)
+        Ok(Walked {
+            headers,
+            last_toc,
+            end,
+        })
+    }
+
+}
+
+impl Archive<Volumes> {
+
+    ///Walk the archive at `path` and create a new toc from the paths stored in each header
+    ///
+    /// See [`Archive::repair_with`] for more information
+    pub fn repair<P: AsRef<Path>>(path: P) -> Result<Self> {
+
+        let archive_file = Volumes::open(&path).map_err(|e| Error::from(e).with_archive(path.as_ref()))?;
+
+        let mut archive = Self::repair_with(archive_file).map_err(|e| e.with_archive(path.as_ref()))?;
+
+        archive.path = Some(PathBuf::from(path.as_ref()));
+
+        archive.recover_volumes().map_err(|e| e.with_archive(path.as_ref()))?;
+
+        Ok(archive)
+    }
+
+    ///Walk the encrypted archive at `path` and create a new toc from the paths stored in each header, unlocking it with any of the keys from `keys`
+    ///
+    /// See [`Archive::repair_with`] for more information
+    pub fn repair_encrypted<P: AsRef<Path>, K: KeyProvider + ?Sized>(path: P, keys: &K) -> Result<Self> {
+
+        let archive_file = Volumes::open(&path).map_err(|e| Error::from(e).with_archive(path.as_ref()))?;
+
+        let mut archive = Self::repair_encrypted_with(archive_file, keys).map_err(|e| e.with_archive(path.as_ref()))?;
+
+        archive.path = Some(PathBuf::from(path.as_ref()));
+
+        archive.recover_volumes().map_err(|e| e.with_archive(path.as_ref()))?;
+
+        Ok(archive)
+    }
+
+}
+
+///The layout of an archive after moving its data forward, see [`Archive::compaction`]
+pub (in crate) struct Compaction {
+    ///The start and end of every block that is still needed, and where it moves to
+    blocks: Vec<(u64, u64, u64)>,
+
+    ///The new offset and contents of the toc of each earlier generation that is kept, oldest first
+    history: Vec<(u128, Vec<u8>)>,
+
+    ///The current toc, pointing at the new locations of the blocks
+    toc: TOC,
+
+    ///The new offset of the current toc
+    toc_offset: u128,
+
+    ///The new length of the archive
+    pub (in crate) end: u128,
+}
+
+///Everything found by walking an archive
+struct Walked {
+    ///The offset of each header, the header and the end of the file data
+    headers: Vec<(u128, EntryHeader, u128)>,
+
+    ///The last toc, and its offset
+    last_toc: Option<(u128, TOC)>,
+
+    ///The end of the last header or toc
+    end: u128,
+}
+
+///The position of the first header, after the key block if the archive is encrypted
+fn data_start(encrypted: bool) -> u128 {
+    if encrypted {
+        (PREAMBLE_SIZE + KEY_BLOCK_SIZE) as u128
+    } else {
+        PREAMBLE_SIZE as u128
     }
+}
+
+///Read the header at `offset`
+pub (in crate) fn read_header_at<S: Storage>(storage: & mut S, offset: u128, metadata_key: Option<&DataKey>) -> Result<EntryHeader> {
 
-}
\ No newline at end of file
+    storage.seek(SeekFrom::Start(offset as u64))?;
+
+    EntryHeader::read_from(& mut *storage, metadata_key)
+}
+
+///Copy `length` bytes within the storage from `source` to `destination`
+///
+/// If the ranges overlap, `destination` must come before `source`
+fn copy_within<S: Storage>(storage: & mut S, source: u64, destination: u64, length: u64) -> Result<()> {
+
+    let mut buffer = vec![0u8; 64 * 1024];
+
+    let mut copied = 0u64;
+
+    while copied < length {
+        let chunk = (length - copied).min(buffer.len() as u64) as usize;
+
+        storage.seek(SeekFrom::Start(source + copied))?;
+        storage.read_exact(& mut buffer[..chunk])?;
+
+        storage.seek(SeekFrom::Start(destination + copied))?;
+        storage.write_all(&buffer[..chunk])?;
+
+        copied += chunk as u64;
+    }
+
+    Ok(())
+}
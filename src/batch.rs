@@ -0,0 +1,101 @@
+use std::path::Path;
+use crate::archive::Archive;
+use crate::toc::TOC;
+use crate::storage::Storage;
+use crate::volume::Volumes;
+use crate::error::Result;
+
+///A set of changes to an archive that are written as a single generation
+///
+/// Batches are not explicitly created, but returned by [`Archive::batch`]. Each call to [`Archive::remove`], [`Archive::rename`]
+/// or [`Archive::undelete`] writes a new toc, so making many changes one at a time grows the archive by a toc for each change.
+/// A batch makes the same changes, and [`Batch::commit`] writes one toc for all of them.
+///
+/// If the batch is dropped without being committed, none of the changes are made, and any data copied by [`Batch::rename`] is removed.
+pub struct Batch<'a, S: Storage = Volumes> {
+    archive: & 'a mut Archive<S>,
+
+    //The toc and end of data before the batch, restored if the batch is not committed
+    original: Option<(TOC, u128)>,
+}
+
+impl<'a, S: Storage> Batch<'a, S> {
+
+    ///Remove an entry, see [`Archive::remove`]
+    ///
+    /// Fails with [`crate::error::ErrorKind::NotFound`] if no entry exists with the given path
+    pub fn remove<P: AsRef<Path>>(& mut self, path: P) -> Result<()> {
+        self.begin();
+
+        self.archive.remove_entry(path.as_ref()).map_err(|e| self.archive.error_context(e))
+    }
+
+    ///Rename an entry, see [`Archive::rename`]
+    ///
+    /// Fails with [`crate::error::ErrorKind::NotFound`] if `from` does not exist, or [`crate::error::ErrorKind::PathConflict`] if `to` already exists
+    pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(& mut self, from: P, to: Q) -> Result<()> {
+        self.begin();
+
+        self.archive.rename_entry(from.as_ref(), to.as_ref()).map_err(|e| self.archive.error_context(e.with_entry(from)))
+    }
+
+    ///Restore a file that was removed from the archive, returning the number of the generation it was restored from, see [`Archive::undelete`]
+    ///
+    /// Files removed earlier in the batch are restored from the current generation
+    pub fn undelete<P: AsRef<Path>>(& mut self, path: P) -> Result<u64> {
+        self.begin();
+
+        let current = self.original.as_ref().map(|(toc, _)| toc);
+
+        self.archive.undelete_entry(path.as_ref(), current).map_err(|e| self.archive.error_context(e.with_entry(path)))
+    }
+
+    ///Write every change in the batch as a single new generation
+    ///
+    /// If nothing was changed, no generation is written
+    pub fn commit(mut self) -> Result<()> {
+
+        if self.original.is_none() {
+            return Ok(());
+        }
+
+        self.archive.write_toc()?;
+
+        self.original = None;
+
+        Ok(())
+    }
+
+    ///Keep the toc as it was before the first change, so it can be restored
+    fn begin(& mut self) {
+        if self.original.is_none() {
+            self.original = Some((self.archive.toc.clone(), self.archive.data_end));
+        }
+    }
+}
+
+impl<'a, S: Storage> Drop for Batch<'a, S> {
+    fn drop(& mut self) {
+        if let Some((toc, data_end)) = self.original.take() {
+            self.archive.toc = toc;
+            self.archive.data_end = data_end;
+
+            //Drop cannot fail, and data left after the toc is removed by the next change anyway
+            let _ = self.archive.storage_mut().set_len(data_end as u64);
+        }
+    }
+}
+
+impl<S: Storage> Archive<S> {
+
+    ///Start a batch of changes, which are written as a single generation when the batch is committed
+    ///
+    /// See [`Batch`] for more information
+    pub fn batch(& mut self) -> Batch<'_, S> {
+        Batch {
+            archive: self,
+            original: None,
+        }
+    }
+
+}
@@ -1,101 +1,722 @@
 use std::path::{Path, PathBuf};
-use crate::archive::Archive;
-use std::fs::{OpenOptions, File};
-use std::io::{Seek, SeekFrom};
-use crate::header::Metadata;
+use crate::archive::{Archive, read_header_at};
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::SeekFrom;
+use std::collections::{HashMap, HashSet};
+use fastcdc::v2020::StreamCDC;
+use crate::header::{Metadata, EntryHeader, Content, ChunkRef, FileType};
+use crate::crypto::{ContentHasher, DataKey, HASH_SIZE, random};
+use crate::toc::Blob;
+use crate::handle::EntryHandle;
+use crate::entry::Entry;
 use crate::safepath::SafePathBuf;
 use std::convert::TryFrom;
+use crate::storage::Storage;
+use crate::volume::Volumes;
 use crate::error::{Result, Error, ErrorKind};
 
 ///A specialised object used to append files to archives
 ///
 /// Builders are not explicitly created, but returned by [`Archive::builder`].
-pub struct Builder<'a> {
-
-    archive: & 'a  mut Archive,
+///
+/// A file with the same contents as a file already in the archive shares its data rather than storing it again. To also share
+/// the parts of files that are the same, such as successive backups of a disk image, see [`Builder::with_chunking`].
+pub struct Builder<'a, S: Storage = Volumes> {
 
-    archive_file: std::fs::File,
+    archive: & 'a  mut Archive<S>,
+    chunking: Option<Chunking>,
 }
 
-impl<'a> Builder<'a> {
+impl<'a, S: Storage> Builder<'a, S> {
 
-    pub(in crate) fn new(archive: & 'a mut Archive) -> Result<Self> {
-        //Truncate the file to remove the toc for overwriting
-        let archive_file = OpenOptions::new().write(true).open(archive.path())?;
-        archive_file.set_len(archive.toc_offset() as u64)?;
+    pub(in crate) fn new(archive: & 'a mut Archive<S>) -> Result<Self> {
+        //Files are appended after the toc, which is kept as the toc of the previous generation. Anything after it (such as the trailer of a streamed archive) is removed
+        let data_end = archive.data_end as u64;
 
-        //Open the file for appending
-        let archive_file = OpenOptions::new().write(true).append(true).open(archive.path())?;
+        archive.storage_mut().set_len(data_end)?;
 
         Ok(Self {
             archive,
-            archive_file,
+            chunking: None,
         })
 
     }
 
+    ///Split the files appended by this builder into chunks, storing each chunk once
+    ///
+    /// Chunked files are read through the chunk list, so reading them is slower, and they cannot be read with [`crate::stream::StreamReader`].
+    /// Files appended without chunking are unaffected, and can be mixed with chunked files in the same archive.
+    pub fn with_chunking(mut self, chunking: Chunking) -> Self {
+        self.chunking = Some(chunking);
+        self
+    }
+
     ///Add a new file at `path` to the archive. The path stored in the archive itself is specified by `name`
+    ///
+    /// A symbolic link is stored as a link to its target, rather than as the file it points to
     pub fn append<P: AsRef<Path>, N: AsRef<Path>>(&mut self, path: P, name: N) -> Result<()> {
+        self.append_file(path.as_ref(), name.as_ref()).map_err(|e| self.archive.error_context(e.with_entry(name)))
+    }
+
+    fn append_file(&mut self, path: &Path, name: &Path) -> Result<()> {
+
+        if let Some(meta) = Metadata::symlink(path)? {
+            return self.append_entry_data(meta, & mut std::io::empty(), name);
+        }
+
+        let mut file = OpenOptions::new().read(true).open(path)?;
+
+        let meta: Metadata = file.metadata()?.into();
+
+        self.append_entry_data(meta, & mut file, name)
+
+    }
+
+    ///Add a new file to the archive, with the given metadata, reading the data from `data`. The path stored in the archive is specified by `name`
+    ///
+    /// Exactly [`Metadata::len`] bytes are read from `data`
+    pub fn append_data<R: Read, N: AsRef<Path>>(&mut self, metadata: Metadata, mut data: R, name: N) -> Result<()> {
+        self.append_entry_data(metadata, & mut data, name.as_ref()).map_err(|e| self.archive.error_context(e.with_entry(name)))
+    }
+
+    ///Add a copy of a file from another archive, with the same path and metadata
+    ///
+    /// If both archives are plain, or encrypted with the same key, the stored data is copied as it is, without being opened or hashed
+    /// again, and shared with any file in this archive with the same contents. Chunked files are copied chunk by chunk, so only the
+    /// chunks this archive does not already have are stored. Otherwise the file is read from the other archive and appended like any
+    /// other file.
+    ///
+    /// Fails with [`ErrorKind::PathConflict`] if the path already exists in this archive
+    pub fn append_entry<T: Storage>(&mut self, entry: &Entry<'_, T>) -> Result<()> {
+        self.copy_entry(entry, entry.path()).map_err(|e| self.archive.error_context(e.with_entry(entry.path())))
+    }
+
+    ///Add a copy of the file `entry` from another archive, stored at `name`
+    pub(in crate) fn copy_entry<T: Storage>(&mut self, entry: &Entry<'_, T>, name: &Path) -> Result<()> {
+
+        let source = entry.archive();
+
+        let handle = entry.handle();
+
+        let same_key = match (&self.archive.key, &source.key) {
+            (Some(key), Some(source_key)) => key.same_as(source_key),
+            (None, None) => true,
+            _ => false,
+        };
+
+        //Data sealed with another key must be opened and sealed again
+        if !same_key {
+            return self.append_entry_data(handle.header().clone(), & mut handle.open(source)?, name);
+        }
+
+        if self.archive.contains(name) {
+            return Err(Error::new(ErrorKind::PathConflict(PathBuf::from(name)), format!("Could not append file to TOC with the chosen path ({}), as path already exists in TOC", name.display())));
+        }
+
+        let name = SafePathBuf::try_from(name)?;
+
+        let metadata_key = self.archive.metadata_key().cloned();
+
+        let content = match handle.content() {
+            Content::Chunked(chunks) => {
+                self.copy_chunks(source, &name, chunks, metadata_key.as_ref())?;
+
+                Content::Chunked(chunks.clone())
+            }
+            //Shared data is found after the header of the file it was first stored with, which is where the handle points
+            Content::Blob(hash) | Content::Shared(hash) => match self.archive.toc.blobs.get_mut(hash) {
+                Some(blob) => {
+                    blob.refs += 1;
+
+                    Content::Shared(*hash)
+                }
+                None => Content::Blob(*hash),
+            },
+            _ => Content::Inline,
+        };
+
+        let storage = self.archive.storage_mut();
+
+        let position = storage.seek(SeekFrom::End(0))?;
+
+        EntryHeader::new(name.clone(), handle.header().clone(), handle.salt(), content.clone()).write_to(& mut *storage, metadata_key.as_ref())?;
+
+        //Data stored after the header, rather than shared or chunked, is copied after the new header
+        if let Content::Blob(_) | Content::Inline = content {
+            copy_between(source, handle.file_offset() as u64, handle.data_len() as u64, storage)?;
+        }
+
+        if let Content::Blob(hash) = content {
+            self.archive.toc.blobs.insert(hash, Blob {
+                header: position as u128,
+                refs: 1,
+            });
+        }
+
+        self.archive.toc._table.insert(name, position as u128);
+
+        //The signature no longer matches the toc
+        self.archive.toc.signature = None;
+
+        Ok(())
+    }
+
+    ///Copy the chunks of a chunked file from `source` that are not already stored, and add a use of every chunk
+    fn copy_chunks<T: Storage>(&mut self, source: &Archive<T>, name: &SafePathBuf, chunks: &[ChunkRef], metadata_key: Option<&DataKey>) -> Result<()> {
+
+        for chunk in chunks {
+            if self.archive.toc.blobs.contains_key(&chunk.hash) {
+                continue;
+            }
+
+            let blob = source.toc.blobs.get(&chunk.hash).ok_or_else(|| Error::new(ErrorKind::BlobNotFound(chunk.hash), String::from("Shared data does not exist in TOC")))?;
+
+            let (owner, data_offset) = {
+                let mut source_storage = source.storage();
+
+                let owner = read_header_at(& mut *source_storage, blob.header, source.metadata_key()).map_err(|e| e.with_offset(blob.header))?;
+
+                (owner, source_storage.stream_position()?)
+            };
+
+            if !matches!(owner.content, Content::Blob(owned) | Content::Chunk(owned) if owned == chunk.hash) || owner.metadata.len() != chunk.len as u128 {
+                return Err(Error::new(ErrorKind::BlobNotFound(chunk.hash), format!("Header at the location of the shared data ({}) has different contents", blob.header)));
+            }
+
+            let storage = self.archive.storage_mut();
+
+            let position = storage.seek(SeekFrom::End(0))?;
+
+            EntryHeader::new(name.clone(), Metadata::new(FileType::File, chunk.len as u128), owner.salt, Content::Chunk(chunk.hash)).write_to(& mut *storage, metadata_key)?;
+
+            copy_between(source, data_offset, owner.data_len() as u64, storage)?;
+
+            self.archive.toc.blobs.insert(chunk.hash, Blob {
+                header: position as u128,
+                refs: 0,
+            });
+        }
+
+        for chunk in chunks {
+            if let Some(blob) = self.archive.toc.blobs.get_mut(&chunk.hash) {
+                blob.refs += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    ///Bring the files below `prefix` in the archive up to date with the files in the directory `src`, which is read recursively
+    ///
+    /// Files that are not in the archive are appended, and files that have changed since they were appended are replaced. By
+    /// default a file has changed if its size or modification time is different, see [`SyncOptions`]. The replaced data is left
+    /// in place until the archive is defragmented, and can still be read from earlier generations.
+    ///
+    /// If this fails part way through, the changes made so far are kept, and are written by [`Builder::finalise`] like any other
+    pub fn sync_dir<P: AsRef<Path>, N: AsRef<Path>>(&mut self, src: P, prefix: N, options: &SyncOptions) -> Result<SyncSummary> {
+        self.sync_entries(src.as_ref(), prefix.as_ref(), options).map_err(|e| self.archive.error_context(e))
+    }
+
+    fn sync_entries(&mut self, src: &Path, prefix: &Path, options: &SyncOptions) -> Result<SyncSummary> {
+
+        let files = collect_files(src)?.into_iter().map(|file| (src.join(&file), prefix.join(file))).collect::<Vec<_>>();
+
+        let mut summary = SyncSummary::default();
+
+        for (path, name) in &files {
+
+            let changed = match self.archive.handle(name)? {
+                Some(handle) => self.has_changed(path, &handle, options).map_err(|e| e.with_entry(name))?,
+                None => {
+                    self.append_file(path, name).map_err(|e| e.with_entry(name))?;
+
+                    summary.added.push(name.clone());
+
+                    continue;
+                }
+            };
+
+            if changed {
+                self.replace_with(name, |builder| builder.append_file(path, name)).map_err(|e| e.with_entry(name))?;
+
+                summary.updated.push(name.clone());
+            } else {
+                summary.unchanged.push(name.clone());
+            }
+        }
+
+        if options.removal {
+            let synced = files.iter().map(|(_, name)| name.as_path()).collect::<HashSet<_>>();
+
+            let mut removed = self.archive.toc._table.keys().map(SafePathBuf::as_path).filter(|path| path.starts_with(prefix) && !synced.contains(path)).map(Path::to_path_buf).collect::<Vec<_>>();
+
+            removed.sort();
+
+            for name in &removed {
+                self.archive.release(name);
+            }
+
+            summary.removed = removed;
+        }
+
+        Ok(summary)
+    }
+
+    ///Compare the file at `path` to the archived file `handle`
+    fn has_changed(&self, path: &Path, handle: &EntryHandle, options: &SyncOptions) -> Result<bool> {
+
+        let meta = match Metadata::symlink(path)? {
+            Some(meta) => meta,
+            None => std::fs::metadata(path)?.into(),
+        };
+
+        let archived = handle.header();
+
+        if archived.file_type() != meta.file_type() || archived.len() != meta.len() {
+            return Ok(true);
+        }
+
+        //Links have no data, so only the target is compared
+        if meta.file_type() == FileType::SystemLink {
+            return Ok(archived.link_target() != meta.link_target());
+        }
+
+        if !options.hash {
+            return Ok(archived.modified().is_none() || archived.modified() != meta.modified());
+        }
+
+        Ok(ContentHasher::hash_reader(self.archive.key.as_ref(), & mut OpenOptions::new().read(true).open(path)?)? != self.archive.content_hash(handle)?)
+    }
+
+    fn append_entry_data(&mut self, meta: Metadata, data: & mut dyn Read, name: &Path) -> Result<()> {
 
         //Check for naming conflicts in the toc
-        if self.archive.table()._table.contains_key(path.as_ref()) {
+        if self.archive.contains(name) {
 
-            return Err(Error::new(ErrorKind::PathConflict(PathBuf::from(path.as_ref())), format!("Could not append file to TOC with the chosen path ({}), as path already exists in TOC", path.as_ref().to_str().unwrap())));
+            return Err(Error::new(ErrorKind::PathConflict(PathBuf::from(name)), format!("Could not append file to TOC with the chosen path ({}), as path already exists in TOC", name.display())));
 
         }
 
+        //Check the name before writing anything
+        let name = SafePathBuf::try_from(name)?;
+
+        let length = meta.len();
+
+        if let Some(chunking) = self.chunking.filter(|_| length > 0) {
+            return self.append_chunked(meta, data, name, chunking);
+        }
+
+        //Each encrypted file has its own key, derived from a random salt stored in the header
+        let key = match &self.archive.key {
+            Some(key) => {
+                let salt = random()?;
+
+                Some((salt, key.derive(&salt)))
+            }
+            None => None,
+        };
+
+        let metadata_key = self.archive.metadata_key().cloned();
+
+        //Empty files have nothing worth sharing
+        let mut hasher = if length > 0 { Some(ContentHasher::new(self.archive.key.as_ref())) } else { None };
+
+        let storage = self.archive.storage_mut();
+
         //Get the position of the stream (this will be used as the file offset in the toc)
-        self.archive_file.seek(SeekFrom::End(0))?;
-        let position = self.archive_file.stream_position()?;
+        let position = storage.seek(SeekFrom::End(0))?;
+
+        //Append the metadata. The content hash is not known until the data has been read, so a placeholder of the same size is written
+        let mut header = EntryHeader::new(name.clone(), meta, key.as_ref().map(|(salt, _)| *salt), if hasher.is_some() { Content::Blob([0; HASH_SIZE]) } else { Content::Inline });
+
+        header.write_to(& mut *storage, metadata_key.as_ref())?;
+
+        //Append the file, hashing it as it is read
+        let mut data = Hashing {
+            inner: data,
+            hasher: hasher.as_mut(),
+        };
+
+        match key {
+            Some((_, key)) => key.seal_data(length, & mut data, storage)?,
+            None => {
+                if std::io::copy(& mut (& mut data).take(length as u64), storage)? != length as u64 {
+                    return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "file ended before the length given in the metadata").into());
+                }
+            }
+        }
+
+        if let Some(hasher) = hasher {
+            let hash = hasher.finalize();
+
+            //If the same contents are already stored, the data that was just written is replaced by a header that shares it
+            header.content = match self.archive.toc.blobs.get_mut(&hash) {
+                Some(blob) => {
+                    blob.refs += 1;
+
+                    Content::Shared(hash)
+                }
+                None => {
+                    self.archive.toc.blobs.insert(hash, Blob {
+                        header: position as u128,
+                        refs: 1,
+                    });
 
-        //Append the metadata
-        let meta: Metadata = File::open(&path)?.metadata()?.into();
+                    Content::Blob(hash)
+                }
+            };
 
-        bincode::serialize_into(&self.archive_file, &meta)?;
+            let storage = self.archive.storage_mut();
 
-        //Append the file
-        {
-            let mut file = OpenOptions::new().read(true).open(&path)?;
+            if let Content::Shared(_) = header.content {
+                storage.set_len(position)?;
+            }
 
-            std::io::copy(& mut file, & mut self.archive_file)?;
+            storage.seek(SeekFrom::Start(position))?;
+
+            header.write_to(& mut *storage, metadata_key.as_ref())?;
         }
 
         //Add the (name, file_offset) pair to the toc
-        self.archive.toc._table.insert(SafePathBuf::try_from(name.as_ref())?, position as u128);
+        self.archive.toc._table.insert(name, position as u128);
+
+        //The signature no longer matches the toc
+        self.archive.toc.signature = None;
 
         Ok(())
 
     }
 
-    ///Must be called when files have been appended to replace the temporarily removed toc.
-    ///
-    /// Because the toc is temporarily removed during appending, this function should be called as soon as possible to preserve the integrity of the archive.
-    /// Any unnecessary code executed before this function is called may cause a panic and result in the loss of the toc for the archive.
-    pub fn finalise(mut self) -> Result<()> {
-        //Get this position of the stream (which is the size of the file at this point, without the toc)
-        self.archive_file.seek(SeekFrom::End(0))?;
-        let position = self.archive_file.stream_position()?;
+    ///Append the chunks of the data that are not already stored, each with its own header, followed by the header of the file listing every chunk
+    fn append_chunked(&mut self, meta: Metadata, data: & mut dyn Read, name: SafePathBuf, chunking: Chunking) -> Result<()> {
 
-        self.archive.toc_offset = position as u128;
+        let length = meta.len();
 
-        //Append toc
-        bincode::serialize_into(&self.archive_file, &self.archive.toc._table)?;
+        let metadata_key = self.archive.metadata_key().cloned();
 
-        //Write offset to toc offset at beginning
-        {
-            let _f = self.archive_file;
+        let mut chunks = Vec::new();
 
-            //Close the file
+        //Chunks written for this file, which are only added to the blobs once the whole file has been read
+        let mut written = HashMap::new();
+
+        for chunk in StreamCDC::new(data.take(length as u64), chunking.min, chunking.avg, chunking.max) {
+            let chunk = chunk.map_err(std::io::Error::from)?;
+
+            let mut hasher = ContentHasher::new(self.archive.key.as_ref());
+
+            hasher.update(&chunk.data);
+
+            let hash = hasher.finalize();
+
+            if !self.archive.toc.blobs.contains_key(&hash) && !written.contains_key(&hash) {
+                written.insert(hash, self.append_chunk(&name, &chunk.data, hash, metadata_key.as_ref())?);
+            }
+
+            chunks.push(ChunkRef {
+                hash,
+                len: chunk.length as u64,
+            });
+        }
+
+        if chunks.iter().map(|chunk| chunk.len as u128).sum::<u128>() != length {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "file ended before the length given in the metadata").into());
         }
 
-        let mut file = OpenOptions::new().write(true).open(self.archive.path())?;
+        for (hash, header) in written {
+            self.archive.toc.blobs.insert(hash, Blob {
+                header: header as u128,
+                refs: 0,
+            });
+        }
+
+        for chunk in &chunks {
+            if let Some(blob) = self.archive.toc.blobs.get_mut(&chunk.hash) {
+                blob.refs += 1;
+            }
+        }
+
+        //The header has a salt like every other header in an encrypted archive, even though no data is sealed with it
+        let salt = match &self.archive.key {
+            Some(_) => Some(random()?),
+            None => None,
+        };
+
+        let storage = self.archive.storage_mut();
+
+        let position = storage.seek(SeekFrom::End(0))?;
+
+        EntryHeader::new(name.clone(), meta, salt, Content::Chunked(chunks)).write_to(& mut *storage, metadata_key.as_ref())?;
 
-        file.seek(SeekFrom::Start(16))?;
+        self.archive.toc._table.insert(name, position as u128);
 
-        bincode::serialize_into(file, &position)?;
+        //The signature no longer matches the toc
+        self.archive.toc.signature = None;
 
         Ok(())
+    }
+
+    ///Append one chunk of the file `name` with its own header, returning the offset of the header
+    fn append_chunk(&mut self, name: &SafePathBuf, data: &[u8], hash: [u8; HASH_SIZE], metadata_key: Option<&DataKey>) -> Result<u64> {
+
+        //Each chunk is sealed with its own key, as it may be shared by any number of files
+        let key = match &self.archive.key {
+            Some(key) => {
+                let salt = random()?;
+
+                Some((salt, key.derive(&salt)))
+            }
+            None => None,
+        };
+
+        let storage = self.archive.storage_mut();
+
+        let position = storage.seek(SeekFrom::End(0))?;
+
+        EntryHeader::new(name.clone(), Metadata::new(FileType::File, data.len() as u128), key.as_ref().map(|(salt, _)| *salt), Content::Chunk(hash)).write_to(& mut *storage, metadata_key)?;
+
+        match key {
+            Some((_, key)) => key.seal_data(data.len() as u128, & mut &data[..], storage)?,
+            None => storage.write_all(data)?,
+        }
+
+        Ok(position)
+    }
+
+    ///Remove `name` from the toc so it can be appended again. The old data is left in place until the archive is defragmented
+    #[cfg(any(feature = "tar", feature = "zip"))]
+    pub(in crate) fn replace(&mut self, name: &Path) {
+        self.archive.release(name);
+    }
+
+    ///Replace the file `name` with the file appended by `append`
+    ///
+    /// The old file is only released once `append` succeeds, so if the new file cannot be read, the old file is kept in the toc.
+    /// Like [`Builder::replace`], the old data is left in place until the archive is defragmented.
+    pub(in crate) fn replace_with<F: FnOnce(& mut Self) -> Result<()>>(&mut self, name: &Path, append: F) -> Result<()> {
+
+        //Taken out of the toc, so the name is free, but still holding its shared data, so the new file can share it if nothing changed
+        let (old_name, offset) = match self.archive.toc._table.remove_entry(name) {
+            Some(old) => old,
+            None => return append(self),
+        };
+
+        match append(self) {
+            Ok(()) => {
+                self.archive.release_header(offset);
+
+                Ok(())
+            }
+            Err(e) => {
+                self.archive.toc._table.insert(old_name, offset);
+
+                Err(e)
+            }
+        }
+    }
+
+    ///Must be called when files have been appended to write the new toc, which records a new generation (see [`crate::generation::Generation`]).
+    ///
+    /// Because the archive cannot be opened while files are being appended, this function should be called as soon as possible to preserve the integrity of the archive.
+    /// Any unnecessary code executed before this function is called may cause a panic, leaving an archive that must be repaired.
+    pub fn finalise(self) -> Result<()> {
+        //Get this position of the stream (which is the end of the appended files, where the new toc is written)
+        let position = self.archive.storage_mut().seek(SeekFrom::End(0)).map_err(|e| self.archive.error_context(Error::from(e)))?;
+
+        self.archive.data_end = position as u128;
+
+        self.archive.write_toc()
+
+    }
+
+}
+
+impl<'a> Builder<'a, Volumes> {
+
+    ///Split the archive into volumes of `volume_size` bytes, so that it can be stored on size-limited media
+    ///
+    /// Anything appended past the end of a volume goes into a new volume at the archive path followed by `.001`, `.002` and so on,
+    /// so entries (and the toc) can span volumes. If the archive is already larger than `volume_size`, everything after the first
+    /// `volume_size` bytes is moved into new volumes. The volume size, and the volume and offset of every entry, are recorded in
+    /// the toc when the builder is finalised, so [`Archive::open`] finds every volume from the path of the first.
+    ///
+    /// Fails with [`ErrorKind::InvalidVolumeSize`] if `volume_size` is zero, or the archive is already split into volumes of a different size
+    pub fn with_volume_size(self, volume_size: u64) -> Result<Self> {
+
+        self.archive.storage_mut().split(volume_size).map_err(|e| self.archive.error_context(e))?;
+
+        self.archive.toc.volume_size = Some(volume_size);
+
+        Ok(self)
+    }
+
+}
+
+///Copy `length` bytes stored at `offset` in the archive `source` to the end of `destination`
+fn copy_between<T: Storage, S: Storage>(source: &Archive<T>, offset: u64, length: u64, destination: & mut S) -> Result<()> {
+
+    let mut source = source.storage();
+
+    source.seek(SeekFrom::Start(offset))?;
+
+    if std::io::copy(& mut (& mut *source).take(length), destination)? != length {
+        return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "file data ended before the length given in the header").into());
+    }
 
+    Ok(())
+}
+
+///Find every file and symbolic link below the directory `dir`, recursing into directories, relative to `dir` and sorted
+///
+/// Symbolic links are not followed, so each is listed like a file and appended as a link. This is how [`Builder::sync_dir`],
+/// [`Archive::diff_dir`] and the `tarpdate` binary find the files in a directory.
+pub fn collect_files<P: AsRef<Path>>(dir: P) -> Result<Vec<PathBuf>> {
+
+    let mut files = Vec::new();
+
+    collect(dir.as_ref(), Path::new(""), & mut files)?;
+
+    Ok(files)
+}
+
+fn collect(dir: &Path, prefix: &Path, files: & mut Vec<PathBuf>) -> Result<()> {
+
+    let mut children = std::fs::read_dir(dir)?.map(|entry| entry.map(|entry| entry.file_name())).collect::<std::io::Result<Vec<_>>>()?;
+
+    children.sort();
+
+    for child in children {
+        let path = dir.join(&child);
+        let name = prefix.join(&child);
+
+        if std::fs::symlink_metadata(&path)?.is_dir() {
+            collect(&path, &name, files)?;
+        } else {
+            files.push(name);
+        }
+    }
+
+    Ok(())
+}
+
+///Hashes data as it is read
+struct Hashing<'r, 'h> {
+    inner: & 'r mut dyn Read,
+    hasher: Option<& 'h mut ContentHasher>,
+}
+
+impl Read for Hashing<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..read]);
+        }
+
+        Ok(read)
     }
+}
+
+///Sizes used to split files into chunks with content-defined chunking, see [`Builder::with_chunking`]
+///
+/// Chunk boundaries are found with FastCDC, a rolling hash over the data, so inserting or removing bytes only changes the chunks
+/// around the change. Smaller chunks find more duplicate data, but each chunk has a header and an entry in the toc.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunking {
+    min: u32,
+    avg: u32,
+    max: u32,
+}
+
+impl Chunking {
+    ///Create chunk sizes, in bytes
+    ///
+    /// Fails with [`ErrorKind::InvalidChunkSizes`] unless `min <= avg <= max`, with `min` between 64 bytes and 1MiB, `avg` between
+    /// 256 bytes and 4MiB and `max` between 1KiB and 16MiB
+    pub fn new(min: u32, avg: u32, max: u32) -> Result<Self> {
+        use fastcdc::v2020::{MINIMUM_MIN, MINIMUM_MAX, AVERAGE_MIN, AVERAGE_MAX, MAXIMUM_MIN, MAXIMUM_MAX};
+
+        if !(MINIMUM_MIN..=MINIMUM_MAX).contains(&min) || !(AVERAGE_MIN..=AVERAGE_MAX).contains(&avg) || !(MAXIMUM_MIN..=MAXIMUM_MAX).contains(&max) || min > avg || avg > max {
+            return Err(Error::new(ErrorKind::InvalidChunkSizes(min, avg, max), format!("Chunk sizes (minimum {}, average {}, maximum {}) are out of range", min, avg, max)));
+        }
 
-}
\ No newline at end of file
+        Ok(Self {
+            min,
+            avg,
+            max,
+        })
+    }
+
+    ///The smallest chunk, except for the last chunk of a file
+    pub fn min_size(&self) -> u32 { self.min }
+
+    ///The size chunks are expected to be on average
+    pub fn avg_size(&self) -> u32 { self.avg }
+
+    ///The largest chunk
+    pub fn max_size(&self) -> u32 { self.max }
+}
+
+///Chunks of 16KiB to 256KiB, averaging 64KiB
+impl Default for Chunking {
+    fn default() -> Self {
+        Self {
+            min: 16 * 1024,
+            avg: 64 * 1024,
+            max: 256 * 1024,
+        }
+    }
+}
+
+///Options for [`Builder::sync_dir`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncOptions {
+    hash: bool,
+    removal: bool,
+}
+
+impl SyncOptions {
+    ///Compare files by size and modification time, and keep files in the archive whose source files no longer exist
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///Compare files by size and content hash rather than modification time, which finds changes that kept the same time, at the cost of
+    /// reading every file
+    pub fn with_hash_comparison(mut self) -> Self {
+        self.hash = true;
+        self
+    }
+
+    ///Remove files below the prefix from the archive if they are not in the directory
+    pub fn with_removal(mut self) -> Self {
+        self.removal = true;
+        self
+    }
+
+    ///Returns true if files are compared by content hash
+    pub fn hash_comparison(&self) -> bool {
+        self.hash
+    }
+
+    ///Returns true if files that are not in the directory are removed from the archive
+    pub fn removal(&self) -> bool {
+        self.removal
+    }
+}
+
+///The changes made by [`Builder::sync_dir`], each listed by the path in the archive
+#[derive(Debug, Default)]
+pub struct SyncSummary {
+    ///Files that were not in the archive
+    pub added: Vec<PathBuf>,
+
+    ///Files that had changed, and were replaced
+    pub updated: Vec<PathBuf>,
+
+    ///Files that were removed, as they are no longer in the directory
+    pub removed: Vec<PathBuf>,
+
+    ///Files that had not changed
+    pub unchanged: Vec<PathBuf>,
+}
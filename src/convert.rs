@@ -0,0 +1,79 @@
+use std::path::{Path, PathBuf};
+use crate::error::Error;
+
+///The result of importing another archive format into a tarpdate archive
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    ///Paths of the files that were imported
+    pub imported: Vec<PathBuf>,
+
+    ///Paths of the files that were skipped, with the reason they were skipped (such as an unsafe path or an unsupported file type)
+    pub skipped: Vec<(PathBuf, Error)>,
+}
+
+///Options for exporting a tarpdate archive into another archive format
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    prefix: Option<PathBuf>,
+    owner: bool,
+    compression: bool,
+}
+
+impl ExportOptions {
+    ///Export every file with its stored path and owner, compressing files in formats that support compression
+    pub fn new() -> Self {
+        Self {
+            prefix: None,
+            owner: true,
+            compression: true,
+        }
+    }
+
+    ///Store every file below the directory `prefix` in the exported archive
+    pub fn with_prefix<P: AsRef<Path>>(mut self, prefix: P) -> Self {
+        self.prefix = Some(prefix.as_ref().to_path_buf());
+        self
+    }
+
+    ///Do not export the owner of each file, so that files are owned by whoever extracts them
+    pub fn without_owner(mut self) -> Self {
+        self.owner = false;
+        self
+    }
+
+    ///Store files without compressing them, in formats that support compression (such as ZIP)
+    pub fn without_compression(mut self) -> Self {
+        self.compression = false;
+        self
+    }
+
+    ///Get the directory that every file is stored below, if any
+    pub fn prefix(&self) -> Option<&Path> {
+        self.prefix.as_deref()
+    }
+
+    ///Returns true if the owner of each file is exported
+    pub fn owner(&self) -> bool {
+        self.owner
+    }
+
+    ///Returns true if files are compressed, in formats that support compression
+    pub fn compression(&self) -> bool {
+        self.compression
+    }
+
+    ///Get the path that a file is exported with
+    #[cfg(any(feature = "tar", feature = "zip"))]
+    pub (in crate) fn export_path(&self, path: &Path) -> PathBuf {
+        match &self.prefix {
+            Some(prefix) => prefix.join(path),
+            None => path.to_path_buf(),
+        }
+    }
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
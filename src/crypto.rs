@@ -0,0 +1,765 @@
+use std::fmt::{Debug, Formatter};
+use std::io::{Read, Write};
+use serde::{Serialize, Deserialize};
+use chacha20poly1305::XChaCha20Poly1305;
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use argon2::{Argon2, Algorithm, Version, Params};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Digest};
+use x25519_dalek::{StaticSecret, PublicKey, SharedSecret};
+use std::str::FromStr;
+use crate::error::{Result, Error, ErrorKind};
+
+///Size of every key, in bytes
+pub (in crate) const KEY_SIZE: usize = 32;
+
+///Size of the random salts used to derive a unique key for each encrypted entry, header or toc
+pub (in crate) const SALT_SIZE: usize = 24;
+
+///Size of the authentication tag added to each sealed chunk
+const TAG_SIZE: u64 = 16;
+
+///Entry data is sealed in chunks of this size, so that it can be read from any position without decrypting the whole file
+pub (in crate) const CHUNK_SIZE: u64 = 64 * 1024;
+
+///Size of the random salt used to derive a key from a password
+const PASSWORD_SALT_SIZE: usize = 16;
+
+///Size of the hashes used to find files with the same contents
+pub (in crate) const HASH_SIZE: usize = 32;
+
+///Authenticated ciphers that can be used to encrypt an archive
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Cipher {
+    ///XChaCha20-Poly1305, which is fast on any platform
+    #[default]
+    XChaCha20Poly1305,
+
+    ///AES-256-GCM, which is fast on platforms with hardware AES support
+    Aes256Gcm,
+}
+
+///A key that unlocks an encrypted archive
+#[derive(Clone)]
+pub enum Key {
+    ///A password, which is stretched into a key with Argon2id
+    Password(String),
+
+    ///A random 32 byte key, which is used as is
+    Raw([u8; KEY_SIZE]),
+
+    ///An X25519 identity, which unlocks archives encrypted to its [`Recipient`]
+    Identity(Identity),
+}
+
+impl Debug for Key {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        //Never print the key itself
+        match self {
+            Key::Password(_) => write!(f, "Password(..)"),
+            Key::Raw(_) => write!(f, "Raw(..)"),
+            Key::Identity(identity) => write!(f, "Identity({:?})", identity),
+        }
+    }
+}
+
+///Prefix of the text form of a [`Recipient`]
+const RECIPIENT_PREFIX: &str = "tarpdate-recipient-";
+
+///Prefix of the text form of an [`Identity`]
+const IDENTITY_PREFIX: &str = "TARPDATE-IDENTITY-";
+
+///The public half of an X25519 key pair. Archives can be encrypted to a recipient without knowing any secret, and only the matching [`Identity`] can open them
+///
+/// The text form is `tarpdate-recipient-` followed by the key in hex, see [`Recipient::from_str`]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Recipient {
+    public: [u8; KEY_SIZE],
+}
+
+impl Recipient {
+    ///Create a recipient from an X25519 public key
+    pub fn from_bytes(public: [u8; KEY_SIZE]) -> Self {
+        Self {
+            public,
+        }
+    }
+
+    ///Get the X25519 public key
+    pub fn to_bytes(&self) -> [u8; KEY_SIZE] {
+        self.public
+    }
+}
+
+impl std::fmt::Display for Recipient {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", RECIPIENT_PREFIX, hex(&self.public, false))
+    }
+}
+
+impl Debug for Recipient {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Recipient({})", self)
+    }
+}
+
+///Parse a recipient, failing with [`ErrorKind::InvalidKey`] if it is not in the form written by [`std::fmt::Display`]
+impl FromStr for Recipient {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(Self::from_bytes(parse_key(s, RECIPIENT_PREFIX)?))
+    }
+}
+
+///The secret half of an X25519 key pair, which unlocks archives encrypted to its [`Recipient`]
+///
+/// The text form is `TARPDATE-IDENTITY-` followed by the key in hex, see [`Identity::from_str`]
+#[derive(Clone)]
+pub struct Identity {
+    secret: [u8; KEY_SIZE],
+}
+
+impl Identity {
+    ///Generate a new random identity
+    pub fn generate() -> Result<Self> {
+        Ok(Self::from_bytes(random()?))
+    }
+
+    ///Create an identity from an X25519 secret key
+    pub fn from_bytes(secret: [u8; KEY_SIZE]) -> Self {
+        Self {
+            secret,
+        }
+    }
+
+    ///Get the recipient that archives must be encrypted to, so this identity can open them
+    pub fn recipient(&self) -> Recipient {
+        Recipient::from_bytes(PublicKey::from(&StaticSecret::from(self.secret)).to_bytes())
+    }
+
+    ///Get the text form of the identity. It is not implemented as [`std::fmt::Display`], so the secret is never printed by accident
+    pub fn to_secret_string(&self) -> String {
+        format!("{}{}", IDENTITY_PREFIX, hex(&self.secret, true))
+    }
+}
+
+impl Debug for Identity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        //Never print the secret itself
+        write!(f, "Identity({})", self.recipient())
+    }
+}
+
+///Parse an identity, failing with [`ErrorKind::InvalidKey`] if it is not in the form written by [`Identity::to_secret_string`]
+impl FromStr for Identity {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(Self::from_bytes(parse_key(s, IDENTITY_PREFIX)?))
+    }
+}
+
+///Supplies the keys used to unlock an encrypted archive
+///
+/// Keys are only asked for once an archive turns out to be encrypted, so a provider can prompt for a password or load
+/// identities lazily. See [`crate::archive::Archive::open_keyed`].
+pub trait KeyProvider {
+    ///Get the keys to try, in order
+    fn keys(&self) -> Result<Vec<Key>>;
+}
+
+impl KeyProvider for Key {
+    fn keys(&self) -> Result<Vec<Key>> {
+        Ok(vec![self.clone()])
+    }
+}
+
+impl KeyProvider for Identity {
+    fn keys(&self) -> Result<Vec<Key>> {
+        Ok(vec![Key::Identity(self.clone())])
+    }
+}
+
+impl KeyProvider for [Key] {
+    fn keys(&self) -> Result<Vec<Key>> {
+        Ok(self.to_vec())
+    }
+}
+
+impl KeyProvider for Vec<Key> {
+    fn keys(&self) -> Result<Vec<Key>> {
+        Ok(self.clone())
+    }
+}
+
+///Options for creating an encrypted archive
+///
+/// The data of every file is encrypted with a random key, which is stored in the archive once for each [`Key`] and
+/// [`Recipient`] that can unlock it. Any one of the keys can be used to open the archive.
+#[derive(Debug, Clone)]
+pub struct Encryption {
+    keys: Vec<Key>,
+    recipients: Vec<Recipient>,
+    cipher: Cipher,
+    encrypted_metadata: bool,
+}
+
+impl Encryption {
+    ///Encrypt an archive so that it can be unlocked with `key`, using XChaCha20-Poly1305
+    pub fn new(key: Key) -> Self {
+        Self {
+            keys: vec![key],
+            recipients: Vec::new(),
+            cipher: Cipher::default(),
+            encrypted_metadata: false,
+        }
+    }
+
+    ///Encrypt an archive so that it can be unlocked by the identity of `recipient`, using XChaCha20-Poly1305
+    pub fn for_recipient(recipient: Recipient) -> Self {
+        Self {
+            keys: Vec::new(),
+            recipients: vec![recipient],
+            cipher: Cipher::default(),
+            encrypted_metadata: false,
+        }
+    }
+
+    ///Also allow the archive to be unlocked by the identity of `recipient`
+    pub fn with_recipient(mut self, recipient: Recipient) -> Self {
+        self.recipients.push(recipient);
+        self
+    }
+
+    ///Also allow the archive to be unlocked with `key`
+    pub fn with_key(mut self, key: Key) -> Self {
+        self.keys.push(key);
+        self
+    }
+
+    ///Encrypt the archive with `cipher`
+    pub fn with_cipher(mut self, cipher: Cipher) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    ///Also encrypt the toc and the header of every file, so that paths, sizes and timestamps cannot be read without the key
+    ///
+    /// Nothing but the magic number, toc offset and the sealed keys is readable without the key, and headers no longer start with a
+    /// magic number, so the start of each file cannot be found either. Archives with encrypted metadata can still be repaired with the key.
+    pub fn with_encrypted_metadata(mut self) -> Self {
+        self.encrypted_metadata = true;
+        self
+    }
+
+    ///Get the keys that can unlock the archive
+    pub fn keys(&self) -> &[Key] {
+        &self.keys
+    }
+
+    ///Get the recipients whose identities can unlock the archive
+    pub fn recipients(&self) -> &[Recipient] {
+        &self.recipients
+    }
+
+    ///Get the cipher the archive is encrypted with
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    ///Returns true if the toc and headers are encrypted as well as the file data
+    pub fn encrypted_metadata(&self) -> bool {
+        self.encrypted_metadata
+    }
+}
+
+///Stored after the magic number and toc offset of an encrypted archive. Contains the data key, sealed once for each key that can unlock it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub (in crate) struct KeyBlock {
+    pub (in crate) cipher: Cipher,
+    pub (in crate) encrypted_metadata: bool,
+    pub (in crate) slots: Vec<KeySlot>,
+}
+
+///The data key, sealed with a key derived from a [`Key`]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub (in crate) enum KeySlot {
+    Password {
+        salt: [u8; PASSWORD_SALT_SIZE],
+        memory: u32,
+        iterations: u32,
+        parallelism: u32,
+        key: Sealed,
+    },
+    Raw {
+        key: Sealed,
+    },
+    X25519 {
+        recipient: Recipient,
+        ephemeral: [u8; KEY_SIZE],
+        key: Sealed,
+    },
+}
+
+///Data sealed with a key derived from a random salt, so the same key can seal any number of messages
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub (in crate) struct Sealed {
+    salt: [u8; SALT_SIZE],
+    data: Vec<u8>,
+}
+
+impl KeyBlock {
+    ///Create a random data key, and a key block that can be unlocked by every key in `encryption`
+    pub (in crate) fn new(encryption: &Encryption) -> Result<(Self, DataKey)> {
+
+        let data_key = DataKey {
+            cipher: encryption.cipher(),
+            key: random()?,
+        };
+
+        let mut slots = encryption.keys().iter().map(|key| KeySlot::new(key, &data_key)).collect::<Result<Vec<_>>>()?;
+
+        for recipient in encryption.recipients() {
+            slots.push(KeySlot::for_recipient(recipient, &data_key)?);
+        }
+
+        Ok((Self {
+            cipher: encryption.cipher(),
+            encrypted_metadata: encryption.encrypted_metadata(),
+            slots,
+        }, data_key))
+    }
+
+    ///Find the data key, failing with [`ErrorKind::WrongKey`] if no slot can be unlocked with any of `keys`
+    pub (in crate) fn unlock(&self, keys: &[Key]) -> Result<DataKey> {
+
+        for key in keys {
+            for slot in &self.slots {
+                if let Some(data_key) = slot.unlock(key, self.cipher)? {
+                    return Ok(data_key);
+                }
+            }
+        }
+
+        Err(Error::new(ErrorKind::WrongKey, String::from("Archive could not be unlocked with the given keys")))
+    }
+
+    ///Get every recipient that can unlock the archive
+    pub (in crate) fn recipients(&self) -> Vec<Recipient> {
+        self.slots.iter().filter_map(|slot| match slot {
+            KeySlot::X25519 { recipient, .. } => Some(*recipient),
+            _ => None,
+        }).collect()
+    }
+
+    ///Seal the data key for `recipient`, unless it can already unlock the archive
+    pub (in crate) fn add_recipient(& mut self, recipient: &Recipient, data_key: &DataKey) -> Result<()> {
+
+        if !self.recipients().contains(recipient) {
+            self.slots.push(KeySlot::for_recipient(recipient, data_key)?);
+        }
+
+        Ok(())
+    }
+
+    ///Remove the slot for `recipient`, failing with [`ErrorKind::LastKey`] if it is the only slot
+    pub (in crate) fn remove_recipient(& mut self, recipient: &Recipient) -> Result<()> {
+
+        let index = self.slots.iter().position(|slot| matches!(slot, KeySlot::X25519 { recipient: r, .. } if r == recipient))
+            .ok_or_else(|| Error::new(ErrorKind::RecipientNotFound(*recipient), format!("Recipient ({}) cannot unlock the archive", recipient)))?;
+
+        if self.slots.len() == 1 {
+            return Err(Error::new(ErrorKind::LastKey, format!("Recipient ({}) is the only key that can unlock the archive", recipient)));
+        }
+
+        self.slots.remove(index);
+
+        Ok(())
+    }
+}
+
+impl KeySlot {
+    ///Seal the data key with `key`
+    fn new(key: &Key, data_key: &DataKey) -> Result<Self> {
+        match key {
+            Key::Password(password) => {
+                let salt = random()?;
+
+                let key_encryption_key = DataKey {
+                    cipher: data_key.cipher,
+                    key: stretch(password, &salt, Params::DEFAULT_M_COST, Params::DEFAULT_T_COST, Params::DEFAULT_P_COST)?,
+                };
+
+                Ok(KeySlot::Password {
+                    salt,
+                    memory: Params::DEFAULT_M_COST,
+                    iterations: Params::DEFAULT_T_COST,
+                    parallelism: Params::DEFAULT_P_COST,
+                    key: key_encryption_key.seal(KEY_SLOT_AAD, &data_key.key)?,
+                })
+            }
+            Key::Raw(raw) => {
+                let key_encryption_key = DataKey {
+                    cipher: data_key.cipher,
+                    key: *raw,
+                };
+
+                Ok(KeySlot::Raw {
+                    key: key_encryption_key.seal(KEY_SLOT_AAD, &data_key.key)?,
+                })
+            }
+            Key::Identity(identity) => Self::for_recipient(&identity.recipient(), data_key),
+        }
+    }
+
+    ///Seal the data key for `recipient`, with a key agreed between a new ephemeral key and the recipient
+    fn for_recipient(recipient: &Recipient, data_key: &DataKey) -> Result<Self> {
+
+        let ephemeral = StaticSecret::from(random::<KEY_SIZE>()?);
+
+        let ephemeral_public = PublicKey::from(&ephemeral).to_bytes();
+
+        let shared = ephemeral.diffie_hellman(&PublicKey::from(recipient.public));
+
+        let key_encryption_key = DataKey {
+            cipher: data_key.cipher,
+            key: wrap_key(&shared, &ephemeral_public, recipient)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidKey(recipient.to_string()), format!("Recipient ({}) is not a valid public key", recipient)))?,
+        };
+
+        Ok(KeySlot::X25519 {
+            recipient: *recipient,
+            ephemeral: ephemeral_public,
+            key: key_encryption_key.seal(KEY_SLOT_AAD, &data_key.key)?,
+        })
+    }
+
+    ///Returns the data key if `key` unlocks this slot
+    fn unlock(&self, key: &Key, cipher: Cipher) -> Result<Option<DataKey>> {
+        let (key_encryption_key, sealed) = match (self, key) {
+            (KeySlot::Password { salt, memory, iterations, parallelism, key: sealed }, Key::Password(password)) => (stretch(password, salt, *memory, *iterations, *parallelism)?, sealed),
+            (KeySlot::Raw { key: sealed }, Key::Raw(raw)) => (*raw, sealed),
+            (KeySlot::X25519 { recipient, ephemeral, key: sealed }, Key::Identity(identity)) if identity.recipient() == *recipient => {
+                let shared = StaticSecret::from(identity.secret).diffie_hellman(&PublicKey::from(*ephemeral));
+
+                match wrap_key(&shared, ephemeral, recipient) {
+                    Some(key) => (key, sealed),
+                    None => return Ok(None),
+                }
+            }
+            _ => return Ok(None),
+        };
+
+        let key_encryption_key = DataKey {
+            cipher,
+            key: key_encryption_key,
+        };
+
+        //A slot that cannot be opened belongs to a different key
+        let key = match key_encryption_key.open(KEY_SLOT_AAD, sealed) {
+            Ok(key) => key,
+            Err(_) => return Ok(None),
+        };
+
+        let mut data_key = DataKey {
+            cipher,
+            key: [0; KEY_SIZE],
+        };
+
+        if key.len() != KEY_SIZE {
+            return Ok(None);
+        }
+
+        data_key.key.copy_from_slice(&key);
+
+        Ok(Some(data_key))
+    }
+}
+
+///Additional data for sealed key slots, so they cannot be swapped with other sealed data
+const KEY_SLOT_AAD: &[u8] = b"tarpdate key slot";
+
+///Additional data for sealed headers
+pub (in crate) const HEADER_AAD: &[u8] = b"tarpdate header";
+
+///Additional data for a sealed toc
+pub (in crate) const TOC_AAD: &[u8] = b"tarpdate toc";
+
+///The most memory (in KiB) that deriving a key from a password may use. Key slots are read from the archive, so without a limit a
+/// crafted archive could make opening it allocate any amount of memory
+const MAX_PASSWORD_MEMORY: u32 = 1024 * 1024;
+
+///The most passes over the memory that deriving a key from a password may make, so a crafted archive cannot make opening it run for hours
+const MAX_PASSWORD_ITERATIONS: u32 = 16;
+
+///The most lanes that deriving a key from a password may use
+const MAX_PASSWORD_PARALLELISM: u32 = 16;
+
+///Derive a key from a password with Argon2id
+///
+/// Fails with [`ErrorKind::WrongKey`] if the parameters are above the limits, which only a crafted archive has
+fn stretch(password: &str, salt: &[u8], memory: u32, iterations: u32, parallelism: u32) -> Result<[u8; KEY_SIZE]> {
+    if memory > MAX_PASSWORD_MEMORY || iterations > MAX_PASSWORD_ITERATIONS || parallelism > MAX_PASSWORD_PARALLELISM {
+        return Err(Error::new(ErrorKind::WrongKey, format!("Password parameters ({} KiB, {} iterations, {} lanes) are above the limits ({} KiB, {} iterations, {} lanes)", memory, iterations, parallelism, MAX_PASSWORD_MEMORY, MAX_PASSWORD_ITERATIONS, MAX_PASSWORD_PARALLELISM)));
+    }
+
+    let params = Params::new(memory, iterations, parallelism, Some(KEY_SIZE)).map_err(|e| Error::new(ErrorKind::WrongKey, format!("Password parameters are invalid ({})", e)))?;
+
+    let mut key = [0; KEY_SIZE];
+
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params).hash_password_into(password.as_bytes(), salt, & mut key)
+        .map_err(|e| Error::new(ErrorKind::WrongKey, format!("Could not derive a key from the password ({})", e)))?;
+
+    Ok(key)
+}
+
+///Derive the key that seals a recipient's slot from the shared secret, or None if the shared secret is all zeroes (from a malicious public key)
+fn wrap_key(shared: &SharedSecret, ephemeral: &[u8; KEY_SIZE], recipient: &Recipient) -> Option<[u8; KEY_SIZE]> {
+
+    if !shared.was_contributory() {
+        return None;
+    }
+
+    let mut salt = [0; 2 * KEY_SIZE];
+
+    salt[..KEY_SIZE].copy_from_slice(ephemeral);
+    salt[KEY_SIZE..].copy_from_slice(&recipient.public);
+
+    let mut key = [0; KEY_SIZE];
+
+    //The output is always a valid length for SHA-256
+    Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes()).expand(b"tarpdate x25519", & mut key).expect("32 byte HKDF output");
+
+    Some(key)
+}
+
+///Encode a key as hex
+fn hex(key: &[u8; KEY_SIZE], upper: bool) -> String {
+    key.iter().map(|byte| if upper { format!("{:02X}", byte) } else { format!("{:02x}", byte) }).collect()
+}
+
+///Parse the text form of a recipient or identity
+fn parse_key(s: &str, prefix: &str) -> Result<[u8; KEY_SIZE]> {
+    let invalid = || Error::new(ErrorKind::InvalidKey(String::from(s)), format!("Key is not in the form {}<{} hex digits>", prefix, 2 * KEY_SIZE));
+
+    let digits = s.trim().strip_prefix(prefix).ok_or_else(invalid)?;
+
+    if digits.len() != 2 * KEY_SIZE || !digits.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut key = [0; KEY_SIZE];
+
+    for (index, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&digits[2 * index..2 * index + 2], 16).map_err(|_| invalid())?;
+    }
+
+    Ok(key)
+}
+
+///Fill an array from the operating system's random number generator
+pub (in crate) fn random<const N: usize>() -> Result<[u8; N]> {
+    let mut bytes = [0; N];
+
+    getrandom::getrandom(& mut bytes).map_err(|e| std::io::Error::other(e.to_string()))?;
+
+    Ok(bytes)
+}
+
+///The number of chunks a file of `length` bytes is sealed in. Empty files are sealed as a single empty chunk, so their length is authenticated too
+fn chunks(length: u128) -> u128 {
+    length.div_ceil(CHUNK_SIZE as u128).max(1)
+}
+
+///The size of a file of `length` bytes once it is sealed
+pub (in crate) fn sealed_len(length: u128) -> u128 {
+    length + chunks(length) * TAG_SIZE as u128
+}
+
+///A key used to seal and open data with a [`Cipher`]
+#[derive(Clone)]
+pub (in crate) struct DataKey {
+    cipher: Cipher,
+    key: [u8; KEY_SIZE],
+}
+
+impl Debug for DataKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DataKey").field("cipher", &self.cipher).finish_non_exhaustive()
+    }
+}
+
+impl DataKey {
+    ///Returns true if `other` is the same key, so data sealed with one can be opened with the other
+    pub (in crate) fn same_as(&self, other: &DataKey) -> bool {
+        self.cipher == other.cipher && self.key == other.key
+    }
+
+    ///Derive a unique key for one entry, header or toc from a random salt, so that nonces never repeat under the same key
+    pub (in crate) fn derive(&self, salt: &[u8; SALT_SIZE]) -> DataKey {
+        let mut key = [0; KEY_SIZE];
+
+        //The output is always a valid length for SHA-256
+        Hkdf::<Sha256>::new(None, &self.key).expand_multi_info(&[b"tarpdate", salt], & mut key).expect("32 byte HKDF output");
+
+        DataKey {
+            cipher: self.cipher,
+            key,
+        }
+    }
+
+    ///Seal a single chunk. The nonce is the chunk index and a flag for the last chunk, so chunks cannot be reordered or dropped
+    fn seal_chunk(&self, index: u64, last: bool, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        let payload = Payload {
+            msg: plaintext,
+            aad,
+        };
+
+        let result = match self.cipher {
+            Cipher::XChaCha20Poly1305 => XChaCha20Poly1305::new(&self.key.into()).encrypt(&nonce::<24>(index, last).into(), payload),
+            Cipher::Aes256Gcm => Aes256Gcm::new(&self.key.into()).encrypt(&nonce::<12>(index, last).into(), payload),
+        };
+
+        result.map_err(|_| Error::new(ErrorKind::AuthenticationFailed, String::from("Data could not be encrypted")))
+    }
+
+    ///Open a single chunk, failing with [`ErrorKind::AuthenticationFailed`] if the key is wrong or the chunk has been modified
+    pub (in crate) fn open_chunk(&self, index: u64, last: bool, aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let payload = Payload {
+            msg: ciphertext,
+            aad,
+        };
+
+        let result = match self.cipher {
+            Cipher::XChaCha20Poly1305 => XChaCha20Poly1305::new(&self.key.into()).decrypt(&nonce::<24>(index, last).into(), payload),
+            Cipher::Aes256Gcm => Aes256Gcm::new(&self.key.into()).decrypt(&nonce::<12>(index, last).into(), payload),
+        };
+
+        result.map_err(|_| Error::new(ErrorKind::AuthenticationFailed, String::from("Encrypted data could not be authenticated, it has been modified or corrupted")))
+    }
+
+    ///Seal a short message with a key derived from a new random salt
+    pub (in crate) fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Result<Sealed> {
+        let salt = random()?;
+
+        Ok(Sealed {
+            salt,
+            data: self.derive(&salt).seal_chunk(0, true, aad, plaintext)?,
+        })
+    }
+
+    ///Open a message sealed by [`DataKey::seal`]
+    pub (in crate) fn open(&self, aad: &[u8], sealed: &Sealed) -> Result<Vec<u8>> {
+        self.derive(&sealed.salt).open_chunk(0, true, aad, &sealed.data)
+    }
+
+    ///Seal exactly `length` bytes of `data` in chunks, writing them to `writer`. Must be called on a key derived for the entry
+    pub (in crate) fn seal_data(&self, length: u128, data: & mut dyn Read, writer: & mut dyn Write) -> Result<()> {
+
+        let aad = length.to_le_bytes();
+
+        let count = chunks(length);
+
+        let mut buffer = Vec::with_capacity(CHUNK_SIZE as usize);
+
+        for index in 0..count {
+            let chunk = (length - index * CHUNK_SIZE as u128).min(CHUNK_SIZE as u128) as u64;
+
+            buffer.clear();
+
+            if data.take(chunk).read_to_end(& mut buffer)? as u64 != chunk {
+                return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "file ended before the length given in the metadata").into());
+            }
+
+            writer.write_all(&self.seal_chunk(index as u64, index + 1 == count, &aad, &buffer)?)?;
+        }
+
+        Ok(())
+    }
+
+    ///Open the chunk at `index` of a file of `length` bytes, given the sealed chunk. Must be called on a key derived for the entry
+    pub (in crate) fn open_data_chunk(&self, length: u128, index: u64, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        self.open_chunk(index, index as u128 + 1 == chunks(length), &length.to_le_bytes(), ciphertext)
+    }
+}
+
+///Hashes the contents of files, so that files with the same contents can share their data
+///
+/// Encrypted archives use a keyed hash, so the hashes stored in the toc do not reveal anything about the contents of files
+pub (in crate) enum ContentHasher {
+    Plain(Sha256),
+    Keyed(Hmac<Sha256>),
+}
+
+impl ContentHasher {
+    ///Create a hasher for an archive encrypted with `key`, or a plain archive if there is no key
+    pub (in crate) fn new(key: Option<&DataKey>) -> Self {
+        match key {
+            Some(key) => {
+                let mut hash_key = [0; KEY_SIZE];
+
+                //The output is always a valid length for SHA-256
+                Hkdf::<Sha256>::new(None, &key.key).expand(b"tarpdate content hash", & mut hash_key).expect("32 byte HKDF output");
+
+                ContentHasher::Keyed(<Hmac<Sha256> as Mac>::new_from_slice(&hash_key).expect("HMAC accepts any key length"))
+            }
+            None => ContentHasher::Plain(Sha256::new()),
+        }
+    }
+
+    pub (in crate) fn update(& mut self, data: &[u8]) {
+        match self {
+            ContentHasher::Plain(hasher) => hasher.update(data),
+            ContentHasher::Keyed(hasher) => hasher.update(data),
+        }
+    }
+
+    pub (in crate) fn finalize(self) -> [u8; HASH_SIZE] {
+        match self {
+            ContentHasher::Plain(hasher) => hasher.finalize().into(),
+            ContentHasher::Keyed(hasher) => hasher.finalize().into_bytes().into(),
+        }
+    }
+
+    ///Hash everything read from `data`, as [`ContentHasher::new`] would for an archive encrypted with `key`
+    pub (in crate) fn hash_reader(key: Option<&DataKey>, data: & mut dyn Read) -> Result<[u8; HASH_SIZE]> {
+
+        let mut hasher = Self::new(key);
+
+        std::io::copy(data, & mut hasher)?;
+
+        Ok(hasher.finalize())
+    }
+}
+
+impl Write for ContentHasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+///The location and size of the sealed chunk at `index` of a file of `length` bytes, relative to the start of the sealed data
+pub (in crate) fn chunk_range(length: u128, index: u64) -> (u64, u64) {
+    let plaintext = (length - index as u128 * CHUNK_SIZE as u128).min(CHUNK_SIZE as u128) as u64;
+
+    (index * (CHUNK_SIZE + TAG_SIZE), plaintext + TAG_SIZE)
+}
+
+///The nonce for a chunk: the chunk index, then a byte that is set for the last chunk, padded with zeroes
+fn nonce<const N: usize>(index: u64, last: bool) -> [u8; N] {
+    let mut nonce = [0; N];
+
+    nonce[..8].copy_from_slice(&index.to_le_bytes());
+    nonce[8] = last as u8;
+
+    nonce
+}
@@ -0,0 +1,266 @@
+use std::collections::{HashSet, BTreeMap};
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf, Component};
+use crate::archive::Archive;
+use crate::builder::collect_files;
+use crate::crypto::{ContentHasher, HASH_SIZE};
+use crate::handle::EntryHandle;
+use crate::header::{Metadata, FileType};
+use crate::storage::Storage;
+use crate::error::Result;
+
+///A way in which a file differs between an archive and what it is compared to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Difference {
+    ///The file is a different type, such as a directory in place of a file
+    Type,
+    ///The file has different unix permissions
+    Mode,
+    ///The file has a different modification time
+    Modified,
+    ///The file has a different size
+    Size,
+    ///The file has the same size but different contents
+    Content,
+    ///The symbolic link points somewhere else
+    LinkTarget,
+}
+
+///The differences between an archive and a directory, see [`Archive::diff_dir`]
+#[derive(Debug, Default)]
+pub struct DirDiff {
+    ///Files in the archive that are not in the directory
+    pub missing_on_disk: Vec<PathBuf>,
+
+    ///Files in the directory that are not in the archive
+    pub missing_from_archive: Vec<PathBuf>,
+
+    ///Files in both that differ, with every way in which they differ
+    pub changed: Vec<(PathBuf, Vec<Difference>)>,
+}
+
+impl DirDiff {
+    ///Returns true if the archive and the directory contain the same files
+    pub fn is_empty(&self) -> bool {
+        self.missing_on_disk.is_empty() && self.missing_from_archive.is_empty() && self.changed.is_empty()
+    }
+}
+
+///The differences between two archives, see [`diff`]
+#[derive(Debug, Default)]
+pub struct ArchiveDiff {
+    ///Files that are only in the new archive
+    pub added: Vec<PathBuf>,
+
+    ///Files that are only in the old archive
+    pub removed: Vec<PathBuf>,
+
+    ///Files whose type, size, contents or link target changed, with every way in which they differ
+    pub modified: Vec<(PathBuf, Vec<Difference>)>,
+
+    ///Files whose data is the same, but whose permissions or modification time changed
+    pub metadata: Vec<(PathBuf, Vec<Difference>)>,
+}
+
+impl ArchiveDiff {
+    ///Returns true if both archives contain the same files
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty() && self.metadata.is_empty()
+    }
+}
+
+///Compare the files in the archive `old` to the files in the archive `new`
+///
+/// Files are compared by type, size, contents (or link target), unix permissions and modification time. The contents of files that
+/// are the same size are compared by hash, which is read from the headers of plain archives, and computed by reading the files
+/// of encrypted archives. Each list is sorted by path.
+pub fn diff<S: Storage, T: Storage>(old: &Archive<S>, new: &Archive<T>) -> Result<ArchiveDiff> {
+    diff_prefix(old, new, "")
+}
+
+///Compare the files below `prefix` in the archive `old` to the files below `prefix` in the archive `new`, see [`diff`]
+pub fn diff_prefix<S: Storage, T: Storage, P: AsRef<Path>>(old: &Archive<S>, new: &Archive<T>, prefix: P) -> Result<ArchiveDiff> {
+
+    let old_handles = handles(old, prefix.as_ref())?;
+    let mut new_handles = handles(new, prefix.as_ref())?;
+
+    let mut diff = ArchiveDiff::default();
+
+    for (path, old_handle) in old_handles {
+        let new_handle = match new_handles.remove(&path) {
+            Some(new_handle) => new_handle,
+            None => {
+                diff.removed.push(path);
+
+                continue;
+            }
+        };
+
+        let differences = compare_archived(old, &old_handle, new, &new_handle)?;
+
+        //Differences in the data itself make the file modified, rather than only its metadata
+        if differences.iter().any(|difference| matches!(difference, Difference::Type | Difference::Size | Difference::Content | Difference::LinkTarget)) {
+            diff.modified.push((path, differences));
+        } else if !differences.is_empty() {
+            diff.metadata.push((path, differences));
+        }
+    }
+
+    diff.added = new_handles.into_keys().collect();
+
+    Ok(diff)
+}
+
+///Get a handle to every file below `prefix` in `archive`, by path
+fn handles<S: Storage>(archive: &Archive<S>, prefix: &Path) -> Result<BTreeMap<PathBuf, EntryHandle>> {
+    archive.iter()
+        .filter(|entry| entry.as_ref().map_or(true, |entry| entry.path().starts_with(prefix)))
+        .map(|entry| entry.map(|entry| (entry.path().to_path_buf(), entry.handle())))
+        .collect()
+}
+
+///Compare the file `old_handle` in the archive `old` to the file `new_handle` in the archive `new`
+fn compare_archived<S: Storage, T: Storage>(old: &Archive<S>, old_handle: &EntryHandle, new: &Archive<T>, new_handle: &EntryHandle) -> Result<Vec<Difference>> {
+
+    let (old_meta, new_meta) = (old_handle.header(), new_handle.header());
+
+    if old_meta.file_type() != new_meta.file_type() {
+        return Ok(vec![Difference::Type]);
+    }
+
+    let mut differences = Vec::new();
+
+    if old_meta.mode().map(|mode| mode & 0o7777) != new_meta.mode().map(|mode| mode & 0o7777) {
+        differences.push(Difference::Mode);
+    }
+
+    if old_meta.modified() != new_meta.modified() {
+        differences.push(Difference::Modified);
+    }
+
+    if old_meta.len() != new_meta.len() {
+        differences.push(Difference::Size);
+    } else if old_meta.file_type() == FileType::File && plain_hash(old, old_handle)? != plain_hash(new, new_handle)? {
+        differences.push(Difference::Content);
+    }
+
+    if old_meta.link_target() != new_meta.link_target() {
+        differences.push(Difference::LinkTarget);
+    }
+
+    Ok(differences)
+}
+
+///Get the unkeyed content hash of the file `handle`, which can be compared between archives with different keys
+fn plain_hash<S: Storage>(archive: &Archive<S>, handle: &EntryHandle) -> Result<[u8; HASH_SIZE]> {
+
+    let hash = match archive.key {
+        Some(_) => handle.open(archive).and_then(|mut entry| ContentHasher::hash_reader(None, & mut entry)),
+        None => archive.content_hash(handle),
+    };
+
+    hash.map_err(|e| archive.error_context(e.with_entry(handle.path())))
+}
+
+impl<S: Storage> Archive<S> {
+
+    ///Compare the archive to the directory `path`, as if the archive had been extracted into it
+    ///
+    /// Like `tar --compare`, files are compared by type, size, modification time, unix permissions and contents (or link target), and
+    /// every file in the archive or the directory is read. Symbolic links in the directory are not followed, and directories are
+    /// only compared if the archive contains them. Every path is relative to `path`, and each list is sorted.
+    pub fn diff_dir<P: AsRef<Path>>(&self, path: P) -> Result<DirDiff> {
+        self.diff_entries(path.as_ref()).map_err(|e| self.error_context(e))
+    }
+
+    fn diff_entries(&self, root: &Path) -> Result<DirDiff> {
+
+        let mut diff = DirDiff::default();
+
+        let mut handles = self.iter().map(|entry| entry.map(|entry| entry.handle())).collect::<Result<Vec<_>>>()?;
+
+        handles.sort_by(|a, b| a.path().cmp(b.path()));
+
+        for handle in &handles {
+            let path = root.join(handle.path());
+
+            let meta = match std::fs::symlink_metadata(&path) {
+                Ok(meta) => meta,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    diff.missing_on_disk.push(handle.path().to_path_buf());
+
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            let differences = self.compare(&path, meta.into(), handle).map_err(|e| e.with_entry(handle.path()))?;
+
+            if !differences.is_empty() {
+                diff.changed.push((handle.path().to_path_buf(), differences));
+            }
+        }
+
+        //Archived paths such as `./a` are found on disk at `a`
+        let archived = handles.iter().map(|handle| normalise(handle.path())).collect::<HashSet<_>>();
+
+        let files = collect_files(root)?;
+
+        diff.missing_from_archive = files.into_iter().filter(|path| !archived.contains(path)).collect();
+
+        Ok(diff)
+    }
+
+    ///Compare the file at `path`, with the metadata `meta`, to the archived file `handle`
+    fn compare(&self, path: &Path, meta: Metadata, handle: &EntryHandle) -> Result<Vec<Difference>> {
+
+        let archived = handle.header();
+
+        //Hard links are extracted as copies of the file they link to, which is compared on its own
+        let file_type = match archived.file_type() {
+            FileType::HardLink => FileType::File,
+            file_type => file_type,
+        };
+
+        if file_type != meta.file_type() {
+            return Ok(vec![Difference::Type]);
+        }
+
+        let mut differences = Vec::new();
+
+        //Only the permission bits are compared, as the file type bits are not always stored
+        if let (Some(archived_mode), Some(mode)) = (archived.mode(), meta.mode()) {
+            if archived_mode & 0o7777 != mode & 0o7777 {
+                differences.push(Difference::Mode);
+            }
+        }
+
+        match archived.file_type() {
+            FileType::File => {
+                if archived.modified().is_some() && archived.modified() != meta.modified() {
+                    differences.push(Difference::Modified);
+                }
+
+                if archived.len() != meta.len() {
+                    differences.push(Difference::Size);
+                } else if ContentHasher::hash_reader(self.key.as_ref(), & mut OpenOptions::new().read(true).open(path)?)? != self.content_hash(handle)? {
+                    differences.push(Difference::Content);
+                }
+            }
+            FileType::SystemLink => {
+                if archived.link_target() != Some(std::fs::read_link(path)?.as_path()) {
+                    differences.push(Difference::LinkTarget);
+                }
+            }
+            FileType::Dir | FileType::HardLink => {}
+        }
+
+        Ok(differences)
+    }
+
+}
+
+///Drop the `.` components of `path`, so paths that lead to the same file compare equal
+fn normalise(path: &Path) -> PathBuf {
+    path.components().filter(|component| *component != Component::CurDir).collect()
+}
@@ -2,16 +2,21 @@ use crate::archive::Archive;
 use std::collections::hash_map::{Iter};
 use crate::entry::Entry;
 use crate::safepath::SafePathBuf;
+use crate::storage::Storage;
+use crate::volume::Volumes;
+use crate::error::Result;
 
 ///An iterator over all the active files in an archive
-pub struct Entries<'a> {
-    archive: & 'a Archive,
+///
+/// Each item is a [`Result`], as the header for each file must be read from the archive.
+pub struct Entries<'a, S: Storage = Volumes> {
+    archive: & 'a Archive<S>,
     iterator: Iter<'a, SafePathBuf, u128>,
 
 }
 
-impl<'a> Entries<'a> {
-    pub (in crate) fn new(archive: & 'a Archive) -> Self {
+impl<'a, S: Storage> Entries<'a, S> {
+    pub (in crate) fn new(archive: & 'a Archive<S>) -> Self {
         Entries {
             archive,
             iterator: archive.toc._table.iter(),
@@ -20,8 +25,8 @@ impl<'a> Entries<'a> {
 
 }
 
-impl<'a> Iterator for Entries<'a> {
-    type Item = Entry<'a>;
+impl<'a, S: Storage> Iterator for Entries<'a, S> {
+    type Item = Result<Entry<'a, S>>;
 
     fn next(&mut self) -> Option<Self::Item> {
         let (path, offset) = self.iterator.next()?;
@@ -1,39 +1,103 @@
 use std::path::Path;
-use crate::header::Metadata;
+use crate::header::{Metadata, EntryHeader, Content, ChunkRef};
+use crate::crypto::{DataKey, SALT_SIZE, HASH_SIZE, CHUNK_SIZE, chunk_range};
 use crate::archive::Archive;
-use std::fs::{OpenOptions, File};
-use std::io::{Seek, SeekFrom, Read, Take};
+use crate::handle::EntryHandle;
+use crate::storage::Storage;
+use crate::volume::Volumes;
+use crate::error::{Result, Error, ErrorKind};
+use std::io::{Seek, SeekFrom, Read};
 
 ///An object representing an archived file
 #[derive(Debug)]
-pub struct Entry<'a> {
+pub struct Entry<'a, S: Storage = Volumes> {
     path: & 'a Path,
     header: Metadata,
+    header_offset: u128,
     file_offset: u128,
-    reader: Take<File>,
+    archive: & 'a Archive<S>,
+    position: u64,
+    salt: Option<[u8; SALT_SIZE]>,
+    content: Content,
+
+    //The stored file data. For chunked files, this is the chunk that was last read, with its index and where each chunk starts in the file
+    data: Extent,
+    chunk: Option<usize>,
+    chunk_starts: Vec<u64>,
 }
 
-impl<'a> Entry<'a> {
-    pub (in crate) fn new(path: & 'a Path, header_offset: u128, archive: &Archive) -> Self {
+impl<'a, S: Storage> Entry<'a, S> {
+    pub (in crate) fn new(path: & 'a Path, header_offset: u128, archive: & 'a Archive<S>) -> Result<Self> {
+        Self::read_header(path, header_offset, archive).map_err(|e| archive.error_context(e.with_entry(path).with_offset(header_offset)))
+    }
 
-        let mut file = OpenOptions::new().read(true).open(archive.path()).unwrap();
+    fn read_header(path: & 'a Path, header_offset: u128, archive: & 'a Archive<S>) -> Result<Self> {
 
-        file.seek(SeekFrom::Start(header_offset as u64)).unwrap();
+        let mut storage = archive.storage();
 
-        let header: Metadata = bincode::deserialize_from(&file).unwrap();
+        storage.seek(SeekFrom::Start(header_offset as u64))?;
 
-        let file_offset = file.stream_position().unwrap() as u128;
+        let header = EntryHeader::read_from(& mut *storage, archive.metadata_key())?;
 
-        file.seek(SeekFrom::Start(file_offset as u64)).unwrap();
+        if header.path.as_path() != path {
+            return Err(Error::new(ErrorKind::HeaderPathMismatch(header.path.as_path().to_path_buf()), format!("Header contains a different path ({}) to the toc", header.path.as_path().display())));
+        }
 
-        let reader = file.take( header.len() as u64);
+        if header.salt.is_some() != archive.is_encrypted() {
+            return Err(Error::new(ErrorKind::AuthenticationFailed, String::from("Header encryption does not match the archive")));
+        }
 
-        Self {
-            path,
-            header,
-            file_offset,
-            reader
+        if let Content::Chunk(_) = header.content {
+            return Err(Error::new(ErrorKind::HeaderPathMismatch(header.path.as_path().to_path_buf()), String::from("Header is a chunk of a file rather than a file")));
         }
+
+        let data_offset = storage.stream_position()? as u128;
+
+        //Shared data is found through the blobs, which needs the storage
+        drop(storage);
+
+        let data = match header.content {
+            Content::Shared(hash) => resolve_blob(archive, hash, header.metadata.len())?,
+            _ => {
+                let data = Extent::new(archive, data_offset, header.metadata.len(), header.salt);
+
+                data.check_end(archive, header.data_len())?;
+
+                data
+            }
+        };
+
+        Ok(Self {
+            path,
+            header: header.metadata,
+            header_offset,
+            file_offset: data.offset,
+            archive,
+            position: 0,
+            salt: data.salt,
+            chunk_starts: chunk_starts(&header.content),
+            content: header.content,
+            data,
+            chunk: None,
+        })
+    }
+
+    pub (in crate) fn from_handle(handle: & 'a EntryHandle, archive: & 'a Archive<S>) -> Result<Self> {
+
+        //The header is already known, so there is no need to read it again
+        Ok(Self {
+            path: handle.path(),
+            header: handle.header().clone(),
+            header_offset: handle.header_offset(),
+            file_offset: handle.file_offset(),
+            archive,
+            position: 0,
+            salt: handle.salt(),
+            content: handle.content().clone(),
+            data: Extent::new(archive, handle.file_offset(), handle.header().len(), handle.salt()),
+            chunk: None,
+            chunk_starts: chunk_starts(handle.content()),
+        })
     }
 
     ///Get the path of the file within the archive
@@ -46,34 +110,225 @@ impl<'a> Entry<'a> {
         &self.header
     }
 
-    //Get the location of the file data itself
-    //fn offset(&self) -> u128 { self.file_offset }
+    ///Create an owned handle to this entry that does not borrow the archive or path
+    ///
+    /// See [`EntryHandle`] for more information
+    pub fn handle(&self) -> EntryHandle {
+        EntryHandle::new(self.path.to_path_buf(), self.header.clone(), self.header_offset, self.file_offset, self.salt, self.content.clone())
+    }
+
+    ///Get where the file data is stored
+    pub (in crate) fn content(&self) -> &Content {
+        &self.content
+    }
+
+    pub (in crate) fn archive(&self) -> & 'a Archive<S> {
+        self.archive
+    }
+
+    ///Returns true if the file data is encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.salt.is_some() && self.archive.key.is_some()
+    }
+
+    ///Read from the current position, which is at most `buf.len()` bytes before the end of the file
+    fn read_data(& mut self, buf: & mut [u8]) -> Result<usize> {
+
+        //Chunked files are read one chunk at a time, finding the chunk containing the position first
+        let position = match &self.content {
+            Content::Chunked(chunks) => {
+                let index = match self.chunk_starts.binary_search(&self.position) {
+                    Ok(index) => index,
+                    //Only a corrupt chunk list has no chunk that starts at or before the position
+                    Err(index) => index.checked_sub(1).ok_or_else(|| Error::new(ErrorKind::ChunkNotFound(self.position), format!("No chunk contains position {} of the file", self.position)))?,
+                };
+
+                if self.chunk != Some(index) {
+                    self.data = resolve_blob(self.archive, chunks[index].hash, chunks[index].len as u128)?;
+                    self.chunk = Some(index);
+                }
+
+                self.position - self.chunk_starts[index]
+            }
+            _ => self.position,
+        };
+
+        let read = self.data.read_at(self.archive, position, buf)?;
+
+        self.position += read as u64;
+
+        Ok(read)
+    }
 }
 
 ///Used to access the archive file data
-impl<'a> Read for Entry<'a> {
+impl<'a, S: Storage> Read for Entry<'a, S> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.reader.read(buf)
+
+        let remaining = (self.header.len() as u64).saturating_sub(self.position);
+
+        let length = remaining.min(buf.len() as u64) as usize;
+
+        if length == 0 {
+            return Ok(0);
+        }
+
+        self.read_data(& mut buf[..length]).map_err(|e| self.archive.error_context(e.with_entry(self.path)).into())
     }
 }
 
 ///Used to access the archive file data
-impl<'a> Seek for Entry<'a> {
+impl<'a, S: Storage> Seek for Entry<'a, S> {
     fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
 
-        match pos {
-            SeekFrom::Start(pos) => {
-                self.reader.set_limit(self.header.len() as u64 - pos);
-                let under = self.reader.get_mut();
-                under.seek(SeekFrom::Start(self.file_offset as u64 + pos))
-            }
-            SeekFrom::End(_) => {
-                todo!()
-            }
-            SeekFrom::Current(_) => {
-                todo!()
+        let length = self.header.len() as u64;
+
+        let new_position = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => length.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        //Seeking past the end is allowed, but any subsequent reads will return no data
+        self.position = new_position.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position"))?;
+
+        Ok(self.position)
+    }
+}
+
+///A run of file data stored after a header
+#[derive(Debug)]
+struct Extent {
+    offset: u128,
+    len: u128,
+    salt: Option<[u8; SALT_SIZE]>,
+
+    //The key for the data and the last chunk that was opened, if the archive is encrypted
+    key: Option<DataKey>,
+    chunk: Option<(u64, Vec<u8>)>,
+}
+
+impl Extent {
+    fn new<S: Storage>(archive: &Archive<S>, offset: u128, len: u128, salt: Option<[u8; SALT_SIZE]>) -> Self {
+        Self {
+            offset,
+            len,
+            salt,
+            key: derive_key(archive, salt),
+            chunk: None,
+        }
+    }
+
+    ///Check that the `data_len` bytes stored for the extent end before the toc, unless they start after it (as files appended by a
+    /// builder that has not been finalised do)
+    fn check_end<S: Storage>(&self, archive: &Archive<S>, data_len: u128) -> Result<()> {
+
+        let end = self.offset + data_len;
+
+        if self.offset < archive.toc_offset() && end > archive.toc_offset() {
+            return Err(Error::new(ErrorKind::EntryOverlapsToc(end), format!("File data ends ({}) after the start of the toc ({})", end, archive.toc_offset())));
+        }
+
+        Ok(())
+    }
+
+    ///Read from `position` within the extent, stopping at the end of the extent
+    fn read_at<S: Storage>(& mut self, archive: &Archive<S>, position: u64, buf: & mut [u8]) -> Result<usize> {
+
+        let length = (self.len as u64).saturating_sub(position).min(buf.len() as u64) as usize;
+
+        if let Some(key) = self.key.clone() {
+            return self.read_sealed(archive, &key, position, & mut buf[..length]);
+        }
+
+        //The storage is shared with other entries, so always seek before reading
+        let mut storage = archive.storage();
+
+        storage.seek(SeekFrom::Start(self.offset as u64 + position))?;
+
+        Ok(storage.read(& mut buf[..length])?)
+    }
+
+    ///Read from the chunk containing `position`, opening it first if it is not the last chunk that was opened
+    fn read_sealed<S: Storage>(& mut self, archive: &Archive<S>, key: &DataKey, position: u64, buf: & mut [u8]) -> Result<usize> {
+
+        let index = position / CHUNK_SIZE;
+
+        if !matches!(&self.chunk, Some((opened, _)) if *opened == index) {
+            let (offset, size) = chunk_range(self.len, index);
+
+            let offset = self.offset as u64 + offset;
+
+            let mut sealed = vec![0; size as usize];
+
+            {
+                let mut storage = archive.storage();
+
+                storage.seek(SeekFrom::Start(offset))?;
+                storage.read_exact(& mut sealed)?;
             }
+
+            let chunk = key.open_data_chunk(self.len, index, &sealed).map_err(|e| e.with_offset(offset as u128))?;
+
+            self.chunk = Some((index, chunk));
         }
+
+        let chunk = match &self.chunk {
+            Some((_, chunk)) => chunk,
+            None => return Ok(0),
+        };
+
+        let start = (position % CHUNK_SIZE) as usize;
+
+        let length = chunk.len().saturating_sub(start).min(buf.len());
+
+        buf[..length].copy_from_slice(&chunk[start..start + length]);
+
+        Ok(length)
     }
 }
 
+fn derive_key<S: Storage>(archive: &Archive<S>, salt: Option<[u8; SALT_SIZE]>) -> Option<DataKey> {
+    match (&archive.key, salt) {
+        (Some(key), Some(salt)) => Some(key.derive(&salt)),
+        _ => None,
+    }
+}
+
+///Find the shared data with content hash `hash`, which follows the header of another file or a chunk, and is `len` bytes long
+fn resolve_blob<S: Storage>(archive: &Archive<S>, hash: [u8; HASH_SIZE], len: u128) -> Result<Extent> {
+
+    let blob = archive.toc.blobs.get(&hash).ok_or_else(|| Error::new(ErrorKind::BlobNotFound(hash), String::from("Shared data does not exist in TOC")))?;
+
+    let mut storage = archive.storage();
+
+    storage.seek(SeekFrom::Start(blob.header as u64))?;
+
+    let owner = EntryHeader::read_from(& mut *storage, archive.metadata_key()).map_err(|e| e.with_offset(blob.header))?;
+
+    if !matches!(owner.content, Content::Blob(owned) | Content::Chunk(owned) if owned == hash) || owner.metadata.len() != len {
+        return Err(Error::new(ErrorKind::BlobNotFound(hash), format!("Header at the location of the shared data ({}) has different contents", blob.header)));
+    }
+
+    let data = Extent::new(archive, storage.stream_position()? as u128, len, owner.salt);
+
+    drop(storage);
+
+    data.check_end(archive, owner.data_len())?;
+
+    Ok(data)
+}
+
+///Where each chunk of a chunked file starts within the file
+fn chunk_starts(content: &Content) -> Vec<u64> {
+    match content {
+        Content::Chunked(chunks) => chunks.iter().scan(0, |start, chunk: &ChunkRef| {
+            let chunk_start = *start;
+
+            *start += chunk.len;
+
+            Some(chunk_start)
+        }).collect(),
+        _ => Vec::new(),
+    }
+}
@@ -1,5 +1,6 @@
 use std::fmt::{Display, Formatter};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use crate::crypto::Recipient;
 
 ///A specialised Result type for tarpdate
 pub type Result<T> =  std::result::Result<T, Error>;
@@ -17,7 +18,17 @@ pub enum TocEntryNotFoundReason {
     /// Serde returned an error when deserialising the toc
     ///
     /// Contains the bincode error
-    CouldNotDeserialiseToc(bincode::Error)
+    CouldNotDeserialiseToc(bincode::Error),
+
+    ///Archive was streamed, but the trailer containing the toc offset could not be found
+    ///
+    /// Contains the trailer magic number found
+    BadTrailer(u128),
+
+    ///Something other than a toc was found at the toc offset
+    ///
+    /// Contains the toc magic number found
+    BadTocMagicNumber(u64),
 }
 
 ///A list of possible tarpdate errors
@@ -29,6 +40,10 @@ pub enum ErrorKind {
     ///An error from [`bincode`]
     Bincode(bincode::Error),
 
+    ///An error from [`zip`] when reading or writing a ZIP archive, other than an I/O error
+    #[cfg(feature = "zip")]
+    Zip(zip::result::ZipError),
+
     ///Magic number does not match.
     ///
     /// Contains a `u128` with the magic number found
@@ -39,6 +54,26 @@ pub enum ErrorKind {
     /// Contains the reason that the toc could not be loaded
     TocEntryNotFound(TocEntryNotFoundReason),
 
+    ///Header magic number does not match, so there is no header at the expected location.
+    ///
+    /// Contains a `u64` with the magic number found
+    BadHeaderMagicNumber(u64),
+
+    ///The path stored in a header does not match the path in the toc
+    ///
+    /// Contains the path found in the header
+    HeaderPathMismatch(PathBuf),
+
+    ///The data for a file continues past the start of the toc
+    ///
+    /// Contains the position of the end of the file data
+    EntryOverlapsToc(u128),
+
+    ///An entry in another archive format could not be converted
+    ///
+    /// Contains a description of the entry type
+    UnsupportedEntry(String),
+
     ///Table contains or is trying to write an unsafe path
     ///
     /// Contains the offending path
@@ -48,13 +83,126 @@ pub enum ErrorKind {
     ///
     /// Contains the offending path
     PathConflict(PathBuf),
+
+    ///No entry exists in the TOC with the given path
+    ///
+    /// Contains the path that was not found
+    NotFound(PathBuf),
+
+    ///Archive is too small to contain the magic number and toc offset
+    ///
+    /// Contains the size of the file
+    Truncated(u128),
+
+    ///Data was found after the end of the toc
+    ///
+    /// Contains the position of the end of the toc and the size of the file
+    DataPastToc(u128, u128),
+
+    ///Archive is encrypted, so it must be opened with a key (see [`crate::archive::Archive::open_encrypted`] and [`crate::archive::Archive::open_keyed`])
+    KeyRequired,
+
+    ///A key was given to open an archive that is not encrypted
+    NotEncrypted,
+
+    ///None of the keys stored in the archive can be unlocked with the given keys
+    WrongKey,
+
+    ///Encrypted data could not be authenticated, so it has been modified or corrupted since it was written
+    AuthenticationFailed,
+
+    ///The keys that unlock an encrypted archive do not fit in the space reserved for them
+    ///
+    /// Contains the size needed, in bytes
+    KeyBlockFull(u128),
+
+    ///A recipient or identity could not be parsed
+    InvalidKey(String),
+
+    ///The recipient to remove cannot unlock the archive
+    RecipientNotFound(Recipient),
+
+    ///Removing the key would leave nothing that can unlock the archive
+    LastKey,
+
+    ///Archive has no signature, see [`crate::archive::Archive::sign`]
+    NotSigned,
+
+    ///Archive was signed by a different key, or has been modified since it was signed
+    BadSignature,
+
+    ///The data a file shares with other files could not be found
+    ///
+    /// Contains the content hash of the data
+    BlobNotFound([u8; 32]),
+
+    ///The data of a file, or of one of its chunks, does not match the content hash stored in its header
+    ///
+    /// Contains the stored content hash
+    ContentHashMismatch([u8; 32]),
+
+    ///The chunk list of a chunked file has no chunk containing a position within the file, so the archive is corrupt
+    ///
+    /// Contains the position within the file
+    ChunkNotFound(u64),
+
+    ///The chunk sizes given to [`crate::builder::Chunking::new`] are out of range
+    ///
+    /// Contains the minimum, average and maximum sizes
+    InvalidChunkSizes(u32, u32, u32),
+
+    ///The archive has no generation with the given number, as it was never written or its history has been discarded
+    ///
+    /// Contains the generation number
+    GenerationNotFound(u64),
+
+    ///A glob pattern could not be parsed
+    ///
+    /// Contains the pattern
+    InvalidPattern(String),
+
+    ///The volume size is zero, or differs from the size of the volumes an archive has already been split into
+    ///
+    /// Contains the volume size
+    InvalidVolumeSize(u64),
+
+    ///An archive has more than one volume, but the size of its first volume does not match the volume size recorded in the toc
+    ///
+    /// Contains the size of the first volume and the recorded volume size, if any
+    VolumeSizeMismatch(u64, Option<u64>),
+
+    ///The options given to [`crate::parity::ParityOptions::new`] are out of range
+    ///
+    /// Contains the overhead percentage and the shard size
+    InvalidParityOptions(u8, u32),
+
+    ///A parity set is damaged, or is not a parity set
+    BadParity,
+
+    ///Too many shards of a stripe are damaged for the parity to reconstruct them
+    ///
+    /// Contains the offset of the first damaged shard in the stripe
+    Unrepairable(u64),
 }
 
 ///An error type encapsulating possible errors from tarpdata operations
+///
+/// As well as the [`ErrorKind`], errors may carry context describing where the error occurred (the archive path, the path
+/// of the entry within the archive and the byte offset within the archive). Errors can be converted into [`std::io::Error`]
+/// so they can be returned from [`std::io::Read`] implementations, and recovered with [`std::io::Error::into_inner`].
 #[derive(Debug)]
 pub struct Error {
     kind: ErrorKind,
     error: String,
+    context: Option<Box<Context>>,
+}
+
+///Where an error occurred. Boxed, as most errors carry little or no context
+#[derive(Debug, Default)]
+struct Context {
+    archive: Option<PathBuf>,
+    entry: Option<PathBuf>,
+    offset: Option<u128>,
 }
 
 impl Error {
@@ -62,23 +210,96 @@ impl Error {
         Self {
             kind,
             error,
+            context: None,
         }
     }
+
+    fn context_mut(&mut self) -> &mut Context {
+        self.context.get_or_insert_with(Default::default)
+    }
+
+    ///Attach the path of the archive to the error, unless one is already attached
+    pub fn with_archive<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.context_mut().archive.get_or_insert_with(|| PathBuf::from(path.as_ref()));
+        self
+    }
+
+    ///Attach the path of the entry within the archive to the error, unless one is already attached
+    pub fn with_entry<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.context_mut().entry.get_or_insert_with(|| PathBuf::from(path.as_ref()));
+        self
+    }
+
+    ///Attach a byte offset within the archive to the error, unless one is already attached
+    pub fn with_offset(mut self, offset: u128) -> Self {
+        self.context_mut().offset.get_or_insert(offset);
+        self
+    }
+
+    ///Get the kind of error
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    ///Get the path of the archive the error occurred in, if known
+    pub fn archive_path(&self) -> Option<&Path> {
+        self.context.as_ref()?.archive.as_deref()
+    }
+
+    ///Get the path of the entry the error occurred in, if known
+    pub fn entry_path(&self) -> Option<&Path> {
+        self.context.as_ref()?.entry.as_deref()
+    }
+
+    ///Get the byte offset within the archive that the error occurred at, if known
+    pub fn offset(&self) -> Option<u128> {
+        self.context.as_ref()?.offset
+    }
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Error: {:?} - {}", self.kind, self.error)
+        write!(f, "Error: {:?} - {}", self.kind, self.error)?;
+
+        if let Some(archive) = self.archive_path() {
+            write!(f, " (archive: {})", archive.display())?;
+        }
+
+        if let Some(entry) = self.entry_path() {
+            write!(f, " (entry: {})", entry.display())?;
+        }
+
+        if let Some(offset) = self.offset() {
+            write!(f, " (offset: {})", offset)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            ErrorKind::IO(e) => Some(e),
+            ErrorKind::Bincode(e) => Some(e),
+            #[cfg(feature = "zip")]
+            ErrorKind::Zip(e) => Some(e),
+            ErrorKind::TocEntryNotFound(TocEntryNotFoundReason::CouldNotDeserialiseToc(e)) => Some(e),
+            _ => None,
+        }
     }
 }
 
+///Errors that were converted into an [`std::io::Error`] (such as those returned when reading an entry) are unwrapped
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Self {
-        let error = e.to_string();
-        Self {
-            kind: ErrorKind::IO(e),
-            error,
+        if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return *e.into_inner().and_then(|inner| inner.downcast::<Error>().ok()).expect("checked above");
         }
+
+        let error = e.to_string();
+
+        Self::new(ErrorKind::IO(e), error)
     }
 }
 
@@ -86,9 +307,38 @@ impl From<bincode::Error> for Error {
     fn from(e: bincode::Error) -> Self {
         let error = e.to_string();
 
-        Self {
-            kind: ErrorKind::Bincode(e),
-            error,
+        Self::new(ErrorKind::Bincode(e), error)
+    }
+}
+
+///I/O errors are unwrapped, so they are reported the same way as I/O errors from anywhere else
+#[cfg(feature = "zip")]
+impl From<zip::result::ZipError> for Error {
+    fn from(e: zip::result::ZipError) -> Self {
+        match e {
+            zip::result::ZipError::Io(e) => e.into(),
+            e => {
+                let error = e.to_string();
+
+                Self::new(ErrorKind::Zip(e), error)
+            }
         }
     }
 }
+
+///Wraps the error in an [`std::io::Error`] with the closest matching [`std::io::ErrorKind`]
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        let kind = match &e.kind {
+            ErrorKind::IO(io) => io.kind(),
+            ErrorKind::NotFound(_) | ErrorKind::GenerationNotFound(_) => std::io::ErrorKind::NotFound,
+            ErrorKind::PathConflict(_) => std::io::ErrorKind::AlreadyExists,
+            ErrorKind::UnsafePath(_) | ErrorKind::InvalidKey(_) | ErrorKind::InvalidChunkSizes(..) | ErrorKind::InvalidPattern(_)
+                | ErrorKind::InvalidVolumeSize(_) | ErrorKind::InvalidParityOptions(..) => std::io::ErrorKind::InvalidInput,
+            ErrorKind::KeyRequired | ErrorKind::WrongKey => std::io::ErrorKind::PermissionDenied,
+            _ => std::io::ErrorKind::InvalidData,
+        };
+
+        std::io::Error::new(kind, e)
+    }
+}
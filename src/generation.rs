@@ -0,0 +1,402 @@
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Serialize, Deserialize};
+use crate::archive::{Archive, Compaction, read_header_at};
+use crate::toc::{TOC, Blob};
+use crate::storage::Storage;
+use crate::error::{Result, Error, ErrorKind};
+
+///A point in the history of an archive, recorded each time the archive is changed
+///
+/// Every change to an archive (adding, removing, renaming or undeleting files, or signing it) writes a new toc after the toc of
+/// the previous generation rather than over it, so the archive can still be read as it was, see [`Archive::open_generation`].
+/// [`Archive::defrag`] discards the history, keeping only the current generation, and [`Archive::prune`] discards the generations
+/// a [`Retention`] policy does not keep.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Generation {
+    pub (in crate) number: u64,
+    pub (in crate) time: SystemTime,
+    pub (in crate) label: Option<String>,
+}
+
+impl Generation {
+    ///The generation of a new archive
+    pub (in crate) fn first() -> Self {
+        Self {
+            number: 0,
+            time: SystemTime::now(),
+            label: None,
+        }
+    }
+
+    ///The generation that follows this one, without a label
+    pub (in crate) fn next(&self) -> Self {
+        Self {
+            number: self.number + 1,
+            time: SystemTime::now(),
+            label: None,
+        }
+    }
+
+    ///The number of the generation, counting up from zero when the archive was created
+    pub fn number(&self) -> u64 { self.number }
+
+    ///When the generation was written
+    pub fn time(&self) -> SystemTime { self.time }
+
+    ///The label given to the generation with [`Archive::set_label`], if any
+    pub fn label(&self) -> Option<&str> { self.label.as_deref() }
+}
+
+///Which generations [`Archive::prune`] keeps
+///
+/// The current generation is always kept. Every other generation is kept if any of the rules keeps it. Days and weeks are in UTC,
+/// counted back from the day of the current generation, and weeks start on Monday.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Retention {
+    last: usize,
+    daily: u64,
+    weekly: u64,
+    labelled: bool,
+}
+
+impl Retention {
+    ///Keep only the current generation
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///Keep the latest `count` generations, including the current generation
+    pub fn with_last(mut self, count: usize) -> Self {
+        self.last = count;
+        self
+    }
+
+    ///Keep the latest generation of each day, for `days` days
+    pub fn with_daily(mut self, days: u64) -> Self {
+        self.daily = days;
+        self
+    }
+
+    ///Keep the latest generation of each week, for `weeks` weeks
+    pub fn with_weekly(mut self, weeks: u64) -> Self {
+        self.weekly = weeks;
+        self
+    }
+
+    ///Keep every generation that has a label, see [`Archive::set_label`]
+    pub fn with_labelled(mut self) -> Self {
+        self.labelled = true;
+        self
+    }
+
+    ///Get the number of latest generations that are kept
+    pub fn last(&self) -> usize { self.last }
+
+    ///Get the number of days that the latest generation of each day is kept for
+    pub fn daily(&self) -> u64 { self.daily }
+
+    ///Get the number of weeks that the latest generation of each week is kept for
+    pub fn weekly(&self) -> u64 { self.weekly }
+
+    ///Returns true if generations with a label are kept
+    pub fn labelled(&self) -> bool { self.labelled }
+
+    ///Get the numbers of the generations to keep from `generations`, which are newest first as returned by [`Archive::generations`]
+    pub fn select(&self, generations: &[Generation]) -> Vec<u64> {
+
+        let day = |generation: &Generation| generation.time.duration_since(UNIX_EPOCH).map(|time| time.as_secs() / SECONDS_PER_DAY).unwrap_or(0);
+
+        //1970-01-01 was a Thursday, so weeks are offset by 3 days to start on Monday
+        let week = |generation: &Generation| (day(generation) + 3) / 7;
+
+        let today = generations.first().map(day).unwrap_or(0);
+        let this_week = generations.first().map(week).unwrap_or(0);
+
+        let mut days = HashSet::new();
+        let mut weeks = HashSet::new();
+
+        generations.iter().enumerate().filter(|(index, generation)| {
+
+            //Generations are newest first, so the first generation seen on each day or week is the latest
+            let daily = today - day(generation).min(today) < self.daily && days.insert(day(generation));
+            let weekly = this_week - week(generation).min(this_week) < self.weekly && weeks.insert(week(generation));
+
+            *index == 0 || *index < self.last || (self.labelled && generation.label.is_some()) || daily || weekly
+        })
+        .map(|(_, generation)| generation.number)
+        .collect()
+    }
+}
+
+///The result of pruning the earlier generations of an archive, see [`Archive::prune`]
+#[derive(Debug, Default)]
+pub struct PruneReport {
+    ///Numbers of the generations that were kept, newest first
+    pub kept: Vec<u64>,
+
+    ///Numbers of the generations that were discarded, newest first
+    pub removed: Vec<u64>,
+
+    ///The number of bytes the archive shrank by
+    pub reclaimed: u128,
+}
+
+///The storage of a read-only view of an earlier generation, see [`Archive::open_generation`]
+///
+/// Reads go to the storage of the archive the view was opened from. Writing fails with [`std::io::ErrorKind::PermissionDenied`],
+/// so any attempt to change the view fails without changing the archive.
+#[derive(Debug)]
+pub struct GenerationStorage<'a, S: Storage> {
+    storage: & 'a Mutex<S>,
+    position: u64,
+}
+
+impl<S: Storage> Read for GenerationStorage<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut storage = self.storage.lock().unwrap_or_else(PoisonError::into_inner);
+
+        //The storage is shared with the archive, so always seek before reading
+        storage.seek(SeekFrom::Start(self.position))?;
+
+        let read = storage.read(buf)?;
+
+        self.position += read as u64;
+
+        Ok(read)
+    }
+}
+
+impl<S: Storage> Seek for GenerationStorage<'_, S> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.storage.lock().unwrap_or_else(PoisonError::into_inner).seek(SeekFrom::End(0))?.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        self.position = new_position.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position"))?;
+
+        Ok(self.position)
+    }
+}
+
+impl<S: Storage> Write for GenerationStorage<'_, S> {
+    fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+        Err(read_only())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<S: Storage> Storage for GenerationStorage<'_, S> {
+    fn set_len(&mut self, _: u64) -> std::io::Result<()> {
+        Err(read_only())
+    }
+}
+
+fn read_only() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::PermissionDenied, "earlier generations of an archive are read-only")
+}
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+impl<S: Storage> Archive<S> {
+
+    ///Get the current generation of the archive
+    pub fn generation(&self) -> &Generation {
+        &self.toc.generation
+    }
+
+    ///Get every generation still in the archive, starting with the current generation
+    pub fn generations(&self) -> Result<Vec<Generation>> {
+
+        let history = self.history().map_err(|e| self.error_context(e))?;
+
+        Ok(std::iter::once(self.toc.generation.clone()).chain(history.into_iter().map(|(_, toc)| toc.generation)).collect())
+    }
+
+    ///Label the current generation, replacing any previous label
+    ///
+    /// The toc of the current generation is rewritten, so labelling does not create a new generation
+    pub fn set_label<L: Into<String>>(& mut self, label: L) -> Result<()> {
+
+        self.toc.generation.label = Some(label.into());
+
+        self.rewrite_toc()
+    }
+
+    ///Open a read-only view of the archive as it was at generation `number`
+    ///
+    /// The view is an archive like any other, so files can be listed, read, verified and exported, but any change fails with
+    /// an I/O error, see [`GenerationStorage`].
+    ///
+    /// Fails with [`ErrorKind::GenerationNotFound`] if the generation is not in the archive
+    pub fn open_generation(&self, number: u64) -> Result<Archive<GenerationStorage<'_, S>>> {
+
+        let (toc, toc_offset) = self.find_generation(number).map_err(|e| self.error_context(e))?;
+
+        Ok(self.view(GenerationStorage {
+            storage: &self.storage,
+            position: 0,
+        }, toc, toc_offset))
+    }
+
+    ///Restore a file that was removed from the archive, from the latest generation that contains it
+    ///
+    /// The file is restored with the same data and metadata, and the number of the generation it was restored from is returned.
+    /// Undeleting creates a new generation.
+    ///
+    /// Fails with [`ErrorKind::PathConflict`] if the path is already in the archive, or [`ErrorKind::NotFound`] if no generation
+    /// still in the archive contains it
+    pub fn undelete<P: AsRef<Path>>(& mut self, path: P) -> Result<u64> {
+
+        let number = self.undelete_entry(path.as_ref(), None).map_err(|e| self.error_context(e.with_entry(path)))?;
+
+        self.write_toc()?;
+
+        Ok(number)
+    }
+
+    ///Restore `path` to the in-memory toc, without writing a new generation
+    ///
+    /// `current` is the toc of the current generation if the in-memory toc has been changed without writing it (by a
+    /// [`crate::batch::Batch`]), so files removed since can be restored from it
+    pub (in crate) fn undelete_entry(& mut self, path: &Path, current: Option<&TOC>) -> Result<u64> {
+
+        if self.contains(path) {
+            return Err(Error::new(ErrorKind::PathConflict(PathBuf::from(path)), format!("Could not undelete entry ({}), as path already exists in TOC", path.display())));
+        }
+
+        let mut tocs = current.map(|toc| vec![(self.toc_offset, toc.clone())]).unwrap_or_default();
+
+        tocs.extend(self.history()?);
+
+        for (_, toc) in tocs {
+            let (path, offset) = match toc._table.get_key_value(path) {
+                Some((path, offset)) => (path.clone(), *offset),
+                None => continue,
+            };
+
+            let header = read_header_at(& mut *self.storage(), offset, self.metadata_key()).map_err(|e| e.with_offset(offset))?;
+
+            let uses = header.content.uses();
+
+            //Shared data that no file in the current generation uses must be found through the old generation
+            for hash in &uses {
+                if !self.toc.blobs.contains_key(hash) && !toc.blobs.contains_key(hash) {
+                    return Err(Error::new(ErrorKind::BlobNotFound(*hash), String::from("Shared data does not exist in TOC")));
+                }
+            }
+
+            for hash in uses {
+                let blob = self.toc.blobs.entry(hash).or_insert_with(|| Blob {
+                    header: toc.blobs[&hash].header,
+                    refs: 0,
+                });
+
+                blob.refs += 1;
+            }
+
+            self.toc._table.insert(path, offset);
+
+            //The signature no longer matches the toc
+            self.toc.signature = None;
+
+            return Ok(toc.generation.number);
+        }
+
+        Err(Error::new(ErrorKind::NotFound(PathBuf::from(path)), format!("Could not undelete entry ({}), as no earlier generation contains it", path.display())))
+    }
+
+    ///Discard the earlier generations that `retention` does not keep, and move the data in the archive forward to fill the gaps
+    /// left by files that no kept generation uses
+    ///
+    /// Like [`Archive::defrag`], any [`crate::handle::EntryHandle`] obtained before pruning is invalidated, and if the application
+    /// fails while pruning, the archive can be recovered with [`Archive::repair`]. Pruning does not create a new generation.
+    pub fn prune(& mut self, retention: &Retention) -> Result<PruneReport> {
+        self.prune_generations(retention).map_err(|e| self.error_context(e))
+    }
+
+    ///Get the report that [`Archive::prune`] would return, without changing the archive
+    pub fn prune_report(&self, retention: &Retention) -> Result<PruneReport> {
+        self.plan_prune(retention).map(|(report, _)| report).map_err(|e| self.error_context(e))
+    }
+
+    fn prune_generations(& mut self, retention: &Retention) -> Result<PruneReport> {
+
+        let (report, compaction) = self.plan_prune(retention)?;
+
+        self.compact(compaction)?;
+
+        Ok(report)
+    }
+
+    fn plan_prune(&self, retention: &Retention) -> Result<(PruneReport, Compaction)> {
+
+        let history = self.history()?;
+
+        let generations = std::iter::once(self.toc.generation.clone()).chain(history.iter().map(|(_, toc)| toc.generation.clone())).collect::<Vec<_>>();
+
+        let kept = retention.select(&generations);
+
+        let removed = generations.iter().map(Generation::number).filter(|number| !kept.contains(number)).collect();
+
+        let history = history.into_iter().map(|(_, toc)| toc).filter(|toc| kept.contains(&toc.generation.number)).collect();
+
+        let compaction = self.compaction(history)?;
+
+        let length = self.storage().seek(SeekFrom::End(0))? as u128;
+
+        let report = PruneReport {
+            kept,
+            removed,
+            reclaimed: length.saturating_sub(compaction.end),
+        };
+
+        Ok((report, compaction))
+    }
+
+    ///Find the toc of generation `number`, and its offset
+    fn find_generation(&self, number: u64) -> Result<(TOC, u128)> {
+
+        //The current toc is read again, as the view needs a toc of its own
+        if number == self.toc.generation.number {
+            return Ok((self.read_toc_at(self.toc_offset)?, self.toc_offset));
+        }
+
+        self.history()?.into_iter()
+            .find(|(_, toc)| toc.generation.number == number)
+            .map(|(offset, toc)| (toc, offset))
+            .ok_or_else(|| Error::new(ErrorKind::GenerationNotFound(number), format!("Generation {} is not in the archive", number)))
+    }
+
+    ///Read the toc of every earlier generation still in the archive, with its offset, starting with the previous generation
+    fn history(&self) -> Result<Vec<(u128, TOC)>> {
+
+        let mut history = Vec::new();
+
+        let mut next = self.toc.previous.map(|previous| (self.toc_offset, previous));
+
+        while let Some((offset, previous)) = next {
+            //Each toc is written after the previous one, so a link to a later toc is corrupt and could loop forever
+            if previous >= offset {
+                break;
+            }
+
+            let toc = self.read_toc_at(previous)?;
+
+            next = toc.previous.map(|earlier| (previous, earlier));
+
+            history.push((previous, toc));
+        }
+
+        Ok(history)
+    }
+
+}
@@ -0,0 +1,92 @@
+use std::path::{Path, PathBuf};
+use crate::header::{Metadata, Content, data_len};
+use crate::crypto::SALT_SIZE;
+use crate::archive::Archive;
+use crate::entry::Entry;
+use crate::storage::Storage;
+use crate::error::Result;
+
+///An owned reference to an archived file
+///
+/// Unlike [`Entry`], a handle does not borrow from the archive or from the caller's path, so it can be cloned, stored
+/// in structs or sent to other threads. Call [`EntryHandle::open`] to read the file data from the archive.
+#[derive(Debug, Clone)]
+pub struct EntryHandle {
+    path: PathBuf,
+    header: Metadata,
+    header_offset: u128,
+    file_offset: u128,
+    salt: Option<[u8; SALT_SIZE]>,
+    content: Content,
+}
+
+impl EntryHandle {
+    pub (in crate) fn new(path: PathBuf, header: Metadata, header_offset: u128, file_offset: u128, salt: Option<[u8; SALT_SIZE]>, content: Content) -> Self {
+        Self {
+            path,
+            header,
+            header_offset,
+            file_offset,
+            salt,
+            content,
+        }
+    }
+
+    ///Get the path of the file within the archive
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    ///Get the metadata for the archived file
+    pub fn header(&self) -> &Metadata {
+        &self.header
+    }
+
+    ///Get the location of the file header within the archive
+    pub fn header_offset(&self) -> u128 {
+        self.header_offset
+    }
+
+    ///Get the location of the file data within the archive
+    ///
+    /// If the data is shared with another file, this is the location of the data after the other file's header. Chunked files
+    /// have no data of their own, so this is the end of the header
+    pub fn file_offset(&self) -> u128 {
+        self.file_offset
+    }
+
+    ///Returns true if the data is shared with another file with the same contents, rather than stored after this file's header
+    pub fn is_shared(&self) -> bool {
+        matches!(self.content, Content::Shared(_))
+    }
+
+    ///Returns true if the file is stored as a list of chunks, which may be shared with other files, see [`crate::builder::Chunking`]
+    pub fn is_chunked(&self) -> bool {
+        matches!(self.content, Content::Chunked(_))
+    }
+
+    ///The salt the key for the file data is derived from, if the archive is encrypted
+    pub (in crate) fn salt(&self) -> Option<[u8; SALT_SIZE]> {
+        self.salt
+    }
+
+    ///Where the file data is stored
+    pub (in crate) fn content(&self) -> &Content {
+        &self.content
+    }
+
+    ///The number of bytes of file data stored in the archive at [`EntryHandle::file_offset`], which is larger than the file if it is encrypted, or zero if it is chunked
+    pub fn data_len(&self) -> u128 {
+        match self.content {
+            Content::Chunked(_) => 0,
+            _ => data_len(&self.header, self.salt.is_some()),
+        }
+    }
+
+    ///Reopen the handle for reading against `archive`
+    ///
+    /// The handle must have been obtained from the same archive, and the archive must not have been defragmented since.
+    pub fn open<'h, S: Storage>(&'h self, archive: &'h Archive<S>) -> Result<Entry<'h, S>> {
+        Entry::from_handle(self, archive)
+    }
+}
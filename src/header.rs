@@ -2,9 +2,17 @@
 
 use serde::{Serialize, Deserialize};
 use std::time::SystemTime;
+use std::path::{Path, PathBuf};
+use std::io::{Read, Write};
+use crate::safepath::SafePathBuf;
+use crate::crypto::{DataKey, Sealed, SALT_SIZE, HASH_SIZE, HEADER_AAD, sealed_len};
+use crate::error::{Result, Error, ErrorKind};
+
+///Written at the start of every header, so that headers can be told apart from the toc when reading an archive sequentially
+pub (in crate) const HEADER_MAGIC_NUMBER: u64 = 0x9e3c51d7a2f46b18;
 
 ///Represents the file type
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FileType {
     ///Metadata is for a directory. (see [`std::fs::Metadata::is_dir`])
     Dir,
@@ -12,14 +20,20 @@ pub enum FileType {
     File,
     ///Metadata is for a symbolic link. (see [`std::fs::Metadata::is_symlink`])
     SystemLink,
+    ///Metadata is for a hard link to another file in the archive (see [`Metadata::link_target`])
+    HardLink,
 }
 
 ///A serialisable representation of file metadata
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Metadata {
     file_type: FileType,
     size: u128,
     permissions: u8,
+    mode: Option<u32>,
+    uid: Option<u64>,
+    gid: Option<u64>,
+    link_target: Option<PathBuf>,
     modified: Option<SystemTime>,
     accessed: Option<SystemTime>,
     created: Option<SystemTime>,
@@ -39,10 +53,24 @@ impl From<std::fs::FileType> for FileType {
 
 impl From<std::fs::Metadata> for Metadata {
     fn from(data: std::fs::Metadata) -> Self {
+
+        #[cfg(unix)]
+        let (mode, uid, gid) = {
+            use std::os::unix::fs::MetadataExt;
+            (Some(data.mode()), Some(data.uid() as u64), Some(data.gid() as u64))
+        };
+
+        #[cfg(not(unix))]
+        let (mode, uid, gid) = (None, None, None);
+
         Metadata {
             file_type: data.file_type().into(),
             size: data.len() as u128,
             permissions: if data.permissions().readonly() { 1 } else { 0 },
+            mode,
+            uid,
+            gid,
+            link_target: None,
             modified: data.modified().ok(),
             accessed: data.accessed().ok(),
             created: data.created().ok()
@@ -51,6 +79,242 @@ impl From<std::fs::Metadata> for Metadata {
 }
 
 impl Metadata {
+    ///Create metadata for a file of type `file_type` containing `size` bytes of data, with no permissions, owner or times
+    ///
+    /// Used with [`crate::builder::Builder::append_data`] to add files that do not come from the file system
+    pub fn new(file_type: FileType, size: u128) -> Self {
+        Metadata {
+            file_type,
+            size,
+            permissions: 0,
+            mode: None,
+            uid: None,
+            gid: None,
+            link_target: None,
+            modified: None,
+            accessed: None,
+            created: None,
+        }
+    }
+
+    ///Read the metadata of the symbolic link at `path`, with its target and no data, or `None` if `path` is not a symbolic link
+    ///
+    /// Used when appending files from the file system, so links are stored as links rather than as the file they point to
+    pub (in crate) fn symlink(path: &Path) -> std::io::Result<Option<Self>> {
+
+        let data = std::fs::symlink_metadata(path)?;
+
+        if !data.file_type().is_symlink() {
+            return Ok(None);
+        }
+
+        let metadata = Metadata {
+            size: 0,
+            ..data.into()
+        };
+
+        Ok(Some(metadata.with_link_target(std::fs::read_link(path)?)))
+    }
+
+    ///Set whether the file is read only
+    pub fn with_readonly(mut self, readonly: bool) -> Self {
+        self.permissions = if readonly { 1 } else { 0 };
+        self
+    }
+
+    ///Set the UNIX mode (permission and file type bits) of the file
+    pub fn with_mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    ///Set the UNIX user and group ids of the file owner
+    pub fn with_owner(mut self, uid: u64, gid: u64) -> Self {
+        self.uid = Some(uid);
+        self.gid = Some(gid);
+        self
+    }
+
+    ///Set the target of a symbolic or hard link
+    pub fn with_link_target<P: AsRef<Path>>(mut self, target: P) -> Self {
+        self.link_target = Some(PathBuf::from(target.as_ref()));
+        self
+    }
+
+    ///Turn the metadata into a hard link to `target` with no data, keeping the permissions, owner and times
+    pub (in crate) fn into_hard_link<P: AsRef<Path>>(self, target: P) -> Self {
+        Metadata {
+            file_type: FileType::HardLink,
+            size: 0,
+            ..self
+        }.with_link_target(target)
+    }
+
+    ///Set the last modification time of the file
+    pub fn with_modified(mut self, modified: SystemTime) -> Self {
+        self.modified = Some(modified);
+        self
+    }
+
+    ///Set the last access time of the file
+    pub fn with_accessed(mut self, accessed: SystemTime) -> Self {
+        self.accessed = Some(accessed);
+        self
+    }
+
     ///Length of the data in the file
     pub fn len(&self) -> u128 { self.size }
+
+    ///Returns true if the file contains no data
+    pub fn is_empty(&self) -> bool { self.size == 0 }
+
+    ///The type of the file
+    pub fn file_type(&self) -> FileType { self.file_type }
+
+    ///Returns true if the file was read only
+    pub fn readonly(&self) -> bool { self.permissions & 1 != 0 }
+
+    ///The UNIX mode (permission and file type bits) of the file, if known
+    pub fn mode(&self) -> Option<u32> { self.mode }
+
+    ///The UNIX user id of the file owner, if known
+    pub fn uid(&self) -> Option<u64> { self.uid }
+
+    ///The UNIX group id of the file owner, if known
+    pub fn gid(&self) -> Option<u64> { self.gid }
+
+    ///The target of a symbolic or hard link. Hard link targets are paths within the archive
+    pub fn link_target(&self) -> Option<&Path> { self.link_target.as_deref() }
+
+    ///The last modification time of the file, if known
+    pub fn modified(&self) -> Option<SystemTime> { self.modified }
+
+    ///The last access time of the file, if known
+    pub fn accessed(&self) -> Option<SystemTime> { self.accessed }
+
+    ///The creation time of the file, if known
+    pub fn created(&self) -> Option<SystemTime> { self.created }
+}
+
+///Where the data of an archived file is stored
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub (in crate) enum Content {
+    ///The data follows the header
+    Inline,
+
+    ///The data follows the header, and may be shared by other files with the same content hash
+    Blob([u8; HASH_SIZE]),
+
+    ///The data is shared with another file with the same content hash, found through the blobs in the toc. No data follows the header
+    Shared([u8; HASH_SIZE]),
+
+    ///The header is not a file, but one chunk of a chunked file, which follows the header. Chunks are not in the toc
+    Chunk([u8; HASH_SIZE]),
+
+    ///The file is split into chunks, found through the blobs in the toc. No data follows the header
+    Chunked(Vec<ChunkRef>),
+}
+
+impl Content {
+    ///The content hashes of the shared data that the file uses, with one hash for each use
+    pub (in crate) fn uses(&self) -> Vec<[u8; HASH_SIZE]> {
+        match self {
+            Content::Blob(hash) | Content::Shared(hash) => vec![*hash],
+            Content::Chunked(chunks) => chunks.iter().map(|chunk| chunk.hash).collect(),
+            Content::Inline | Content::Chunk(_) => Vec::new(),
+        }
+    }
+}
+
+///One chunk of a chunked file
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub (in crate) struct ChunkRef {
+    pub (in crate) hash: [u8; HASH_SIZE],
+    pub (in crate) len: u64,
+}
+
+///The header written before the data of each archived file
+///
+/// As well as the metadata, the header contains the path of the file, so files can be recovered by reading the archive sequentially without the toc.
+/// In encrypted archives, the header also contains the salt that the key for the file data is derived from.
+#[derive(Debug)]
+pub (in crate) struct EntryHeader {
+    pub (in crate) path: SafePathBuf,
+    pub (in crate) metadata: Metadata,
+    pub (in crate) salt: Option<[u8; SALT_SIZE]>,
+    pub (in crate) content: Content,
+}
+
+impl EntryHeader {
+    pub (in crate) fn new(path: SafePathBuf, metadata: Metadata, salt: Option<[u8; SALT_SIZE]>, content: Content) -> Self {
+        Self {
+            path,
+            metadata,
+            salt,
+            content,
+        }
+    }
+
+    ///The number of bytes of file data that follow the header, which is larger than the file if it is encrypted, or zero if the data is shared or chunked
+    pub (in crate) fn data_len(&self) -> u128 {
+        match self.content {
+            Content::Shared(_) | Content::Chunked(_) => 0,
+            _ => data_len(&self.metadata, self.salt.is_some()),
+        }
+    }
+
+    ///Write the header magic number, path and metadata
+    ///
+    /// If the archive encrypts its metadata, `key` is the archive data key and the header is sealed instead. Sealed headers have
+    /// no magic number, as it would show where each file starts.
+    pub (in crate) fn write_to<W: Write>(&self, mut writer: W, key: Option<&DataKey>) -> Result<()> {
+        if let Some(key) = key {
+            let sealed = key.seal(HEADER_AAD, &bincode::serialize(&(&self.path, &self.metadata, &self.salt, &self.content))?)?;
+
+            bincode::serialize_into(& mut writer, &sealed)?;
+
+            return Ok(());
+        }
+
+        bincode::serialize_into(& mut writer, &HEADER_MAGIC_NUMBER)?;
+        bincode::serialize_into(& mut writer, &(&self.path, &self.metadata, &self.salt, &self.content))?;
+        Ok(())
+    }
+
+    ///Read a header, failing with [`ErrorKind::BadHeaderMagicNumber`] if there is no header at the current position
+    ///
+    /// If the archive encrypts its metadata, `key` is the archive data key, and reading fails with [`ErrorKind::AuthenticationFailed`] instead
+    pub (in crate) fn read_from<R: Read>(mut reader: R, key: Option<&DataKey>) -> Result<Self> {
+        if let Some(key) = key {
+            let sealed: Sealed = bincode::deserialize_from(& mut reader)?;
+
+            let (path, metadata, salt, content) = bincode::deserialize(&key.open(HEADER_AAD, &sealed)?)?;
+
+            return Ok(Self::new(path, metadata, salt, content));
+        }
+
+        let magic_number: u64 = bincode::deserialize_from(& mut reader)?;
+
+        if magic_number != HEADER_MAGIC_NUMBER {
+            return Err(Error::new(ErrorKind::BadHeaderMagicNumber(magic_number), format!("Header magic number ({:#x}) does not match", magic_number)));
+        }
+
+        Self::read_after_magic_number(reader)
+    }
+
+    ///Read the rest of a header, after the magic number has already been read and checked
+    pub (in crate) fn read_after_magic_number<R: Read>(reader: R) -> Result<Self> {
+        let (path, metadata, salt, content) = bincode::deserialize_from(reader)?;
+
+        Ok(Self::new(path, metadata, salt, content))
+    }
+}
+
+///The number of bytes of data stored for a file with `metadata`
+pub (in crate) fn data_len(metadata: &Metadata, encrypted: bool) -> u128 {
+    if encrypted {
+        sealed_len(metadata.len())
+    } else {
+        metadata.len()
+    }
 }
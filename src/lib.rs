@@ -8,15 +8,62 @@ pub mod archive;
 ///Object used to add files to archive
 pub mod builder;
 
+///Changes to an archive written as a single generation
+pub mod batch;
+
 ///Iterator over files in archive
 pub mod entries;
 
 ///Object representing an archived file
 pub mod entry;
 
+///Owned handles to archived files
+pub mod handle;
+
 ///An archive-safe wrapper around Path
 pub mod safepath;
 
+///Creating archives in sinks that cannot seek
+pub mod stream;
+
+///Conversion between tarpdate and other archive formats
+pub mod convert;
+
+#[cfg(feature = "tar")]
+mod tarball;
+
+#[cfg(feature = "zip")]
+mod zipfile;
+
+///Encryption of archives
+pub mod crypto;
+
+///Signing archives
+pub mod signature;
+
+///Snapshot generations of archives
+pub mod generation;
+
+///Comparing archives to directories and other archives
+pub mod diff;
+
+pub use diff::diff;
+
+///Merging archives
+pub mod merge;
+
+///Copying parts of archives into new archives
+pub mod subset;
+
+///Backends that archives can be stored in
+pub mod storage;
+
+///Archives split into several files
+pub mod volume;
+
+///Reed-Solomon parity for repairing damaged archives
+pub mod parity;
+
 ///Objects used in tarpdate-specific errors
 pub mod error;
 
@@ -25,10 +72,13 @@ mod toc;
 #[cfg(test)]
 mod tests {
     use crate::archive::Archive;
-    use std::path::{PathBuf};
-    use std::io::{Read, Seek, SeekFrom};
+    use std::path::{Path, PathBuf};
+    use std::io::{Read, Seek, SeekFrom, Cursor};
     use crate::safepath::SafePathBuf;
+    use crate::stream::{StreamBuilder, StreamReader};
     use std::convert::TryFrom;
+    use crate::error::{ErrorKind, TocEntryNotFoundReason};
+    use crate::header::FileType;
 
     #[test]
     fn archive_test() {
@@ -44,7 +94,9 @@ mod tests {
 
         builder.finalise().unwrap();
 
-        for mut entry in archive.iter() {
+        for entry in archive.iter() {
+            let mut entry = entry.unwrap();
+
             println!("path: {:?}, header: {:?}", entry.path(), entry.header());
 
             let mut v = String::new();
@@ -60,7 +112,9 @@ mod tests {
 
         archive.remove("./b").unwrap();
 
-        for mut entry in archive.iter() {
+        for entry in archive.iter() {
+            let mut entry = entry.unwrap();
+
             println!("path: {:?}, header: {:?}", entry.path(), entry.header());
 
             let mut v = String::new();
@@ -74,6 +128,1880 @@ mod tests {
 
     }
 
+    #[test]
+    fn entry_handle() {
+        let path = "./test/handle.t";
+        let mut archive = Archive::create(path).unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.append("./test/a", "./a").unwrap();
+
+        builder.finalise().unwrap();
+
+        let handle = archive.handle("./a").unwrap().unwrap();
+
+        //Handles are owned, so they can be moved to another thread and reopened there
+        let data = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut v = String::new();
+
+                handle.clone().open(&archive).unwrap().read_to_string(& mut v).unwrap();
+
+                v
+            }).join().unwrap()
+        });
+
+        assert_eq!(data, std::fs::read_to_string("./test/a").unwrap());
+        assert_eq!(handle.path(), PathBuf::from("./a").as_path());
+    }
+
+    #[test]
+    fn fallible_lookup() {
+        let path = "./test/lookup.t";
+        let mut archive = Archive::create(path).unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.append("./test/a", "./a").unwrap();
+
+        assert!(matches!(builder.append("./test/b", "./a").unwrap_err().kind(), ErrorKind::PathConflict(_)));
+
+        builder.finalise().unwrap();
+
+        assert!(archive.contains("./a"));
+        assert!(!archive.contains("./b"));
+        assert!(archive.get("./b").unwrap().is_none());
+
+        let mut entry = archive.get("./a").unwrap().unwrap();
+
+        let mut v = String::new();
+
+        entry.seek(SeekFrom::End(-5)).unwrap();
+        entry.seek(SeekFrom::Current(1)).unwrap();
+        entry.read_to_string(& mut v).unwrap();
+
+        assert_eq!(v, "e a\n");
+        assert!(entry.seek(SeekFrom::Current(-100)).is_err());
+
+        assert!(matches!(archive.remove("./b").unwrap_err().kind(), ErrorKind::NotFound(_)));
+
+        archive.remove("./a").unwrap();
+
+        assert!(Archive::open(path).unwrap().get("./a").unwrap().is_none());
+    }
+
+    #[test]
+    fn corrupt_archive() {
+        assert!(matches!(Archive::open("./test/a").unwrap_err().kind(), ErrorKind::Truncated(_)));
+
+        let path = "./test/corrupt.t";
+
+        Archive::create(path).unwrap();
+
+        let mut bytes = std::fs::read(path).unwrap();
+
+        bytes.push(0);
+        std::fs::write(path, &bytes).unwrap();
+        assert!(matches!(Archive::open(path).unwrap_err().kind(), ErrorKind::DataPastToc(88, 89)));
+
+        bytes[16] = 0xff;
+        std::fs::write(path, &bytes).unwrap();
+        assert!(matches!(Archive::open(path).unwrap_err().kind(), ErrorKind::TocEntryNotFound(TocEntryNotFoundReason::TocOffsetPastEOF(_, _))));
+
+        bytes[0] = 0;
+        std::fs::write(path, &bytes).unwrap();
+        assert!(matches!(Archive::open(path).unwrap_err().kind(), ErrorKind::BadMagicNumber(_)));
+    }
+
+    #[test]
+    fn error_context() {
+        let path = "./test/context.t";
+        let mut archive = Archive::create(path).unwrap();
+
+        let error = archive.remove("./missing").unwrap_err();
+
+        assert_eq!(error.archive_path(), Some(PathBuf::from(path).as_path()));
+        assert!(error.to_string().contains("./test/context.t"));
+
+        let error = Archive::open("./test/missing.t").unwrap_err();
+
+        assert!(std::error::Error::source(&error).is_some());
+
+        //Errors can flow through io::Error and be recovered
+        let io_error = std::io::Error::from(error);
+
+        assert_eq!(io_error.kind(), std::io::ErrorKind::NotFound);
+
+        let error = io_error.into_inner().unwrap().downcast::<crate::error::Error>().unwrap();
+
+        assert!(matches!(error.kind(), ErrorKind::IO(_)));
+        assert_eq!(error.archive_path(), Some(PathBuf::from("./test/missing.t").as_path()));
+    }
+
+    #[test]
+    fn in_memory_archive() {
+        let mut archive = Archive::create_with(Cursor::new(Vec::new())).unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.append("./test/a", "./a").unwrap();
+        builder.append("./test/b", "./b").unwrap();
+
+        builder.finalise().unwrap();
+
+        archive.remove("./b").unwrap();
+
+        assert!(archive.path().is_none());
+
+        let archive = Archive::open_with(Cursor::new(archive.into_inner().into_inner())).unwrap();
+
+        let mut v = String::new();
+
+        archive.get("./a").unwrap().unwrap().read_to_string(& mut v).unwrap();
+
+        assert_eq!(v, std::fs::read_to_string("./test/a").unwrap());
+        assert!(!archive.contains("./b"));
+    }
+
+    #[test]
+    fn streamed_archive() {
+        //A Vec is a sink that cannot seek
+        let mut builder = StreamBuilder::new(Vec::new()).unwrap();
+
+        builder.append("./test/a", "./a").unwrap();
+        builder.append("./test/b", "./b").unwrap();
+
+        assert!(matches!(builder.append("./test/b", "./b").unwrap_err().kind(), ErrorKind::PathConflict(_)));
+
+        let bytes = builder.finish().unwrap();
+
+        let mut archive = Archive::open_with(Cursor::new(bytes.clone())).unwrap();
+
+        let mut v = String::new();
+
+        archive.get("./b").unwrap().unwrap().read_to_string(& mut v).unwrap();
+
+        assert_eq!(v, std::fs::read_to_string("./test/b").unwrap());
+
+        //Modifying a streamed archive converts it to the usual layout
+        archive.remove("./b").unwrap();
+
+        let archive = Archive::open_with(Cursor::new(archive.into_inner().into_inner())).unwrap();
+
+        assert!(archive.contains("./a"));
+        assert!(!archive.contains("./b"));
+
+        //An incomplete stream has no trailer
+        let truncated = bytes[..bytes.len() - 1].to_vec();
+
+        assert!(matches!(Archive::open_with(Cursor::new(truncated)).unwrap_err().kind(), ErrorKind::TocEntryNotFound(TocEntryNotFoundReason::BadTrailer(_))));
+    }
+
+    #[test]
+    fn stream_reader() {
+        let mut builder = StreamBuilder::new(Vec::new()).unwrap();
+
+        builder.append("./test/a", "./a").unwrap();
+        builder.append("./test/b", "./b").unwrap();
+
+        let streamed = builder.finish().unwrap();
+
+        let mut archive = Archive::create_with(Cursor::new(Vec::new())).unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.append("./test/a", "./a").unwrap();
+        builder.append("./test/b", "./b").unwrap();
+
+        builder.finalise().unwrap();
+
+        let seekable = archive.into_inner().into_inner();
+
+        //Both layouts are read in file order, and unread data is skipped
+        for bytes in [streamed.clone(), seekable] {
+            let mut reader = StreamReader::new(bytes.as_slice()).unwrap();
+
+            assert_eq!(reader.next_entry().unwrap().unwrap().path(), PathBuf::from("./a").as_path());
+
+            let mut entry = reader.next_entry().unwrap().unwrap();
+
+            let mut v = String::new();
+
+            entry.read_to_string(& mut v).unwrap();
+
+            assert_eq!(entry.path(), PathBuf::from("./b").as_path());
+            assert_eq!(entry.header().len() as usize, v.len());
+            assert_eq!(v, std::fs::read_to_string("./test/b").unwrap());
+
+            assert!(reader.next_entry().unwrap().is_none());
+        }
+
+        //Anything other than a header or the toc is an error, rather than the end of a streamed archive
+        let toc = streamed.windows(8).position(|window| window == crate::toc::TOC_MAGIC_NUMBER.to_le_bytes()).unwrap();
+
+        let mut corrupt = streamed.clone();
+
+        corrupt[toc] ^= 0xff;
+
+        let mut reader = StreamReader::new(corrupt.as_slice()).unwrap();
+
+        reader.next_entry().unwrap();
+        reader.next_entry().unwrap();
+
+        assert!(matches!(reader.next_entry().err().unwrap().kind(), ErrorKind::BadHeaderMagicNumber(_)));
+
+        //The toc and trailer are read, so a stream that ends early is an error
+        let mut reader = StreamReader::new(&streamed[..streamed.len() - 1]).unwrap();
+
+        reader.next_entry().unwrap();
+        reader.next_entry().unwrap();
+
+        assert!(reader.next_entry().is_err());
+
+        //A file that shares its data with an earlier file is read as a hard link to it
+        let mut archive = Archive::create_with(Cursor::new(Vec::new())).unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.append("./test/a", "./a").unwrap();
+        builder.append("./test/a", "./c").unwrap();
+
+        builder.finalise().unwrap();
+
+        let bytes = archive.into_inner().into_inner();
+
+        let mut reader = StreamReader::new(bytes.as_slice()).unwrap();
+
+        assert_eq!(reader.next_entry().unwrap().unwrap().header().file_type(), FileType::File);
+
+        let mut entry = reader.next_entry().unwrap().unwrap();
+
+        assert_eq!(entry.path(), PathBuf::from("./c").as_path());
+        assert_eq!(entry.header().file_type(), FileType::HardLink);
+        assert_eq!(entry.header().link_target(), Some(PathBuf::from("./a").as_path()));
+        assert_eq!(entry.read(& mut [0; 8]).unwrap(), 0);
+
+        assert!(reader.next_entry().unwrap().is_none());
+
+        //Files that report a different size than they contain, such as in procfs, are stored with the length in the header
+        #[cfg(target_os = "linux")]
+        {
+            let mut builder = StreamBuilder::new(Vec::new()).unwrap();
+
+            builder.append("/proc/self/status", "./status").unwrap();
+            builder.append("./test/b", "./b").unwrap();
+
+            let bytes = builder.finish().unwrap();
+
+            let mut reader = StreamReader::new(bytes.as_slice()).unwrap();
+
+            let mut paths = Vec::new();
+
+            while let Some(entry) = reader.next_entry().unwrap() {
+                paths.push(entry.path().to_path_buf());
+            }
+
+            assert_eq!(paths, [PathBuf::from("./status"), PathBuf::from("./b")]);
+
+            let archive = Archive::open_with(Cursor::new(bytes)).unwrap();
+
+            let mut v = String::new();
+
+            archive.get("./b").unwrap().unwrap().read_to_string(& mut v).unwrap();
+
+            assert_eq!(v, std::fs::read_to_string("./test/b").unwrap());
+        }
+    }
+
+    #[test]
+    fn rename_defrag_repair() {
+        let mut archive = Archive::create_with(Cursor::new(Vec::new())).unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.append("./test/a", "./a").unwrap();
+        builder.append("./test/b", "./b").unwrap();
+
+        builder.finalise().unwrap();
+
+        archive.rename("./a", "./c").unwrap();
+        archive.remove("./b").unwrap();
+
+        assert!(matches!(archive.rename("./a", "./d").unwrap_err().kind(), ErrorKind::NotFound(_)));
+
+        //The old copies are still in the archive until it is defragmented
+        assert_eq!(archive.walk().unwrap().len(), 3);
+
+        archive.defrag().unwrap();
+        archive.verify().unwrap();
+
+        assert_eq!(archive.walk().unwrap(), vec![32]);
+
+        let mut v = String::new();
+
+        archive.get("./c").unwrap().unwrap().read_to_string(& mut v).unwrap();
+
+        assert_eq!(v, std::fs::read_to_string("./test/a").unwrap());
+
+        //Corrupt the toc offset, then rebuild the toc from the headers
+        let mut bytes = archive.into_inner().into_inner();
+
+        bytes[16] = 0xff;
+
+        assert!(Archive::open_with(Cursor::new(bytes.clone())).is_err());
+
+        let archive = Archive::repair_with(Cursor::new(bytes)).unwrap();
+
+        assert!(archive.contains("./c"));
+
+        Archive::open_with(archive.into_inner()).unwrap().verify().unwrap();
+    }
+
+    #[test]
+    fn dedup() {
+        use crate::header::Metadata;
+        use crate::crypto::{Encryption, Key};
+
+        let data = (0..10_000u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+
+        let read = |archive: &Archive<Cursor<Vec<u8>>>, path: &str| {
+            let mut v = Vec::new();
+
+            archive.get(path).unwrap().unwrap().read_to_end(& mut v).unwrap();
+
+            v
+        };
+
+        let mut archive = Archive::create_with(Cursor::new(Vec::new())).unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.append_data(Metadata::new(FileType::File, data.len() as u128), data.as_slice(), "a").unwrap();
+        builder.append_data(Metadata::new(FileType::File, data.len() as u128), data.as_slice(), "b").unwrap();
+        builder.append_data(Metadata::new(FileType::File, data.len() as u128), data.as_slice(), "c").unwrap();
+
+        builder.finalise().unwrap();
+
+        //The data is only stored once
+        assert!(archive.toc_offset() < 2 * data.len() as u128);
+        assert!(!archive.get("a").unwrap().unwrap().handle().is_shared());
+        assert!(archive.get("b").unwrap().unwrap().handle().is_shared());
+        assert_eq!(read(&archive, "c"), data);
+
+        archive.verify().unwrap();
+
+        //The data outlives the file it was stored with
+        archive.remove("a").unwrap();
+        archive.rename("b", "d").unwrap();
+        archive.defrag().unwrap();
+
+        assert_eq!(read(&archive, "c"), data);
+        assert_eq!(read(&archive, "d"), data);
+        assert_eq!(archive.reclaimable().unwrap(), 0);
+
+        //Repairing counts the files that share the data again
+        let mut bytes = archive.into_inner().into_inner();
+
+        bytes[16] = 0xff;
+
+        let mut archive = Archive::repair_with(Cursor::new(bytes)).unwrap();
+
+        assert_eq!(read(&archive, "d"), data);
+
+        //The header the data follows is still in the archive, so like any removed file that was not defragmented away, it returns
+        assert!(archive.contains("a"));
+
+        archive.remove("a").unwrap();
+        archive.remove("c").unwrap();
+        archive.remove("d").unwrap();
+        archive.defrag().unwrap();
+
+        //Once no file uses the data, defragmenting drops it
+        assert_eq!(archive.toc_offset(), 32);
+
+        //Encrypted archives only share data encrypted with the same key
+        let mut archive = Archive::create_encrypted_with(Cursor::new(Vec::new()), &Encryption::new(Key::Raw([3; 32]))).unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.append_data(Metadata::new(FileType::File, data.len() as u128), data.as_slice(), "a").unwrap();
+        builder.append_data(Metadata::new(FileType::File, data.len() as u128), data.as_slice(), "b").unwrap();
+
+        builder.finalise().unwrap();
+
+        assert!(archive.toc_offset() < 2 * data.len() as u128);
+
+        let bytes = archive.into_inner();
+
+        let archive = Archive::open_encrypted_with(bytes, &Key::Raw([3; 32])).unwrap();
+
+        assert_eq!(read(&archive, "b"), data);
+    }
+
+    #[test]
+    #[cfg(feature = "tar")]
+    fn import_tar() {
+        let mut tar = tar::Builder::new(Vec::new());
+
+        let long_name = format!("{}/file", "long".repeat(40));
+
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o640);
+        header.set_uid(1000);
+        header.set_gid(100);
+        header.set_mtime(1642502400);
+        tar.append_data(& mut header, &long_name, "hello".as_bytes()).unwrap();
+
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        tar.append_link(& mut header, "link", "target").unwrap();
+
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        tar.append_link(& mut header, "hard", &long_name).unwrap();
+
+        //The tar crate refuses to write unsafe paths, so write the name directly
+        let mut header = tar::Header::new_gnu();
+        header.as_gnu_mut().unwrap().name[..7].copy_from_slice(b"../evil");
+        header.set_size(4);
+        header.set_cksum();
+        tar.append(&header, "evil".as_bytes()).unwrap();
+
+        let mut archive = Archive::create_with(Cursor::new(Vec::new())).unwrap();
+
+        let report = archive.import_tar(tar.into_inner().unwrap().as_slice()).unwrap();
+
+        assert_eq!(report.imported.len(), 3);
+        assert_eq!(report.skipped.len(), 1);
+        assert!(matches!(report.skipped[0].1.kind(), ErrorKind::UnsafePath(_)));
+
+        let mut entry = archive.get(&long_name).unwrap().unwrap();
+
+        let mut v = String::new();
+
+        entry.read_to_string(& mut v).unwrap();
+
+        assert_eq!(v, "hello");
+        assert_eq!(entry.header().mode(), Some(0o640));
+        assert_eq!(entry.header().uid(), Some(1000));
+        assert_eq!(entry.header().modified(), Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1642502400)));
+
+        let link = archive.get("link").unwrap().unwrap();
+
+        assert_eq!(link.header().file_type(), FileType::SystemLink);
+        assert_eq!(link.header().link_target(), Some(PathBuf::from("target").as_path()));
+
+        assert_eq!(archive.get("hard").unwrap().unwrap().header().file_type(), FileType::HardLink);
+    }
+
+    #[cfg(feature = "tar")]
+    #[test]
+    fn export_tar() {
+        use crate::convert::ExportOptions;
+        use crate::header::Metadata;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let long_name = format!("{}/file", "long".repeat(40));
+        let modified = UNIX_EPOCH + Duration::new(1642502400, 250_000_000);
+
+        let mut archive = Archive::create_with(Cursor::new(Vec::new())).unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.append_data(Metadata::new(FileType::File, 5).with_mode(0o600).with_owner(1000, 100).with_modified(modified), "hello".as_bytes(), &long_name).unwrap();
+        builder.append_data(Metadata::new(FileType::HardLink, 0).with_link_target(&long_name), std::io::empty(), "hard").unwrap();
+
+        builder.finalise().unwrap();
+
+        let exported = archive.export_tar(Vec::new(), &ExportOptions::new().with_prefix("out")).unwrap();
+
+        let mut tar = tar::Archive::new(exported.as_slice());
+
+        let mut entries = tar.entries().unwrap();
+
+        let mut entry = entries.next().unwrap().unwrap();
+
+        assert_eq!(entry.path().unwrap(), PathBuf::from("out").join(&long_name));
+        assert_eq!(entry.header().mode().unwrap(), 0o600);
+        assert_eq!(entry.header().uid().unwrap(), 1000);
+
+        let mut v = String::new();
+
+        entry.read_to_string(& mut v).unwrap();
+
+        assert_eq!(v, "hello");
+
+        let entry = entries.next().unwrap().unwrap();
+
+        assert_eq!(entry.path().unwrap(), PathBuf::from("out/hard"));
+        assert_eq!(entry.link_name().unwrap().unwrap(), PathBuf::from("out").join(&long_name));
+
+        assert!(entries.next().is_none());
+
+        //Importing the export again keeps the sub-second modification time
+        let mut imported = Archive::create_with(Cursor::new(Vec::new())).unwrap();
+
+        imported.import_tar(exported.as_slice()).unwrap();
+
+        let entry = imported.get(PathBuf::from("out").join(&long_name)).unwrap().unwrap();
+
+        assert_eq!(entry.header().modified(), Some(modified));
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn zip_round_trip() {
+        use crate::convert::ExportOptions;
+        use crate::header::Metadata;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let modified = UNIX_EPOCH + Duration::from_secs(1642502401);
+
+        let mut archive = Archive::create_with(Cursor::new(Vec::new())).unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.append_data(Metadata::new(FileType::Dir, 0).with_mode(0o750), std::io::empty(), "dir").unwrap();
+        builder.append_data(Metadata::new(FileType::File, 5).with_mode(0o600).with_modified(modified), "hello".as_bytes(), "dir/a").unwrap();
+        builder.append_data(Metadata::new(FileType::SystemLink, 0).with_link_target("a"), std::io::empty(), "dir/link").unwrap();
+        builder.append_data(Metadata::new(FileType::HardLink, 0).with_link_target("dir/a"), std::io::empty(), "hard").unwrap();
+
+        builder.finalise().unwrap();
+
+        let exported = archive.export_zip(Cursor::new(Vec::new()), &ExportOptions::new()).unwrap();
+
+        let mut imported = Archive::create_with(Cursor::new(Vec::new())).unwrap();
+
+        let report = imported.import_zip(exported).unwrap();
+
+        assert_eq!(report.imported.len(), 4);
+        assert!(report.skipped.is_empty());
+
+        assert_eq!(imported.get("dir").unwrap().unwrap().header().file_type(), FileType::Dir);
+
+        let mut entry = imported.get("dir/a").unwrap().unwrap();
+
+        let mut v = String::new();
+
+        entry.read_to_string(& mut v).unwrap();
+
+        assert_eq!(v, "hello");
+        assert_eq!(entry.header().mode(), Some(0o600));
+        assert_eq!(entry.header().modified(), Some(modified));
+
+        let link = imported.get("dir/link").unwrap().unwrap();
+
+        assert_eq!(link.header().file_type(), FileType::SystemLink);
+        assert_eq!(link.header().link_target(), Some(PathBuf::from("a").as_path()));
+
+        //Hard links are exported as copies
+        let mut hard = imported.get("hard").unwrap().unwrap();
+
+        let mut v = String::new();
+
+        hard.read_to_string(& mut v).unwrap();
+
+        assert_eq!(v, "hello");
+    }
+
+    #[test]
+    fn generations() {
+        use crate::crypto::{Encryption, Key};
+
+        let read = |archive: &Archive<Cursor<Vec<u8>>>, path: &str| {
+            let mut v = String::new();
+
+            archive.get(path).unwrap().unwrap().read_to_string(& mut v).unwrap();
+
+            v
+        };
+
+        let a = std::fs::read_to_string("./test/a").unwrap();
+
+        let mut archive = Archive::create_with(Cursor::new(Vec::new())).unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.append("./test/a", "a").unwrap();
+        builder.append("./test/b", "b").unwrap();
+
+        builder.finalise().unwrap();
+
+        archive.set_label("first").unwrap();
+
+        archive.remove("a").unwrap();
+        archive.rename("b", "c").unwrap();
+
+        assert_eq!(archive.generation().number(), 3);
+        assert_eq!(archive.generations().unwrap().iter().map(|generation| generation.number()).collect::<Vec<_>>(), vec![3, 2, 1, 0]);
+        assert_eq!(archive.generations().unwrap()[2].label(), Some("first"));
+
+        //Earlier generations can be read, but not changed
+        {
+            let mut first = archive.open_generation(1).unwrap();
+
+            assert!(first.contains("a") && first.contains("b") && !first.contains("c"));
+
+            let mut v = String::new();
+
+            first.get("a").unwrap().unwrap().read_to_string(& mut v).unwrap();
+
+            assert_eq!(v, a);
+
+            first.verify().unwrap();
+
+            assert!(first.remove("b").is_err());
+        }
+
+        assert!(matches!(archive.open_generation(9).unwrap_err().kind(), ErrorKind::GenerationNotFound(9)));
+
+        //Removed files can be restored from the generation before they were removed
+        assert_eq!(archive.undelete("a").unwrap(), 1);
+        assert_eq!(read(&archive, "a"), a);
+        assert_eq!(archive.generation().number(), 4);
+
+        assert!(matches!(archive.undelete("a").unwrap_err().kind(), ErrorKind::PathConflict(_)));
+        assert!(matches!(archive.undelete("missing").unwrap_err().kind(), ErrorKind::NotFound(_)));
+
+        //The history survives reopening and repairing the archive
+        let mut bytes = archive.into_inner().into_inner();
+
+        bytes[16] = 0xff;
+
+        let archive = Archive::repair_with(Cursor::new(bytes)).unwrap();
+
+        assert_eq!(archive.generation().number(), 5);
+        assert!(archive.open_generation(1).unwrap().contains("b"));
+
+        let mut archive = Archive::open_with(archive.into_inner()).unwrap();
+
+        assert_eq!(archive.generations().unwrap().len(), 6);
+
+        //Defragmenting discards the history
+        archive.defrag().unwrap();
+
+        assert_eq!(archive.generations().unwrap().len(), 1);
+        assert!(matches!(archive.open_generation(1).unwrap_err().kind(), ErrorKind::GenerationNotFound(1)));
+        assert_eq!(read(&archive, "a"), a);
+
+        //A batch that is not committed changes nothing, and leaves no copied data behind
+        {
+            let mut batch = archive.batch();
+
+            batch.remove("a").unwrap();
+            batch.rename("c", "d").unwrap();
+
+            assert!(matches!(batch.remove("missing").unwrap_err().kind(), ErrorKind::NotFound(_)));
+        }
+
+        let mut archive = Archive::open_with(archive.into_inner()).unwrap();
+
+        assert!(archive.contains("a") && archive.contains("c"));
+
+        //A committed batch is written as a single generation
+        let mut batch = archive.batch();
+
+        batch.remove("a").unwrap();
+        batch.rename("c", "d").unwrap();
+        batch.undelete("a").unwrap();
+        batch.remove("d").unwrap();
+
+        batch.commit().unwrap();
+
+        assert!(archive.contains("a") && !archive.contains("c") && !archive.contains("d"));
+        assert_eq!(archive.generations().unwrap().len(), 2);
+
+        archive.verify().unwrap();
+
+        //Sealed tocs are found the same way
+        let key = Key::Raw([9; 32]);
+
+        let mut archive = Archive::create_encrypted_with(Cursor::new(Vec::new()), &Encryption::new(key.clone()).with_encrypted_metadata()).unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.append("./test/a", "a").unwrap();
+
+        builder.finalise().unwrap();
+
+        archive.remove("a").unwrap();
+
+        let mut archive = Archive::repair_encrypted_with(archive.into_inner(), &key).unwrap();
+
+        //Repairing restores the removed file, as its data is still in the archive
+        assert_eq!(archive.generation().number(), 3);
+        assert!(archive.open_generation(1).unwrap().contains("a") && !archive.open_generation(2).unwrap().contains("a"));
+
+        archive.remove("a").unwrap();
+
+        assert_eq!(archive.undelete("a").unwrap(), 3);
+        assert_eq!(read(&archive, "a"), a);
+    }
+
+    #[test]
+    fn prune() {
+        use std::time::{Duration, UNIX_EPOCH};
+        use crate::crypto::{Encryption, Key};
+        use crate::generation::{Generation, Retention};
+        use crate::header::Metadata;
+
+        let mut archive = Archive::create_with(Cursor::new(Vec::new())).unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.append("./test/a", "a").unwrap();
+        builder.append("./test/b", "b").unwrap();
+
+        builder.finalise().unwrap();
+
+        archive.set_label("release").unwrap();
+
+        archive.remove("a").unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.append_data(Metadata::new(FileType::File, 5), b"third".as_slice(), "c").unwrap();
+
+        builder.finalise().unwrap();
+
+        archive.remove("b").unwrap();
+
+        let length = |archive: &Archive<Cursor<Vec<u8>>>| archive.storage().get_ref().len() as u128;
+
+        //A dry run reports what would be removed, without changing the archive
+        let before = length(&archive);
+
+        let report = archive.prune_report(&Retention::new().with_last(2)).unwrap();
+
+        assert_eq!(report.kept, vec![4, 3]);
+        assert_eq!(report.removed, vec![2, 1, 0]);
+        assert!(report.reclaimed > 0);
+        assert_eq!(length(&archive), before);
+        assert_eq!(archive.generations().unwrap().len(), 5);
+
+        let report = archive.prune(&Retention::new().with_last(2).with_labelled()).unwrap();
+
+        assert_eq!(report.kept, vec![4, 3, 1]);
+        assert_eq!(report.removed, vec![2, 0]);
+        assert_eq!(length(&archive), before - report.reclaimed);
+
+        //The kept generations still read the data they used
+        let mut archive = Archive::open_with(archive.into_inner()).unwrap();
+
+        assert_eq!(archive.generations().unwrap().iter().map(|generation| generation.number()).collect::<Vec<_>>(), vec![4, 3, 1]);
+
+        archive.open_generation(1).unwrap().verify().unwrap();
+
+        assert!(matches!(archive.open_generation(2).unwrap_err().kind(), ErrorKind::GenerationNotFound(2)));
+
+        assert_eq!(archive.undelete("a").unwrap(), 1);
+
+        archive.verify().unwrap();
+
+        //Keeping only the current generation drops the data of removed files
+        let report = archive.prune(&Retention::new()).unwrap();
+
+        assert_eq!(report.kept, vec![5]);
+        assert_eq!(archive.reclaimable().unwrap(), 0);
+        assert!(matches!(archive.undelete("b").unwrap_err().kind(), ErrorKind::NotFound(_)));
+
+        archive.verify().unwrap();
+
+        //Sealed tocs are moved the same way
+        let key = Key::Raw([3; 32]);
+
+        let mut archive = Archive::create_encrypted_with(Cursor::new(Vec::new()), &Encryption::new(key.clone()).with_encrypted_metadata()).unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.append("./test/a", "a").unwrap();
+        builder.append("./test/b", "b").unwrap();
+
+        builder.finalise().unwrap();
+
+        archive.remove("a").unwrap();
+        archive.remove("b").unwrap();
+
+        archive.prune(&Retention::new().with_last(2)).unwrap();
+
+        let archive = Archive::open_encrypted_with(archive.into_inner(), &key).unwrap();
+
+        assert!(archive.open_generation(2).unwrap().contains("b"));
+        assert!(matches!(archive.open_generation(1).unwrap_err().kind(), ErrorKind::GenerationNotFound(1)));
+
+        let archive = Archive::repair_encrypted_with(archive.into_inner(), &key).unwrap();
+
+        assert_eq!(archive.generations().unwrap().len(), 3);
+
+        //Daily and weekly rules keep the latest generation of each day or week
+        let day = 24 * 60 * 60;
+
+        let generations = [(6, 20000 * day + day / 2), (5, 20000 * day + day / 3), (4, 19999 * day), (3, 19997 * day), (2, 19990 * day), (1, 19980 * day)]
+            .iter()
+            .map(|&(number, time)| Generation { number, time: UNIX_EPOCH + Duration::from_secs(time), label: None })
+            .collect::<Vec<_>>();
+
+        assert_eq!(Retention::new().select(&generations), vec![6]);
+        assert_eq!(Retention::new().with_daily(3).select(&generations), vec![6, 4]);
+        assert_eq!(Retention::new().with_weekly(2).select(&generations), vec![6, 2]);
+        assert_eq!(Retention::new().with_last(2).with_weekly(2).select(&generations), vec![6, 5, 2]);
+    }
+
+    #[test]
+    fn sync_dir() {
+        use std::fs::{File, OpenOptions};
+        use crate::builder::SyncOptions;
+
+        let dir = std::env::temp_dir().join(format!("tarpdate-sync-{}", std::process::id()));
+
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a.txt"), "one").unwrap();
+        std::fs::write(dir.join("c.txt"), "three").unwrap();
+        std::fs::write(dir.join("sub/b.txt"), "two").unwrap();
+
+        let mut archive = Archive::create_with(Cursor::new(Vec::new())).unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.append("./test/a", "other").unwrap();
+
+        let summary = builder.sync_dir(&dir, "backup", &SyncOptions::new()).unwrap();
+
+        builder.finalise().unwrap();
+
+        assert_eq!(summary.added, vec![PathBuf::from("backup/a.txt"), PathBuf::from("backup/c.txt"), PathBuf::from("backup/sub/b.txt")]);
+
+        let mut builder = archive.builder().unwrap();
+
+        let summary = builder.sync_dir(&dir, "backup", &SyncOptions::new()).unwrap();
+
+        builder.finalise().unwrap();
+
+        assert!(summary.added.is_empty() && summary.updated.is_empty());
+        assert_eq!(summary.unchanged.len(), 3);
+
+        //A change that keeps the size and modification time is only found by comparing contents
+        let modified = std::fs::metadata(dir.join("a.txt")).unwrap().modified().unwrap();
+
+        std::fs::write(dir.join("a.txt"), "uno").unwrap();
+
+        OpenOptions::new().write(true).open(dir.join("a.txt")).unwrap().set_modified(modified).unwrap();
+
+        std::fs::write(dir.join("sub/b.txt"), "twenty").unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        let summary = builder.sync_dir(&dir, "backup", &SyncOptions::new()).unwrap();
+
+        assert_eq!(summary.updated, vec![PathBuf::from("backup/sub/b.txt")]);
+
+        let summary = builder.sync_dir(&dir, "backup", &SyncOptions::new().with_hash_comparison()).unwrap();
+
+        builder.finalise().unwrap();
+
+        assert_eq!(summary.updated, vec![PathBuf::from("backup/a.txt")]);
+
+        let mut v = String::new();
+
+        archive.get("backup/a.txt").unwrap().unwrap().read_to_string(& mut v).unwrap();
+
+        assert_eq!(v, "uno");
+
+        //Files that are no longer in the directory are only removed when asked, and only below the prefix
+        File::create(dir.join("d.txt")).unwrap();
+        std::fs::remove_file(dir.join("c.txt")).unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        let summary = builder.sync_dir(&dir, "backup", &SyncOptions::new()).unwrap();
+
+        assert!(summary.removed.is_empty());
+        assert_eq!(summary.added, vec![PathBuf::from("backup/d.txt")]);
+
+        let summary = builder.sync_dir(&dir, "backup", &SyncOptions::new().with_removal().with_hash_comparison()).unwrap();
+
+        builder.finalise().unwrap();
+
+        assert_eq!(summary.removed, vec![PathBuf::from("backup/c.txt")]);
+        assert_eq!(summary.unchanged.len(), 3);
+        assert!(!archive.contains("backup/c.txt") && archive.contains("other"));
+
+        //A replacement that cannot be read keeps the old file
+        let mut builder = archive.builder().unwrap();
+
+        let error = builder.replace_with(Path::new("backup/a.txt"), |_| Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied).into())).unwrap_err();
+
+        builder.finalise().unwrap();
+
+        assert!(matches!(error.kind(), ErrorKind::IO(_)));
+
+        let mut v = String::new();
+
+        archive.get("backup/a.txt").unwrap().unwrap().read_to_string(& mut v).unwrap();
+
+        assert_eq!(v, "uno");
+
+        archive.verify().unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn diff_dir() {
+        use std::fs::OpenOptions;
+        use crate::builder::SyncOptions;
+        use crate::diff::Difference;
+        use crate::header::{FileType, Metadata};
+
+        let dir = std::env::temp_dir().join(format!("tarpdate-diff-{}", std::process::id()));
+
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a.txt"), "one").unwrap();
+        std::fs::write(dir.join("c.txt"), "three").unwrap();
+        std::fs::write(dir.join("sub/b.txt"), "two").unwrap();
+
+        let mut archive = Archive::create_with(Cursor::new(Vec::new())).unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.sync_dir(&dir, "", &SyncOptions::new()).unwrap();
+        builder.append_data(Metadata::new(FileType::SystemLink, 0).with_link_target("a.txt"), std::io::empty(), "link").unwrap();
+
+        builder.finalise().unwrap();
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("c.txt", dir.join("link")).unwrap();
+
+            assert_eq!(archive.diff_dir(&dir).unwrap().changed, vec![(PathBuf::from("link"), vec![Difference::LinkTarget])]);
+
+            //Links are stored as links, so an archive synced from the directory matches it
+            let mut synced = Archive::create_with(Cursor::new(Vec::new())).unwrap();
+
+            let mut builder = synced.builder().unwrap();
+
+            builder.sync_dir(&dir, "", &SyncOptions::new().with_hash_comparison()).unwrap();
+
+            let summary = builder.sync_dir(&dir, "", &SyncOptions::new().with_hash_comparison()).unwrap();
+
+            builder.finalise().unwrap();
+
+            assert_eq!(summary.unchanged.len(), 4);
+            assert_eq!(synced.handle("link").unwrap().unwrap().header().link_target(), Some(Path::new("c.txt")));
+            assert!(synced.diff_dir(&dir).unwrap().is_empty());
+
+            std::fs::remove_file(dir.join("link")).unwrap();
+        }
+
+        //Nothing has changed yet, apart from the link
+        let diff = archive.diff_dir(&dir).unwrap();
+
+        assert!(diff.changed.is_empty() && diff.missing_from_archive.is_empty());
+        assert_eq!(diff.missing_on_disk, vec![PathBuf::from("link")]);
+
+        let mut archive = Archive::create_with(Cursor::new(Vec::new())).unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.sync_dir(&dir, "", &SyncOptions::new()).unwrap();
+
+        builder.finalise().unwrap();
+
+        assert!(archive.diff_dir(&dir).unwrap().is_empty());
+
+        //Paths starting with `./` are the same as paths without
+        let mut dotted = Archive::create_with(Cursor::new(Vec::new())).unwrap();
+
+        let mut builder = dotted.builder().unwrap();
+
+        builder.sync_dir(&dir, "./", &SyncOptions::new()).unwrap();
+
+        builder.finalise().unwrap();
+
+        assert!(dotted.contains("./a.txt"));
+        assert!(dotted.diff_dir(&dir).unwrap().is_empty());
+
+        //Change the contents without changing the size or modification time, and the size of another file
+        let modified = std::fs::metadata(dir.join("a.txt")).unwrap().modified().unwrap();
+
+        std::fs::write(dir.join("a.txt"), "uno").unwrap();
+
+        OpenOptions::new().write(true).open(dir.join("a.txt")).unwrap().set_modified(modified).unwrap();
+
+        std::fs::write(dir.join("sub/b.txt"), "twenty").unwrap();
+        std::fs::write(dir.join("d.txt"), "four").unwrap();
+        std::fs::remove_file(dir.join("c.txt")).unwrap();
+
+        let diff = archive.diff_dir(&dir).unwrap();
+
+        assert_eq!(diff.missing_on_disk, vec![PathBuf::from("c.txt")]);
+        assert_eq!(diff.missing_from_archive, vec![PathBuf::from("d.txt")]);
+        assert_eq!(diff.changed[0], (PathBuf::from("a.txt"), vec![Difference::Content]));
+        assert_eq!(diff.changed[1].0, PathBuf::from("sub/b.txt"));
+        assert!(diff.changed[1].1.contains(&Difference::Size));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn diff_archives() {
+        use std::time::{Duration, UNIX_EPOCH};
+        use crate::crypto::{Encryption, Key};
+        use crate::diff::Difference;
+        use crate::header::{FileType, Metadata};
+
+        let time = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+
+        let file = |data: &str| Metadata::new(FileType::File, data.len() as u128).with_mode(0o644).with_modified(time);
+
+        let mut old = Archive::create_with(Cursor::new(Vec::new())).unwrap();
+
+        let mut builder = old.builder().unwrap();
+
+        for (name, data) in [("same", "hello"), ("meta", "hello"), ("content", "hello"), ("size", "hello"), ("gone", "hello"), ("docs/kept", "a")] {
+            builder.append_data(file(data), data.as_bytes(), name).unwrap();
+        }
+
+        builder.finalise().unwrap();
+
+        //The new archive is encrypted, so its content hashes are keyed and cannot be compared directly
+        let mut new = Archive::create_encrypted_with(Cursor::new(Vec::new()), &Encryption::new(Key::Raw([5; 32]))).unwrap();
+
+        let mut builder = new.builder().unwrap();
+
+        for (name, data) in [("same", "hello"), ("content", "jello"), ("size", "hello!"), ("docs/kept", "a"), ("docs/new", "b")] {
+            builder.append_data(file(data), data.as_bytes(), name).unwrap();
+        }
+
+        builder.append_data(file("hello").with_modified(time + Duration::from_secs(1)).with_mode(0o600), "hello".as_bytes(), "meta").unwrap();
+
+        builder.finalise().unwrap();
+
+        let diff = crate::diff(&old, &new).unwrap();
+
+        assert_eq!(diff.added, vec![PathBuf::from("docs/new")]);
+        assert_eq!(diff.removed, vec![PathBuf::from("gone")]);
+        assert_eq!(diff.modified, vec![(PathBuf::from("content"), vec![Difference::Content]), (PathBuf::from("size"), vec![Difference::Size])]);
+        assert_eq!(diff.metadata, vec![(PathBuf::from("meta"), vec![Difference::Mode, Difference::Modified])]);
+
+        let diff = crate::diff::diff_prefix(&old, &new, "docs").unwrap();
+
+        assert_eq!(diff.added, vec![PathBuf::from("docs/new")]);
+        assert!(diff.removed.is_empty() && diff.modified.is_empty() && diff.metadata.is_empty());
+
+        assert!(crate::diff(&old, &old).unwrap().is_empty());
+    }
+
+    #[test]
+    fn merge() {
+        use std::time::{Duration, UNIX_EPOCH};
+        use crate::builder::Chunking;
+        use crate::crypto::{Encryption, Key};
+        use crate::header::{FileType, Metadata};
+        use crate::merge::ConflictPolicy;
+
+        let file = |data: &[u8], secs: u64| Metadata::new(FileType::File, data.len() as u128).with_modified(UNIX_EPOCH + Duration::from_secs(secs));
+
+        let read = |archive: &Archive<Cursor<Vec<u8>>>, path: &str| {
+            let mut v = Vec::new();
+
+            archive.get(path).unwrap().unwrap().read_to_end(& mut v).unwrap();
+
+            v
+        };
+
+        let big = (0..100_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect::<Vec<u8>>();
+
+        let shard = |key: Option<&Key>, files: &[(&str, &[u8], u64)]| {
+            let mut archive = match key {
+                Some(key) => Archive::create_encrypted_with(Cursor::new(Vec::new()), &Encryption::new(key.clone())).unwrap(),
+                None => Archive::create_with(Cursor::new(Vec::new())).unwrap(),
+            };
+
+            let mut builder = archive.builder().unwrap();
+
+            for (name, data, secs) in files {
+                builder.append_data(file(data, *secs), *data, name).unwrap();
+            }
+
+            builder.finalise().unwrap();
+
+            archive
+        };
+
+        let first = shard(None, &[("a", b"same", 1), ("x", b"one", 10)]);
+        let mut second = shard(None, &[("a", b"same", 1), ("x", b"two", 20)]);
+
+        let mut builder = second.builder().unwrap().with_chunking(Chunking::new(1024, 4096, 16384).unwrap());
+
+        builder.append_data(file(&big, 1), &big[..], "big").unwrap();
+
+        builder.finalise().unwrap();
+
+        let merged = |policy: &ConflictPolicy| {
+            let mut archive = shard(None, &[("x", b"zero", 5)]);
+
+            let report = archive.merge([&first, &second], policy).unwrap();
+
+            archive.verify().unwrap();
+
+            (archive, report)
+        };
+
+        let (archive, report) = merged(&ConflictPolicy::KeepExisting);
+
+        assert_eq!(report.added, vec![PathBuf::from("a"), PathBuf::from("big")]);
+        assert_eq!(report.skipped, vec![PathBuf::from("x"), PathBuf::from("a"), PathBuf::from("x")]);
+        assert_eq!(read(&archive, "x"), b"zero");
+        assert_eq!(read(&archive, "big"), big);
+        assert!(archive.handle("big").unwrap().unwrap().is_chunked());
+
+        let (archive, report) = merged(&ConflictPolicy::Newer);
+
+        assert_eq!(report.replaced, vec![PathBuf::from("x"), PathBuf::from("x")]);
+        assert_eq!(report.skipped, vec![PathBuf::from("a")]);
+        assert_eq!(read(&archive, "x"), b"two");
+
+        //Copied data is shared with files that have the same contents
+        let (archive, report) = merged(&ConflictPolicy::Rename(String::from("~")));
+
+        assert_eq!(report.renamed, vec![(PathBuf::from("x"), PathBuf::from("x~")), (PathBuf::from("a"), PathBuf::from("a~")), (PathBuf::from("x"), PathBuf::from("x~1"))]);
+        assert_eq!(read(&archive, "x~1"), b"two");
+        assert!(archive.handle("a~").unwrap().unwrap().is_shared());
+
+        let mut archive = shard(None, &[("x", b"zero", 5)]);
+
+        assert!(matches!(archive.merge([&first, &second], &ConflictPolicy::Error).unwrap_err().kind(), ErrorKind::PathConflict(_)));
+        assert!(!archive.contains("a"));
+        assert_eq!(archive.generation().number(), 1);
+
+        //Archives with the same data key are copied as stored, and others are sealed again
+        let key = Key::Raw([8; 32]);
+
+        let template = Archive::create_encrypted_with(Cursor::new(Vec::new()), &Encryption::new(key.clone())).unwrap().into_inner().into_inner();
+
+        let mut archive = Archive::open_encrypted_with(Cursor::new(template.clone()), &key).unwrap();
+        let mut same_key = Archive::open_encrypted_with(Cursor::new(template), &key).unwrap();
+
+        let mut builder = same_key.builder().unwrap().with_chunking(Chunking::new(1024, 4096, 16384).unwrap());
+
+        builder.append_data(file(&big, 1), &big[..], "big").unwrap();
+        builder.append_data(file(b"one", 1), &b"one"[..], "one").unwrap();
+
+        builder.finalise().unwrap();
+
+        let other_key = shard(Some(&Key::Raw([9; 32])), &[("other", b"other", 1)]);
+
+        archive.merge([&same_key], &ConflictPolicy::Error).unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.append_entry(&other_key.get("other").unwrap().unwrap()).unwrap();
+        builder.append_entry(&first.get("a").unwrap().unwrap()).unwrap();
+
+        assert!(matches!(builder.append_entry(&first.get("a").unwrap().unwrap()).unwrap_err().kind(), ErrorKind::PathConflict(_)));
+
+        builder.finalise().unwrap();
+
+        archive.verify().unwrap();
+
+        //A file read and appended again would not be chunked
+        assert!(archive.handle("big").unwrap().unwrap().is_chunked());
+        assert_eq!(read(&archive, "big"), big);
+        assert_eq!(read(&archive, "one"), b"one");
+        assert_eq!(read(&archive, "other"), b"other");
+        assert_eq!(read(&archive, "a"), b"same");
+    }
+
+    #[test]
+    fn extract_subset() {
+        use crate::header::{FileType, Metadata};
+        use crate::subset::Filter;
+
+        let mut archive = Archive::create_with(Cursor::new(Vec::new())).unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.append("./test/a", "docs/a.txt").unwrap();
+        builder.append("./test/a", "docs/copy.txt").unwrap();
+        builder.append("./test/b", "docs/api/b.md").unwrap();
+        builder.append("./test/b", "src/b.rs").unwrap();
+        builder.append_data(Metadata::new(FileType::File, 12), b"fn main() {}".as_slice(), "src/main.rs").unwrap();
+        builder.append_data(Metadata::new(FileType::HardLink, 0).with_link_target("src/b.rs"), std::io::empty(), "docs/link").unwrap();
+
+        builder.finalise().unwrap();
+
+        archive.remove("src/main.rs").unwrap();
+
+        let subset = |filter: &Filter| {
+            let mut destination = Archive::create_with(Cursor::new(Vec::new())).unwrap();
+
+            let copied = archive.extract_subset(filter, & mut destination).unwrap();
+
+            destination.verify().unwrap();
+
+            (destination, copied)
+        };
+
+        //`*` does not match directories, so only the files directly in docs are selected
+        let (destination, copied) = subset(&Filter::glob("docs/*.txt").unwrap());
+
+        assert_eq!(copied, vec![PathBuf::from("docs/a.txt"), PathBuf::from("docs/copy.txt")]);
+        assert_eq!(destination.iter().count(), 2);
+
+        //Files that shared their data still do, so the data is only stored once, and only the toc of the empty first generation can be reclaimed
+        let empty = Archive::create_with(Cursor::new(Vec::new())).unwrap();
+
+        assert!(destination.handle("docs/copy.txt").unwrap().unwrap().is_shared());
+        let empty_toc = empty.storage().get_ref().len() as u128 - empty.toc_offset();
+
+        assert_eq!(destination.reclaimable().unwrap(), empty_toc);
+
+        let (_, copied) = subset(&Filter::glob("**/*b*").unwrap());
+
+        assert_eq!(copied, vec![PathBuf::from("docs/api/b.md"), PathBuf::from("src/b.rs")]);
+
+        //The target of a hard link is copied with it, even though the filter does not select it
+        let (destination, copied) = subset(&Filter::prefix("docs"));
+
+        assert_eq!(copied.len(), 5);
+        assert!(copied.contains(&PathBuf::from("src/b.rs")));
+        assert_eq!(destination.get("docs/link").unwrap().unwrap().header().link_target(), Some(Path::new("src/b.rs")));
+        assert!(destination.contains("src/b.rs"));
+
+        let (_, copied) = subset(&Filter::predicate(|path, metadata| metadata.file_type() == FileType::File && path.extension().is_some_and(|extension| extension == "rs")));
+
+        assert_eq!(copied, vec![PathBuf::from("src/b.rs")]);
+
+        assert!(matches!(Filter::glob("[").unwrap_err().kind(), ErrorKind::InvalidPattern(_)));
+    }
+
+    #[test]
+    fn volumes() {
+        use crate::volume::volume_path;
+        use crate::header::Metadata;
+
+        let dir = std::env::temp_dir().join(format!("tarpdate-volumes-{}", std::process::id()));
+
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("split.t");
+
+        let data = (0..3000u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+
+        std::fs::write(dir.join("big"), &data).unwrap();
+
+        let small = (0..500u32).map(|i| (i % 13) as u8).collect::<Vec<u8>>();
+
+        let volume_sizes = || (0..).map(|index| std::fs::metadata(volume_path(&path, index))).map_while(|meta| meta.ok()).map(|meta| meta.len()).collect::<Vec<_>>();
+
+        let mut archive = Archive::create(&path).unwrap();
+
+        let mut builder = archive.builder().unwrap().with_volume_size(1000).unwrap();
+
+        builder.append_data(Metadata::new(FileType::File, small.len() as u128), small.as_slice(), "small").unwrap();
+        builder.append(dir.join("big"), "big").unwrap();
+
+        builder.finalise().unwrap();
+
+        //Every volume but the last is full, and the big file spans several of them
+        let sizes = volume_sizes();
+
+        assert!(sizes.len() >= 4);
+        assert!(sizes[..sizes.len() - 1].iter().all(|size| *size == 1000));
+
+        let handle = archive.handle("big").unwrap().unwrap();
+
+        let (first, _) = archive.locate(handle.file_offset());
+        let (last, _) = archive.locate(handle.file_offset() + handle.data_len() - 1);
+
+        assert!(last > first);
+
+        drop(archive);
+
+        //The rest of the volumes are found from the first, and the toc records where each entry is
+        let mut archive = Archive::open(&path).unwrap();
+
+        assert_eq!(archive.volume_size(), Some(1000));
+        assert_eq!(archive.table().locations.len(), 2);
+        assert_eq!(archive.location("big"), Some(archive.locate(handle.header_offset())));
+        assert_eq!(archive.location("missing"), None);
+
+        let mut v = Vec::new();
+
+        archive.get("big").unwrap().unwrap().read_to_end(& mut v).unwrap();
+
+        assert_eq!(v, data);
+
+        archive.verify().unwrap();
+
+        assert!(matches!(archive.builder().unwrap().with_volume_size(500).err().unwrap().kind(), ErrorKind::InvalidVolumeSize(500)));
+
+        //Volumes that are no longer needed are deleted when the archive shrinks
+        archive.remove("big").unwrap();
+        archive.defrag().unwrap();
+
+        assert!(volume_sizes().len() < sizes.len());
+
+        archive.verify().unwrap();
+
+        //Creating an archive deletes the volumes of the old one, and setting a volume size splits an archive that is already larger
+        let mut archive = Archive::create(&path).unwrap();
+
+        assert_eq!(volume_sizes().len(), 1);
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.append(dir.join("big"), "big").unwrap();
+
+        builder.finalise().unwrap();
+
+        archive.builder().unwrap().with_volume_size(1000).unwrap().finalise().unwrap();
+
+        assert_eq!(volume_sizes().len(), 4);
+
+        drop(archive);
+
+        let archive = Archive::open(&path).unwrap();
+
+        let mut v = Vec::new();
+
+        archive.get("big").unwrap().unwrap().read_to_end(& mut v).unwrap();
+
+        assert_eq!(v, data);
+
+        drop(archive);
+
+        //An archive that is still smaller than its volume size is opened without being changed
+        let mut archive = Archive::create(&path).unwrap();
+
+        let mut builder = archive.builder().unwrap().with_volume_size(100_000).unwrap();
+
+        builder.append_data(Metadata::new(FileType::File, small.len() as u128), small.as_slice(), "small").unwrap();
+
+        builder.finalise().unwrap();
+
+        drop(archive);
+
+        let before = std::fs::read(&path).unwrap();
+
+        let archive = Archive::open(&path).unwrap();
+
+        assert_eq!(archive.volume_size(), Some(100_000));
+        assert_eq!(archive.location("small"), Some((0, archive.handle("small").unwrap().unwrap().header_offset())));
+
+        drop(archive);
+
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+        assert_eq!(volume_sizes().len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parity() {
+        use crate::parity::{Parity, ParityOptions};
+        use crate::crypto::{Encryption, Identity};
+        use crate::header::Metadata;
+
+        let data = (0..400u32).map(|i| (i % 29) as u8).collect::<Vec<u8>>();
+
+        let mut archive = Archive::create_with(Cursor::new(Vec::new())).unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.append("./test/a", "a").unwrap();
+        builder.append("./test/b", "b").unwrap();
+        builder.append_data(Metadata::new(FileType::File, data.len() as u128), data.as_slice(), "data").unwrap();
+
+        builder.finalise().unwrap();
+
+        let options = ParityOptions::new(25, 64).unwrap();
+
+        //A sidecar parity set survives being written and read back
+        let mut sidecar = Vec::new();
+
+        archive.write_parity(&options, & mut sidecar).unwrap();
+
+        let mut parity = Parity::read_from(Cursor::new(sidecar.as_slice())).unwrap();
+
+        let original = archive.into_inner().into_inner();
+
+        assert_eq!(parity.length(), original.len() as u64);
+
+        //A run of damaged bytes is spread between stripes, so it can be reconstructed
+        let mut damaged = original.clone();
+
+        damaged[100..260].iter_mut().for_each(|byte| *byte ^= 0xff);
+
+        let mut storage = Cursor::new(damaged);
+
+        assert_eq!(parity.repair(& mut storage).unwrap(), vec![(64, 320)]);
+        assert!(storage.get_ref() == &original);
+        assert!(parity.repair(& mut storage).unwrap().is_empty());
+
+        //Truncation is repaired as well
+        storage.get_mut().truncate(original.len() - 10);
+
+        parity.repair(& mut storage).unwrap();
+
+        assert!(storage.get_ref() == &original);
+
+        //Too much damage cannot be repaired
+        let mut storage = Cursor::new(vec![0; original.len()]);
+
+        assert!(matches!(parity.repair(& mut storage).unwrap_err().kind(), ErrorKind::Unrepairable(_)));
+
+        //A truncated sidecar, or one claiming a huge archive, is found before anything is allocated for it
+        assert!(matches!(Parity::read_from(Cursor::new(&sidecar[..sidecar.len() - 1])).unwrap_err().kind(), ErrorKind::BadParity));
+
+        let mut huge = sidecar.clone();
+
+        huge[12..20].copy_from_slice(&u64::MAX.to_le_bytes());
+
+        assert!(matches!(Parity::read_from(Cursor::new(huge.as_slice())).unwrap_err().kind(), ErrorKind::BadParity));
+
+        //Embedded parity is skipped when opening the archive, and used by repair
+        let mut archive = Archive::open_with(Cursor::new(original)).unwrap();
+
+        archive.embed_parity(&options).unwrap();
+
+        let embedded = archive.into_inner().into_inner();
+
+        let archive = Archive::open_with(Cursor::new(embedded.clone())).unwrap();
+
+        archive.verify().unwrap();
+
+        let toc_offset = archive.toc_offset() as usize;
+
+        let mut damaged = embedded;
+
+        damaged[40..200].iter_mut().for_each(|byte| *byte = 0);
+        damaged[toc_offset..toc_offset + 30].iter_mut().for_each(|byte| *byte = 0);
+
+        assert!(Archive::open_with(Cursor::new(damaged.clone())).is_err());
+
+        let archive = Archive::repair_with(Cursor::new(damaged)).unwrap();
+
+        archive.verify().unwrap();
+
+        let mut v = Vec::new();
+
+        archive.get("data").unwrap().unwrap().read_to_end(& mut v).unwrap();
+
+        assert!(v == data);
+
+        assert!(matches!(ParityOptions::new(0, 4096).unwrap_err().kind(), ErrorKind::InvalidParityOptions(0, 4096)));
+
+        //Changing the key block in place removes the parity, so repairing cannot bring back a removed recipient
+        let alice = Identity::generate().unwrap();
+        let bob = Identity::generate().unwrap();
+
+        let mut archive = Archive::create_encrypted_with(Cursor::new(Vec::new()), &Encryption::for_recipient(alice.recipient())).unwrap();
+
+        archive.add_recipient(&bob.recipient()).unwrap();
+        archive.embed_parity(&options).unwrap();
+        archive.remove_recipient(&bob.recipient()).unwrap();
+
+        let archive = Archive::repair_encrypted_with(archive.into_inner(), &alice).unwrap();
+
+        assert_eq!(archive.recipients().unwrap(), vec![alice.recipient()]);
+        assert!(matches!(Archive::open_encrypted_with(archive.into_inner(), &bob).unwrap_err().kind(), ErrorKind::WrongKey));
+    }
+
+    #[test]
+    fn chunked() {
+        use crate::header::{Metadata, Content};
+        use crate::handle::EntryHandle;
+        use crate::builder::Chunking;
+        use crate::crypto::{Encryption, Key};
+
+        //Data without repeats, so the chunks are all different
+        let mut state = 1u32;
+
+        let old = (0..300_000).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+
+            (state >> 16) as u8
+        }).collect::<Vec<u8>>();
+
+        //A copy with bytes inserted in the middle, which only changes the chunks around the insertion
+        let new = [&old[..150_000], b"inserted", &old[150_000..]].concat();
+
+        let chunking = Chunking::new(1024, 4096, 16384).unwrap();
+
+        assert!(matches!(Chunking::new(4096, 1024, 16384).unwrap_err().kind(), ErrorKind::InvalidChunkSizes(4096, 1024, 16384)));
+
+        let read = |archive: &Archive<Cursor<Vec<u8>>>, path: &str| {
+            let mut v = Vec::new();
+
+            archive.get(path).unwrap().unwrap().read_to_end(& mut v).unwrap();
+
+            v
+        };
+
+        for encrypted in [false, true] {
+            let mut archive = if encrypted {
+                Archive::create_encrypted_with(Cursor::new(Vec::new()), &Encryption::new(Key::Raw([5; 32])).with_encrypted_metadata()).unwrap()
+            } else {
+                Archive::create_with(Cursor::new(Vec::new())).unwrap()
+            };
+
+            let mut builder = archive.builder().unwrap().with_chunking(chunking);
+
+            builder.append_data(Metadata::new(FileType::File, old.len() as u128), old.as_slice(), "old").unwrap();
+            builder.append_data(Metadata::new(FileType::File, new.len() as u128), new.as_slice(), "new").unwrap();
+
+            builder.finalise().unwrap();
+
+            //Most of the second file is shared with the first
+            assert!(archive.toc_offset() < (old.len() + old.len() / 4) as u128);
+            assert!(archive.handle("new").unwrap().unwrap().is_chunked());
+
+            assert_eq!(read(&archive, "old"), old);
+            assert_eq!(read(&archive, "new"), new);
+
+            //Seeking moves between chunks
+            let mut entry = archive.get("new").unwrap().unwrap();
+            let mut buffer = [0; 5000];
+
+            for position in [149_000, 0, 290_000, 7, 150_000] {
+                entry.seek(SeekFrom::Start(position)).unwrap();
+                entry.read_exact(& mut buffer).unwrap();
+
+                assert_eq!(&buffer[..], &new[position as usize..position as usize + 5000]);
+            }
+
+            //The chunks outlive the file they were first stored with
+            archive.remove("old").unwrap();
+            archive.defrag().unwrap();
+            archive.verify().unwrap();
+
+            assert_eq!(read(&archive, "new"), new);
+
+            let mut bytes = archive.into_inner().into_inner();
+
+            //Corrupt the toc offset, and find the chunks again from their headers
+            bytes[16] = 0xff;
+
+            let archive = if encrypted {
+                Archive::repair_encrypted_with(Cursor::new(bytes), &Key::Raw([5; 32])).unwrap()
+            } else {
+                Archive::repair_with(Cursor::new(bytes)).unwrap()
+            };
+
+            assert_eq!(archive.iter().count(), 1);
+            assert_eq!(read(&archive, "new"), new);
+
+            //Damage to a chunk is found by checking it against its content hash
+            if !encrypted {
+                let mut bytes = archive.into_inner().into_inner();
+
+                let position = bytes.windows(64).position(|window| window == &new[..64]).unwrap();
+
+                bytes[position + 10] ^= 1;
+
+                let archive = Archive::open_with(Cursor::new(bytes)).unwrap();
+
+                let error = archive.verify().unwrap_err();
+
+                assert!(matches!(error.kind(), ErrorKind::ContentHashMismatch(_)));
+                assert_eq!(error.entry_path(), Some(Path::new("new")));
+
+                //A chunk list that does not cover the file is an error rather than a panic
+                let handle = archive.handle("new").unwrap().unwrap();
+
+                let corrupt = EntryHandle::new(handle.path().to_path_buf(), handle.header().clone(), handle.header_offset(), handle.file_offset(), None, Content::Chunked(Vec::new()));
+
+                let io_error = corrupt.open(&archive).unwrap().read_to_end(& mut Vec::new()).unwrap_err();
+
+                let error = io_error.into_inner().unwrap().downcast::<crate::error::Error>().unwrap();
+
+                assert!(matches!(error.kind(), ErrorKind::ChunkNotFound(0)));
+            }
+        }
+    }
+
+    #[test]
+    fn encrypted_archive() {
+        use crate::crypto::{Encryption, Key, Cipher};
+        use argon2::Params;
+        use crate::header::Metadata;
+
+        //Large enough to be sealed in more than one chunk
+        let data = (0..150_000u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+
+        let password = Key::Password(String::from("correct horse battery staple"));
+        let raw = Key::Raw([7; 32]);
+
+        for cipher in [Cipher::XChaCha20Poly1305, Cipher::Aes256Gcm] {
+            let mut archive = Archive::create_encrypted_with(Cursor::new(Vec::new()), &Encryption::new(raw.clone()).with_key(password.clone()).with_cipher(cipher)).unwrap();
+
+            let mut builder = archive.builder().unwrap();
+
+            builder.append_data(Metadata::new(FileType::File, data.len() as u128), data.as_slice(), "data").unwrap();
+            builder.append("./test/a", "a").unwrap();
+
+            builder.finalise().unwrap();
+
+            archive.rename("a", "b").unwrap();
+            archive.defrag().unwrap();
+
+            let bytes = archive.into_inner().into_inner();
+
+            //Neither file is stored in plaintext
+            assert!(!bytes.windows(data.len().min(1000)).any(|window| window == &data[..1000]));
+            assert!(!bytes.windows(8).any(|window| window == b"Contents"));
+
+            assert!(matches!(Archive::open_with(Cursor::new(bytes.clone())).unwrap_err().kind(), ErrorKind::KeyRequired));
+            assert!(matches!(Archive::open_encrypted_with(Cursor::new(bytes.clone()), &Key::Raw([8; 32])).unwrap_err().kind(), ErrorKind::WrongKey));
+
+            //Password parameters are read from the archive, so ones that would take too much memory or time are refused
+            let params = [Params::DEFAULT_M_COST, Params::DEFAULT_T_COST, Params::DEFAULT_P_COST].map(u32::to_le_bytes).concat();
+
+            let slot = bytes.windows(params.len()).position(|window| window == params.as_slice()).unwrap();
+
+            let mut crafted = bytes.clone();
+
+            crafted[slot..slot + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+
+            let error = Archive::open_encrypted_with(Cursor::new(crafted), &password).unwrap_err();
+
+            assert!(matches!(error.kind(), ErrorKind::WrongKey));
+            assert!(error.to_string().contains("above the limits"));
+
+            let archive = Archive::open_encrypted_with(Cursor::new(bytes.clone()), &raw).unwrap();
+
+            let mut entry = archive.get("data").unwrap().unwrap();
+
+            let mut v = Vec::new();
+
+            entry.read_to_end(& mut v).unwrap();
+
+            assert_eq!(v, data);
+
+            //Seeking across chunks
+            entry.seek(SeekFrom::Start(70_000)).unwrap();
+
+            let mut v = [0u8; 100];
+
+            entry.read_exact(& mut v).unwrap();
+
+            assert_eq!(v[..], data[70_000..70_100]);
+
+            let archive = Archive::open_encrypted_with(Cursor::new(bytes.clone()), &password).unwrap();
+
+            let mut v = String::new();
+
+            archive.get("b").unwrap().unwrap().read_to_string(& mut v).unwrap();
+
+            assert_eq!(v, std::fs::read_to_string("./test/a").unwrap());
+
+            //Modify a byte of the file data
+            let file_offset = archive.handle("data").unwrap().unwrap().file_offset() as usize;
+
+            let mut tampered = bytes.clone();
+
+            tampered[file_offset + 100] ^= 1;
+
+            let archive = Archive::open_encrypted_with(Cursor::new(tampered), &raw).unwrap();
+
+            let e = archive.get("data").unwrap().unwrap().read_to_end(& mut Vec::new()).unwrap_err();
+
+            assert!(matches!(e.into_inner().unwrap().downcast::<crate::error::Error>().unwrap().kind(), ErrorKind::AuthenticationFailed));
+        }
+    }
+
+    #[test]
+    fn encrypted_metadata() {
+        use crate::crypto::{Encryption, Key};
+        use crate::header::{Metadata, HEADER_MAGIC_NUMBER};
+
+        let key = Key::Raw([3; 32]);
+
+        let mut archive = Archive::create_encrypted_with(Cursor::new(Vec::new()), &Encryption::new(key.clone()).with_encrypted_metadata()).unwrap();
+
+        assert!(archive.is_metadata_encrypted());
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.append_data(Metadata::new(FileType::File, 5), b"hello".as_ref(), "secret/name").unwrap();
+        builder.append("./test/a", "a").unwrap();
+
+        builder.finalise().unwrap();
+
+        archive.rename("a", "renamed").unwrap();
+        archive.remove("secret/name").unwrap();
+
+        let toc_offset = archive.toc_offset() as usize;
+
+        let bytes = archive.into_inner().into_inner();
+
+        //Neither the paths nor the start of each header can be found
+        assert!(!bytes.windows(6).any(|window| window == b"secret"));
+        assert!(!bytes.windows(7).any(|window| window == b"renamed"));
+        assert!(!bytes.windows(8).any(|window| window == HEADER_MAGIC_NUMBER.to_le_bytes()));
+
+        let archive = Archive::open_encrypted_with(Cursor::new(bytes.clone()), &key).unwrap();
+
+        assert!(archive.is_metadata_encrypted());
+        assert_eq!(archive.iter().map(|entry| entry.unwrap().path().to_path_buf()).collect::<Vec<_>>(), vec![PathBuf::from("renamed")]);
+        assert_eq!(archive.walk().unwrap().len(), 3);
+
+        let mut v = String::new();
+
+        archive.get("renamed").unwrap().unwrap().read_to_string(& mut v).unwrap();
+
+        assert_eq!(v, std::fs::read_to_string("./test/a").unwrap());
+
+        //Modify a byte of the toc
+        let mut tampered = bytes.clone();
+
+        *tampered.last_mut().unwrap() ^= 1;
+
+        assert!(matches!(Archive::open_encrypted_with(Cursor::new(tampered), &key).unwrap_err().kind(), ErrorKind::AuthenticationFailed));
+
+        //Lose the toc, and recover every file that was written (including the removed file) by walking the sealed headers
+        let archive = Archive::repair_encrypted_with(Cursor::new(bytes[..toc_offset].to_vec()), &key).unwrap();
+
+        let mut paths = archive.iter().map(|entry| entry.unwrap().path().to_path_buf()).collect::<Vec<_>>();
+
+        paths.sort();
+
+        assert_eq!(paths, vec![PathBuf::from("a"), PathBuf::from("renamed"), PathBuf::from("secret/name")]);
+
+        let archive = Archive::open_encrypted_with(Cursor::new(archive.into_inner().into_inner()), &key).unwrap();
+
+        let mut v = String::new();
+
+        archive.get("secret/name").unwrap().unwrap().read_to_string(& mut v).unwrap();
+
+        assert_eq!(v, "hello");
+    }
+
+    #[test]
+    fn recipients() {
+        use crate::crypto::{Encryption, Key, Identity, Recipient};
+        use crate::header::Metadata;
+        use std::str::FromStr;
+
+        let alice = Identity::generate().unwrap();
+        let bob = Identity::generate().unwrap();
+
+        //Text forms round trip
+        assert_eq!(Recipient::from_str(&alice.recipient().to_string()).unwrap(), alice.recipient());
+        assert_eq!(Identity::from_str(&alice.to_secret_string()).unwrap().recipient(), alice.recipient());
+        assert!(matches!(Recipient::from_str("tarpdate-recipient-00").unwrap_err().kind(), ErrorKind::InvalidKey(_)));
+
+        let mut archive = Archive::create_encrypted_with(Cursor::new(Vec::new()), &Encryption::for_recipient(alice.recipient())).unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.append_data(Metadata::new(FileType::File, 5), b"hello".as_ref(), "greeting").unwrap();
+
+        builder.finalise().unwrap();
+
+        //Adding a recipient only rewrites the key block
+        let toc_offset = archive.toc_offset();
+
+        archive.add_recipient(&bob.recipient()).unwrap();
+        archive.add_recipient(&bob.recipient()).unwrap();
+
+        assert_eq!(archive.toc_offset(), toc_offset);
+        assert_eq!(archive.recipients().unwrap(), vec![alice.recipient(), bob.recipient()]);
+
+        let bytes = archive.into_inner().into_inner();
+
+        //Each key from the provider is tried in turn
+        let mut archive = Archive::open_keyed_with(Cursor::new(bytes), &vec![Key::Raw([1; 32]), Key::Identity(bob.clone())]).unwrap();
+
+        let mut v = String::new();
+
+        archive.get("greeting").unwrap().unwrap().read_to_string(& mut v).unwrap();
+
+        assert_eq!(v, "hello");
+
+        archive.remove_recipient(&alice.recipient()).unwrap();
+
+        assert!(matches!(archive.remove_recipient(&alice.recipient()).unwrap_err().kind(), ErrorKind::RecipientNotFound(_)));
+        assert!(matches!(archive.remove_recipient(&bob.recipient()).unwrap_err().kind(), ErrorKind::LastKey));
+
+        let bytes = archive.into_inner().into_inner();
+
+        assert!(matches!(Archive::open_encrypted_with(Cursor::new(bytes.clone()), &alice).unwrap_err().kind(), ErrorKind::WrongKey));
+        assert!(matches!(Archive::open_with(Cursor::new(bytes.clone())).unwrap_err().kind(), ErrorKind::KeyRequired));
+
+        Archive::open_encrypted_with(Cursor::new(bytes), &bob).unwrap();
+
+        //Plain archives are opened without asking for keys
+        let plain = Archive::create_with(Cursor::new(Vec::new())).unwrap().into_inner();
+
+        assert!(!Archive::open_keyed_with(plain, &alice).unwrap().is_encrypted());
+    }
+
+    #[test]
+    fn signature() {
+        use crate::signature::SigningKey;
+        use crate::header::Metadata;
+
+        let key = SigningKey::generate().unwrap();
+        let other = SigningKey::generate().unwrap();
+
+        let mut archive = Archive::create_with(Cursor::new(Vec::new())).unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.append_data(Metadata::new(FileType::File, 5), b"hello".as_ref(), "greeting").unwrap();
+        builder.append("./test/a", "a").unwrap();
+        builder.append("./test/b", "b").unwrap();
+
+        builder.finalise().unwrap();
+
+        assert!(matches!(archive.verify_signature(&key.verifying_key()).unwrap_err().kind(), ErrorKind::NotSigned));
+
+        archive.sign(&key).unwrap();
+
+        //Defragmenting moves data but does not change what is signed
+        archive.remove("b").unwrap();
+        archive.sign(&key).unwrap();
+        archive.defrag().unwrap();
+
+        let bytes = archive.into_inner().into_inner();
+
+        let archive = Archive::open_with(Cursor::new(bytes.clone())).unwrap();
+
+        assert_eq!(archive.signer(), Some(key.verifying_key()));
+
+        archive.verify_signature(&key.verifying_key()).unwrap();
+
+        assert!(matches!(archive.verify_signature(&other.verifying_key()).unwrap_err().kind(), ErrorKind::BadSignature));
+
+        //Modify a byte of the file data
+        let file_offset = archive.handle("greeting").unwrap().unwrap().file_offset() as usize;
+
+        let mut tampered = bytes.clone();
+
+        tampered[file_offset] ^= 1;
+
+        assert!(matches!(Archive::open_with(Cursor::new(tampered)).unwrap().verify_signature(&key.verifying_key()).unwrap_err().kind(), ErrorKind::BadSignature));
+
+        //Any change removes the signature
+        let mut archive = Archive::open_with(Cursor::new(bytes)).unwrap();
+
+        let mut builder = archive.builder().unwrap();
+
+        builder.append("./test/b", "b").unwrap();
+
+        builder.finalise().unwrap();
+
+        assert!(archive.signer().is_none());
+        assert!(matches!(archive.verify_signature(&key.verifying_key()).unwrap_err().kind(), ErrorKind::NotSigned));
+    }
+
     #[test]
     fn safe_path() {
 
@@ -0,0 +1,1102 @@
+use std::path::{Path, PathBuf, Component};
+use std::fs::OpenOptions;
+use std::io::{Read, Write, BufRead, BufReader, BufWriter, Cursor};
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use tarpdate::archive::Archive;
+use tarpdate::header::{Metadata, FileType};
+use tarpdate::stream::{StreamBuilder, StreamReader};
+use tarpdate::convert::ExportOptions;
+use tarpdate::generation::Retention;
+use tarpdate::builder::{SyncOptions, collect_files};
+use tarpdate::diff::Difference;
+use tarpdate::merge::ConflictPolicy;
+use tarpdate::subset::Filter;
+use tarpdate::volume::{Volumes, volume_path};
+use tarpdate::parity::{Parity, ParityOptions};
+use tarpdate::crypto::{Encryption, Key, KeyProvider, Identity, Recipient};
+use tarpdate::error::{Result, Error, ErrorKind};
+
+///Exit code for I/O errors
+const EXIT_IO: u8 = 1;
+
+///Exit code for a path that does not exist in the archive
+const EXIT_NOT_FOUND: u8 = 3;
+
+///Exit code for a path that cannot be added to the archive (unsafe or already exists)
+const EXIT_BAD_PATH: u8 = 4;
+
+///Exit code for an archive that is corrupt or not a tarpdate archive
+const EXIT_CORRUPT: u8 = 5;
+
+///Exit code for an encrypted archive that could not be unlocked, or a key that could not be read
+const EXIT_KEY: u8 = 6;
+
+///Exit code for an archive that differs from what it was compared to
+const EXIT_DIFFERENT: u8 = 7;
+
+///Create, inspect and modify tarpdate archives
+#[derive(Parser)]
+#[command(name = "tarpdate", version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    ///Identity file used to open encrypted archives (can be given more than once)
+    #[arg(short, long, global = true)]
+    identity: Vec<PathBuf>,
+
+    ///Encrypt new archives to this recipient (can be given more than once)
+    #[arg(short, long, global = true)]
+    recipient: Vec<Recipient>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    ///Create a new archive from files and directories. Use `-` as the archive to write to stdout
+    Create {
+        archive: PathBuf,
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        ///Split the archive into volumes of this many bytes, stored at the archive path followed by .001, .002 and so on
+        #[arg(long)]
+        volume_size: Option<u64>,
+    },
+
+    ///Add files and directories to an existing archive
+    Add {
+        archive: PathBuf,
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+
+    ///Bring an archive up to date with a directory, adding new files and replacing changed files
+    Sync {
+        archive: PathBuf,
+        directory: PathBuf,
+        ///Store the files below this directory in the archive
+        #[arg(long, default_value = "")]
+        prefix: PathBuf,
+        ///Compare files by content rather than modification time
+        #[arg(long)]
+        hash: bool,
+        ///Remove files below the prefix that are no longer in the directory
+        #[arg(long)]
+        delete: bool,
+    },
+
+    ///List the files in an archive. Use `-` as the archive to read from stdin
+    List {
+        archive: PathBuf,
+        ///Show the type, permissions, size and modification time of each file
+        #[arg(short, long)]
+        long: bool,
+        ///Print the listing as JSON
+        #[arg(long, conflicts_with = "long")]
+        json: bool,
+    },
+
+    ///Extract files from an archive. Use `-` as the archive to read from stdin
+    Extract {
+        archive: PathBuf,
+        ///Only extract these paths (and anything below them)
+        paths: Vec<PathBuf>,
+        ///Directory to extract into
+        #[arg(short = 'C', long, default_value = ".")]
+        directory: PathBuf,
+    },
+
+    ///Write the contents of an archived file to stdout
+    Cat {
+        archive: PathBuf,
+        path: PathBuf,
+    },
+
+    ///Remove files from an archive
+    Rm {
+        archive: PathBuf,
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+
+    ///Rename a file in an archive
+    Mv {
+        archive: PathBuf,
+        from: PathBuf,
+        to: PathBuf,
+    },
+
+    ///Show information about an archive
+    Info {
+        archive: PathBuf,
+    },
+
+    ///Check that every file in an archive can be read
+    Verify {
+        archive: PathBuf,
+    },
+
+    ///Copy the files from other archives into an archive
+    Merge {
+        archive: PathBuf,
+        #[arg(required = true)]
+        others: Vec<PathBuf>,
+        ///What to do with a file whose path is already taken
+        #[arg(long, value_enum, default_value_t = Conflict::Error)]
+        on_conflict: Conflict,
+        ///Suffix added to the paths of conflicting files with `--on-conflict rename`
+        #[arg(long, default_value = "~")]
+        suffix: String,
+    },
+
+    ///Copy the files matching a glob or below a path into a new archive, without extracting them
+    Subset {
+        archive: PathBuf,
+        output: PathBuf,
+        ///Copy files whose path matches this glob pattern
+        #[arg(long, required_unless_present = "prefix", conflicts_with = "prefix")]
+        glob: Option<String>,
+        ///Copy files below this path
+        #[arg(long)]
+        prefix: Option<PathBuf>,
+    },
+
+    ///Compare an archive to a directory or another archive, listing files that are missing or differ. Exits with 7 if anything differs
+    Diff {
+        archive: PathBuf,
+        ///Directory the archive is compared to (as if it had been extracted there), or a newer archive
+        #[arg(default_value = ".")]
+        other: PathBuf,
+        ///Only compare files below this path, when comparing two archives
+        #[arg(long)]
+        prefix: Option<PathBuf>,
+        ///Print the differences as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    ///Rebuild the toc of a damaged archive from the file headers, first reconstructing damaged byte ranges from any parity
+    Repair {
+        archive: PathBuf,
+        ///Reconstruct damaged byte ranges from this sidecar parity file. Parity embedded in the archive is always used
+        #[arg(long)]
+        parity: Option<PathBuf>,
+    },
+
+    ///Add Reed-Solomon parity to an archive, so `repair` can reconstruct damaged byte ranges. Embedded parity is removed by the next change to the archive
+    Parity {
+        archive: PathBuf,
+        ///Size of the parity as a percentage of the size of the archive
+        #[arg(long, default_value_t = 10)]
+        overhead: u8,
+        ///Size of the shards the archive is split into, in bytes
+        #[arg(long, default_value_t = 4096)]
+        shard_size: u32,
+        ///Write the parity to this sidecar file rather than the end of the archive
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    ///Reclaim the space left by removed and renamed files
+    Defrag {
+        archive: PathBuf,
+    },
+
+    ///Discard earlier generations of an archive, and reclaim the space they used. Only the current generation is kept unless a rule keeps more
+    Prune {
+        archive: PathBuf,
+        ///Keep this many of the latest generations
+        #[arg(long, default_value_t = 0)]
+        last: usize,
+        ///Keep the latest generation of each day, for this many days
+        #[arg(long, default_value_t = 0)]
+        daily: u64,
+        ///Keep the latest generation of each week, for this many weeks
+        #[arg(long, default_value_t = 0)]
+        weekly: u64,
+        ///Keep every generation with a label
+        #[arg(long)]
+        labelled: bool,
+        ///Report what would be discarded without changing the archive
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+
+    ///Convert a ZIP or a tar (optionally compressed with gzip, xz or zstd) into a new archive. Use `-` as the input to read from stdin
+    Convert {
+        input: PathBuf,
+        archive: PathBuf,
+    },
+
+    ///Export an archive as a POSIX tar or a ZIP. It is written to stdout if no output is given, or the output is `-`
+    Export {
+        archive: PathBuf,
+        output: Option<PathBuf>,
+        ///Format to export to
+        #[arg(short, long, value_enum, default_value_t = ExportFormat::Tar)]
+        format: ExportFormat,
+        ///Store files in a ZIP without compressing them
+        #[arg(long)]
+        store: bool,
+        ///Store every file below this directory in the tar
+        #[arg(long)]
+        prefix: Option<PathBuf>,
+        ///Do not store the owner of each file
+        #[arg(long)]
+        no_owner: bool,
+    },
+
+    ///Generate an identity for encrypted archives, and print its recipient. It is written to stdout if no output is given
+    Keygen {
+        output: Option<PathBuf>,
+    },
+
+    ///List the recipients of an encrypted archive, after adding or removing any given
+    Recipients {
+        archive: PathBuf,
+        ///Allow this recipient to open the archive
+        #[arg(long)]
+        add: Vec<Recipient>,
+        ///Stop this recipient from opening the archive
+        #[arg(long)]
+        remove: Vec<Recipient>,
+    },
+}
+
+///What `merge` does with a file whose path is already taken
+#[derive(Clone, Copy, ValueEnum)]
+enum Conflict {
+    ///Keep the file that is already there
+    Keep,
+    ///Keep the file that was modified last
+    Newer,
+    ///Add the new file with a suffix
+    Rename,
+    ///Fail without changing the archive
+    Error,
+}
+
+///Formats that an archive can be exported to
+#[derive(Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    Tar,
+    Zip,
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("tarpdate: {}", e);
+            ExitCode::from(exit_code(e.kind()))
+        }
+    }
+}
+
+///Print a usage error and exit with 2, like clap does for arguments it can check itself
+fn usage_error(message: &str) -> ! {
+    Cli::command().error(clap::error::ErrorKind::ArgumentConflict, message).exit()
+}
+
+fn exit_code(kind: &ErrorKind) -> u8 {
+    match kind {
+        ErrorKind::IO(e) if e.kind() == std::io::ErrorKind::NotFound => EXIT_NOT_FOUND,
+        ErrorKind::IO(_) => EXIT_IO,
+        ErrorKind::NotFound(_) => EXIT_NOT_FOUND,
+        ErrorKind::PathConflict(_) | ErrorKind::UnsafePath(_) => EXIT_BAD_PATH,
+        ErrorKind::KeyRequired | ErrorKind::WrongKey | ErrorKind::InvalidKey(_) | ErrorKind::RecipientNotFound(_) | ErrorKind::LastKey => EXIT_KEY,
+        _ => EXIT_CORRUPT,
+    }
+}
+
+///Reads the identity files given with `--identity`, only once an archive turns out to be encrypted
+struct IdentityFiles<'a>(&'a [PathBuf]);
+
+impl KeyProvider for IdentityFiles<'_> {
+    fn keys(&self) -> Result<Vec<Key>> {
+        if self.0.is_empty() {
+            return Err(Error::new(ErrorKind::KeyRequired, String::from("Archive is encrypted, use --identity to open it")));
+        }
+
+        let mut keys = Vec::new();
+
+        for path in self.0 {
+            //Blank lines and comments (such as the recipient written by keygen) are skipped
+            for line in std::fs::read_to_string(path)?.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+                keys.push(Key::Identity(line.parse()?));
+            }
+        }
+
+        Ok(keys)
+    }
+}
+
+fn run(cli: Cli) -> Result<()> {
+    let keys = IdentityFiles(&cli.identity);
+    let recipients = cli.recipient.as_slice();
+
+    match cli.command {
+        Command::Create { archive, files, volume_size } => create(&archive, &files, volume_size, recipients),
+        Command::Add { archive, files } => add(&archive, &files, &keys),
+        Command::List { archive, long, json } => list(&archive, long, json, &keys),
+        Command::Extract { archive, paths, directory } => extract(&archive, &paths, &directory, &keys),
+        Command::Cat { archive, path } => cat(&archive, &path, &keys),
+        Command::Rm { archive, paths } => {
+            let mut archive = Archive::open_keyed(&archive, &keys)?;
+
+            let mut batch = archive.batch();
+
+            for path in paths {
+                batch.remove(&path)?;
+            }
+
+            batch.commit()
+        }
+        Command::Mv { archive, from, to } => Archive::open_keyed(&archive, &keys)?.rename(&from, &to),
+        Command::Info { archive } => info(&archive, &keys),
+        Command::Merge { archive, others, on_conflict, suffix } => {
+            let policy = match on_conflict {
+                Conflict::Keep => ConflictPolicy::KeepExisting,
+                Conflict::Newer => ConflictPolicy::Newer,
+                Conflict::Rename => ConflictPolicy::Rename(suffix),
+                Conflict::Error => ConflictPolicy::Error,
+            };
+
+            let others = others.iter().map(|other| Archive::open_keyed(other, &keys)).collect::<Result<Vec<_>>>()?;
+
+            let report = Archive::open_keyed(&archive, &keys)?.merge(&others, &policy)?;
+
+            for (path, name) in &report.renamed {
+                println!("{} renamed to {}", path.display(), name.display());
+            }
+
+            println!("Added {}, replaced {}, renamed {}, skipped {}", report.added.len(), report.replaced.len(), report.renamed.len(), report.skipped.len());
+
+            Ok(())
+        }
+        Command::Subset { archive, output, glob, prefix } => {
+            let filter = match glob {
+                Some(pattern) => Filter::glob(&pattern)?,
+                None => Filter::prefix(prefix.unwrap_or_default()),
+            };
+
+            let archive = Archive::open_keyed(&archive, &keys)?;
+
+            let copied = archive.extract_subset(&filter, & mut create_archive(&output, recipients)?)?;
+
+            println!("Copied {} files", copied.len());
+
+            Ok(())
+        }
+        Command::Diff { archive, other, prefix, json } => {
+            let same = if other.is_dir() {
+                if prefix.is_some() {
+                    usage_error("--prefix can only be used to compare two archives");
+                }
+
+                diff_dir(&archive, &other, json, &keys)?
+            } else {
+                diff_archives(&archive, &other, prefix.as_deref().unwrap_or(Path::new("")), json, &keys)?
+            };
+
+            if !same {
+                std::process::exit(EXIT_DIFFERENT.into());
+            }
+
+            Ok(())
+        }
+        Command::Verify { archive } => {
+            let archive = Archive::open_keyed(&archive, &keys)?;
+
+            archive.verify()?;
+
+            println!("{} files verified", archive.iter().count());
+
+            Ok(())
+        }
+        Command::Repair { archive, parity } => {
+            if let Some(parity) = parity {
+                let mut parity = Parity::read_from(BufReader::new(std::fs::File::open(parity)?))?;
+
+                let repaired = parity.repair(& mut Volumes::open(&archive)?)?;
+
+                println!("Reconstructed {} damaged byte ranges", repaired.len());
+            }
+
+            let archive = if cli.identity.is_empty() {
+                Archive::repair(&archive)?
+            } else {
+                Archive::repair_encrypted(&archive, &keys)?
+            };
+
+            println!("Recovered {} files", archive.iter().count());
+
+            Ok(())
+        }
+        Command::Parity { archive, overhead, shard_size, output } => {
+            let options = ParityOptions::new(overhead, shard_size)?;
+
+            let mut archive = Archive::open_keyed(&archive, &keys)?;
+
+            match output {
+                Some(output) => {
+                    let mut sidecar = BufWriter::new(std::fs::File::create(output)?);
+
+                    archive.write_parity(&options, & mut sidecar)?;
+                }
+                None => archive.embed_parity(&options)?,
+            }
+
+            Ok(())
+        }
+        Command::Defrag { archive } => {
+            let before = volume_sizes(&archive).iter().sum::<u64>();
+
+            Archive::open_keyed(&archive, &keys)?.defrag()?;
+
+            let after = volume_sizes(&archive).iter().sum::<u64>();
+
+            println!("Reclaimed {} bytes", before.saturating_sub(after));
+
+            Ok(())
+        }
+        Command::Sync { archive, directory, prefix, hash, delete } => {
+            let mut options = SyncOptions::new();
+
+            if hash {
+                options = options.with_hash_comparison();
+            }
+
+            if delete {
+                options = options.with_removal();
+            }
+
+            let mut archive = Archive::open_keyed(&archive, &keys)?;
+
+            let mut builder = archive.builder()?;
+
+            //Always replace the toc, even if a file could not be synced
+            let result = builder.sync_dir(&directory, &prefix, &options);
+
+            builder.finalise()?;
+
+            let summary = result?;
+
+            println!("Added {}, updated {}, removed {}, unchanged {}", summary.added.len(), summary.updated.len(), summary.removed.len(), summary.unchanged.len());
+
+            Ok(())
+        }
+        Command::Prune { archive, last, daily, weekly, labelled, dry_run } => {
+            let mut retention = Retention::new().with_last(last).with_daily(daily).with_weekly(weekly);
+
+            if labelled {
+                retention = retention.with_labelled();
+            }
+
+            let mut archive = Archive::open_keyed(&archive, &keys)?;
+
+            let report = if dry_run {
+                archive.prune_report(&retention)?
+            } else {
+                archive.prune(&retention)?
+            };
+
+            for number in &report.removed {
+                println!("{} generation {}", if dry_run { "Would discard" } else { "Discarded" }, number);
+            }
+
+            println!("{} {} bytes", if dry_run { "Would reclaim" } else { "Reclaimed" }, report.reclaimed);
+
+            Ok(())
+        }
+        Command::Convert { input, archive } => convert(&input, &archive, recipients),
+        Command::Export { archive, output, format, store, prefix, no_owner } => {
+            let mut options = ExportOptions::new();
+
+            if let Some(prefix) = prefix {
+                options = options.with_prefix(prefix);
+            }
+
+            if no_owner {
+                options = options.without_owner();
+            }
+
+            if store {
+                options = options.without_compression();
+            }
+
+            export(&archive, output.as_deref(), format, &options, &keys)
+        }
+        Command::Keygen { output } => keygen(output.as_deref()),
+        Command::Recipients { archive, add, remove } => {
+            let mut archive = Archive::open_keyed(&archive, &keys)?;
+
+            for recipient in &add {
+                archive.add_recipient(recipient)?;
+            }
+
+            for recipient in &remove {
+                archive.remove_recipient(recipient)?;
+            }
+
+            for recipient in archive.recipients()? {
+                println!("{}", recipient);
+            }
+
+            Ok(())
+        }
+    }
+}
+
+///Create a new archive, encrypted to `recipients` if there are any
+fn create_archive(archive: &Path, recipients: &[Recipient]) -> Result<Archive> {
+    match recipients.split_first() {
+        Some((first, rest)) => {
+            let encryption = rest.iter().fold(Encryption::for_recipient(*first), |encryption, recipient| encryption.with_recipient(*recipient));
+
+            Archive::create_encrypted(archive, &encryption)
+        }
+        None => Archive::create(archive),
+    }
+}
+
+///Returns true if the archive argument means stdin or stdout
+fn is_stdio(archive: &Path) -> bool {
+    archive == Path::new("-")
+}
+
+///Find every file in `paths`, recursing into directories. Each file is paired with the name it is stored under in the archive
+fn collect_paths(paths: &[PathBuf]) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let mut files = Vec::new();
+
+    for path in paths {
+        collect(path, & mut files)?;
+    }
+
+    Ok(files)
+}
+
+fn collect(path: &Path, files: & mut Vec<(PathBuf, PathBuf)>) -> Result<()> {
+    let paths = match std::fs::symlink_metadata(path)?.is_dir() {
+        true => collect_files(path)?.into_iter().map(|file| path.join(file)).collect(),
+        false => vec![path.to_path_buf()],
+    };
+
+    for path in paths {
+        //Stored names are relative, so the archive cannot be extracted outside of the target directory
+        let name = path.components().filter(|component| !matches!(component, Component::Prefix(_) | Component::RootDir | Component::CurDir)).collect();
+
+        files.push((path, name));
+    }
+
+    Ok(())
+}
+
+fn create(archive: &Path, files: &[PathBuf], volume_size: Option<u64>, recipients: &[Recipient]) -> Result<()> {
+    let files = collect_paths(files)?;
+
+    if is_stdio(archive) {
+        if !recipients.is_empty() {
+            usage_error("encrypted archives cannot be written to stdout");
+        }
+
+        if volume_size.is_some() {
+            usage_error("archives written to stdout cannot be split into volumes");
+        }
+
+        let mut builder = StreamBuilder::new(BufWriter::new(std::io::stdout().lock()))?;
+
+        for (path, name) in files {
+            builder.append(path, name)?;
+        }
+
+        builder.finish()?;
+
+        return Ok(());
+    }
+
+    let mut archive = create_archive(archive, recipients)?;
+
+    let mut builder = match volume_size {
+        Some(volume_size) => archive.builder()?.with_volume_size(volume_size)?,
+        None => archive.builder()?,
+    };
+
+    //Always replace the toc, even if a file could not be added
+    let result = files.into_iter().try_for_each(|(path, name)| builder.append(path, name));
+
+    builder.finalise()?;
+
+    result
+}
+
+fn add(archive: &Path, files: &[PathBuf], keys: &IdentityFiles<'_>) -> Result<()> {
+    let files = collect_paths(files)?;
+
+    let mut archive = Archive::open_keyed(archive, keys)?;
+
+    //Check for conflicts first, so we don't fail part way through
+    if let Some((_, name)) = files.iter().find(|(_, name)| archive.contains(name)) {
+        return Err(Error::new(ErrorKind::PathConflict(name.clone()), format!("Could not append file to TOC with the chosen path ({}), as path already exists in TOC", name.display())));
+    }
+
+    let mut builder = archive.builder()?;
+
+    //Always replace the toc, even if a file could not be added
+    let result = files.into_iter().try_for_each(|(path, name)| builder.append(path, name));
+
+    builder.finalise()?;
+
+    result
+}
+
+///Call `f` for every file in the archive, reading from stdin if the archive is `-`
+fn for_each_entry<F: FnMut(&Path, &Metadata, &mut dyn Read) -> Result<()>>(archive: &Path, keys: &IdentityFiles<'_>, mut f: F) -> Result<()> {
+    if is_stdio(archive) {
+        let stdin = std::io::stdin();
+
+        let mut reader = StreamReader::new(stdin.lock())?;
+
+        while let Some(mut entry) = reader.next_entry()? {
+            let path = entry.path().to_path_buf();
+            let header = entry.header().clone();
+
+            f(&path, &header, & mut entry)?;
+        }
+
+        return Ok(());
+    }
+
+    let archive = Archive::open_keyed(archive, keys)?;
+
+    let mut entries = archive.iter().collect::<Result<Vec<_>>>()?;
+
+    entries.sort_by(|a, b| a.path().cmp(b.path()));
+
+    for mut entry in entries {
+        let path = entry.path().to_path_buf();
+        let header = entry.header().clone();
+
+        f(&path, &header, & mut entry)?;
+    }
+
+    Ok(())
+}
+
+fn list(archive: &Path, long: bool, json: bool, keys: &IdentityFiles<'_>) -> Result<()> {
+    let stdout = std::io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+
+    let mut listing = Vec::new();
+
+    for_each_entry(archive, keys, |path, header, _| {
+        if json {
+            listing.push(serde_json::json!({
+                "path": path.to_string_lossy(),
+                "type": type_name(header.file_type()),
+                "size": header.len() as u64,
+                "readonly": header.readonly(),
+                "mode": header.mode(),
+                "uid": header.uid(),
+                "gid": header.gid(),
+                "link_target": header.link_target().map(|target| target.to_string_lossy()),
+                "modified": header.modified().and_then(unix_time),
+            }));
+        } else if long {
+            write!(out, "{}{} {:>5} {:>5} {:>12} {} {}",
+                     type_name(header.file_type()).chars().next().unwrap_or('?'),
+                     permissions(header),
+                     header.uid().map(|uid| uid.to_string()).unwrap_or_else(|| String::from("-")),
+                     header.gid().map(|gid| gid.to_string()).unwrap_or_else(|| String::from("-")),
+                     header.len(),
+                     header.modified().and_then(unix_time).map(format_time).unwrap_or_else(|| String::from("-")),
+                     path.display())?;
+
+            match header.link_target() {
+                Some(target) => writeln!(out, " -> {}", target.display())?,
+                None => writeln!(out)?,
+            }
+        } else {
+            writeln!(out, "{}", path.display())?;
+        }
+
+        Ok(())
+    })?;
+
+    if json {
+        serde_json::to_writer_pretty(& mut out, &listing).map_err(std::io::Error::from)?;
+        writeln!(out)?;
+    }
+
+    out.flush()?;
+
+    Ok(())
+}
+
+fn extract(archive: &Path, paths: &[PathBuf], directory: &Path, keys: &IdentityFiles<'_>) -> Result<()> {
+    //Links are created last, as their targets may not have been extracted yet
+    let mut links = Vec::new();
+
+    for_each_entry(archive, keys, |path, header, reader| {
+        if !paths.is_empty() && !paths.iter().any(|p| path.starts_with(p)) {
+            return Ok(());
+        }
+
+        let destination = directory.join(relative(path));
+
+        if let Some(parent) = destination.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        match header.file_type() {
+            FileType::Dir => {
+                std::fs::create_dir_all(&destination)?;
+            }
+            FileType::File => {
+                let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&destination)?;
+
+                std::io::copy(reader, & mut file)?;
+
+                if let Some(modified) = header.modified() {
+                    file.set_modified(modified)?;
+                }
+
+                set_permissions(&file, header)?;
+            }
+            FileType::SystemLink | FileType::HardLink => {
+                links.push((destination, header.clone()));
+            }
+        }
+
+        Ok(())
+    })?;
+
+    for (destination, header) in links {
+        let target = match header.link_target() {
+            Some(target) => target,
+            None => continue,
+        };
+
+        if header.file_type() == FileType::HardLink {
+            //Hard link targets are paths within the archive
+            std::fs::hard_link(directory.join(relative(target)), &destination)?;
+            continue;
+        }
+
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(target, &destination)?;
+
+        #[cfg(not(unix))]
+        eprintln!("tarpdate: skipping symbolic link {}", destination.display());
+    }
+
+    Ok(())
+}
+
+///Only keep normal components, so files are always extracted inside the target directory
+fn relative(path: &Path) -> PathBuf {
+    path.components().filter(|component| matches!(component, Component::Normal(_))).collect()
+}
+
+///Apply the stored mode, or the read only flag if there is no mode
+fn set_permissions(file: &std::fs::File, header: &Metadata) -> Result<()> {
+    let mut permissions = file.metadata()?.permissions();
+
+    #[cfg(unix)]
+    if let Some(mode) = header.mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        permissions.set_mode(mode & 0o7777);
+        file.set_permissions(permissions)?;
+
+        return Ok(());
+    }
+
+    if header.readonly() {
+        permissions.set_readonly(true);
+        file.set_permissions(permissions)?;
+    }
+
+    Ok(())
+}
+
+fn convert(input: &Path, archive: &Path, recipients: &[Recipient]) -> Result<()> {
+    let mut reader: Box<dyn BufRead> = if is_stdio(input) {
+        Box::new(BufReader::new(std::io::stdin()))
+    } else {
+        Box::new(BufReader::new(std::fs::File::open(input)?))
+    };
+
+    //Detect the format and compression from the magic number at the start of the stream
+    let magic = reader.fill_buf()?.to_vec();
+
+    let mut archive = create_archive(archive, recipients)?;
+
+    let report = if magic.starts_with(b"PK\x03\x04") || magic.starts_with(b"PK\x05\x06") {
+        //The central directory is at the end of a ZIP, so stdin has to be read into memory to seek in it
+        if is_stdio(input) {
+            let mut data = Vec::new();
+
+            reader.read_to_end(& mut data)?;
+
+            archive.import_zip(Cursor::new(data))?
+        } else {
+            archive.import_zip(std::fs::File::open(input)?)?
+        }
+    } else {
+        let reader: Box<dyn Read> = if magic.starts_with(&[0x1f, 0x8b]) {
+            Box::new(flate2::bufread::MultiGzDecoder::new(reader))
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Box::new(xz2::bufread::XzDecoder::new_multi_decoder(reader))
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Box::new(zstd::stream::read::Decoder::with_buffer(reader)?)
+        } else {
+            Box::new(reader)
+        };
+
+        archive.import_tar(reader)?
+    };
+
+    for (path, error) in &report.skipped {
+        eprintln!("tarpdate: skipped {}: {}", path.display(), error);
+    }
+
+    println!("Imported {} files, skipped {}", report.imported.len(), report.skipped.len());
+
+    Ok(())
+}
+
+fn export(archive: &Path, output: Option<&Path>, format: ExportFormat, options: &ExportOptions, keys: &IdentityFiles<'_>) -> Result<()> {
+    let archive = Archive::open_keyed(archive, keys)?;
+
+    let writer: Box<dyn Write> = match output {
+        Some(output) if !is_stdio(output) => Box::new(BufWriter::new(std::fs::File::create(output)?)),
+        _ => Box::new(BufWriter::new(std::io::stdout().lock())),
+    };
+
+    match format {
+        ExportFormat::Tar => archive.export_tar(writer, options)?.flush()?,
+        ExportFormat::Zip => archive.export_zip(writer, options)?.flush()?,
+    }
+
+    Ok(())
+}
+
+fn cat(archive: &Path, path: &Path, keys: &IdentityFiles<'_>) -> Result<()> {
+    let archive = Archive::open_keyed(archive, keys)?;
+
+    let mut entry = archive.get(path)?.ok_or_else(|| Error::new(ErrorKind::NotFound(path.to_path_buf()), format!("Path ({}) does not exist in TOC", path.display())))?;
+
+    let stdout = std::io::stdout();
+
+    std::io::copy(& mut entry, & mut stdout.lock())?;
+
+    Ok(())
+}
+
+///Write a new identity to `output` (or stdout), and print its recipient to stderr so it is not mixed up with the identity
+fn keygen(output: Option<&Path>) -> Result<()> {
+    let identity = Identity::generate()?;
+
+    let contents = format!("# recipient: {}\n{}\n", identity.recipient(), identity.to_secret_string());
+
+    match output {
+        Some(output) if !is_stdio(output) => {
+            let mut options = OpenOptions::new();
+
+            options.write(true).create_new(true);
+
+            //Only the owner can read the identity
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(& mut options, 0o600);
+
+            options.open(output)?.write_all(contents.as_bytes())?;
+        }
+        _ => std::io::stdout().lock().write_all(contents.as_bytes())?,
+    }
+
+    eprintln!("Recipient: {}", identity.recipient());
+
+    Ok(())
+}
+
+///The size of each volume of the archive at `path`, which is only the archive file itself unless it is split
+fn volume_sizes(path: &Path) -> Vec<u64> {
+    (0..).map(|index| std::fs::metadata(volume_path(path, index))).map_while(|meta| meta.ok()).map(|meta| meta.len()).collect()
+}
+
+fn info(path: &Path, keys: &IdentityFiles<'_>) -> Result<()> {
+    let archive = Archive::open_keyed(path, keys)?;
+
+    let sizes = volume_sizes(path);
+
+    let mut files = 0u64;
+    let mut data = 0u128;
+
+    for entry in archive.iter() {
+        files += 1;
+        data += entry?.header().len();
+    }
+
+    println!("Archive:     {}", path.display());
+    println!("Size:        {} bytes", sizes.iter().sum::<u64>());
+
+    if let Some(volume_size) = archive.volume_size() {
+        println!("Volumes:     {} of up to {} bytes", sizes.len(), volume_size);
+    }
+
+    println!("Encrypted:   {}", if archive.is_encrypted() { "yes" } else { "no" });
+    println!("Files:       {}", files);
+    println!("File data:   {} bytes", data);
+    println!("Generation:  {}", archive.generation().number());
+    println!("Toc offset:  {}", archive.toc_offset());
+    println!("Reclaimable: {} bytes", archive.reclaimable()?);
+
+    Ok(())
+}
+
+///Print the differences between an archive and a directory, returning true if there are none
+fn diff_dir(path: &Path, directory: &Path, json: bool, keys: &IdentityFiles<'_>) -> Result<bool> {
+    let diff = Archive::open_keyed(path, keys)?.diff_dir(directory)?;
+
+    if json {
+        print_json(&serde_json::json!({
+            "missing_on_disk": diff.missing_on_disk.iter().map(|path| path.to_string_lossy()).collect::<Vec<_>>(),
+            "missing_from_archive": diff.missing_from_archive.iter().map(|path| path.to_string_lossy()).collect::<Vec<_>>(),
+            "changed": json_changes(&diff.changed),
+        }))?;
+
+        return Ok(diff.is_empty());
+    }
+
+    for path in &diff.missing_on_disk {
+        println!("{}: Not in directory", path.display());
+    }
+
+    for path in &diff.missing_from_archive {
+        println!("{}: Not in archive", path.display());
+    }
+
+    print_changes(&diff.changed);
+
+    Ok(diff.is_empty())
+}
+
+///Print the differences between an archive and a newer archive, returning true if there are none
+fn diff_archives(old: &Path, new: &Path, prefix: &Path, json: bool, keys: &IdentityFiles<'_>) -> Result<bool> {
+    let old = Archive::open_keyed(old, keys)?;
+    let new = Archive::open_keyed(new, keys)?;
+
+    let diff = tarpdate::diff::diff_prefix(&old, &new, prefix)?;
+
+    if json {
+        print_json(&serde_json::json!({
+            "added": diff.added.iter().map(|path| path.to_string_lossy()).collect::<Vec<_>>(),
+            "removed": diff.removed.iter().map(|path| path.to_string_lossy()).collect::<Vec<_>>(),
+            "modified": json_changes(&diff.modified),
+            "metadata": json_changes(&diff.metadata),
+        }))?;
+
+        return Ok(diff.is_empty());
+    }
+
+    for path in &diff.added {
+        println!("{}: Added", path.display());
+    }
+
+    for path in &diff.removed {
+        println!("{}: Removed", path.display());
+    }
+
+    print_changes(&diff.modified);
+    print_changes(&diff.metadata);
+
+    Ok(diff.is_empty())
+}
+
+fn print_changes(changes: &[(PathBuf, Vec<Difference>)]) {
+    for (path, differences) in changes {
+        for difference in differences {
+            let description = match difference {
+                Difference::Type => "File type differs",
+                Difference::Mode => "Mode differs",
+                Difference::Modified => "Modification time differs",
+                Difference::Size => "Size differs",
+                Difference::Content => "Contents differ",
+                Difference::LinkTarget => "Symlink differs",
+            };
+
+            println!("{}: {}", path.display(), description);
+        }
+    }
+}
+
+fn json_changes(changes: &[(PathBuf, Vec<Difference>)]) -> serde_json::Value {
+    changes.iter().map(|(path, differences)| serde_json::json!({
+        "path": path.to_string_lossy(),
+        "differences": differences.iter().map(|difference| match difference {
+            Difference::Type => "type",
+            Difference::Mode => "mode",
+            Difference::Modified => "modified",
+            Difference::Size => "size",
+            Difference::Content => "content",
+            Difference::LinkTarget => "link_target",
+        }).collect::<Vec<_>>(),
+    })).collect()
+}
+
+fn print_json(value: &serde_json::Value) -> Result<()> {
+    let stdout = std::io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+
+    serde_json::to_writer_pretty(& mut out, value).map_err(std::io::Error::from)?;
+    writeln!(out)?;
+
+    out.flush()?;
+
+    Ok(())
+}
+
+fn type_name(file_type: FileType) -> &'static str {
+    match file_type {
+        FileType::Dir => "dir",
+        FileType::File => "file",
+        FileType::SystemLink => "link",
+        FileType::HardLink => "hardlink",
+    }
+}
+
+///Format the permissions of a file like `ls -l`
+fn permissions(header: &Metadata) -> String {
+    match header.mode() {
+        Some(mode) => (0..9).map(|bit| if mode & (0o400 >> bit) != 0 { ['r', 'w', 'x'][bit % 3] } else { '-' }).collect(),
+        None if header.readonly() => String::from("r--r--r--"),
+        None => String::from("rw-rw-rw-"),
+    }
+}
+
+fn unix_time(time: SystemTime) -> Option<u64> {
+    time.duration_since(UNIX_EPOCH).ok().map(|duration| duration.as_secs())
+}
+
+///Format seconds since the unix epoch as a UTC date and time
+fn format_time(seconds: u64) -> String {
+    let days = (seconds / 86400) as i64;
+    let time = seconds % 86400;
+
+    //Convert days since the epoch to a civil date (see http://howardhinnant.github.io/date_algorithms.html)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use crate::archive::Archive;
+use crate::entry::Entry;
+use crate::storage::Storage;
+use crate::error::{Result, Error, ErrorKind};
+
+///What [`Archive::merge`] does with a file whose path is already taken
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    ///Keep the file that is already there, and skip the new file
+    KeepExisting,
+    ///Keep whichever file was modified last. The file that is already there is kept if they were modified at the same time, or either
+    /// has no modification time
+    Newer,
+    ///Add the new file at its path followed by this suffix, and a number if that is also taken
+    Rename(String),
+    ///Fail with [`ErrorKind::PathConflict`] without changing the archive
+    Error,
+}
+
+///The result of merging archives into an archive, see [`Archive::merge`]
+#[derive(Debug, Default)]
+pub struct MergeReport {
+    ///Paths of the files that were added
+    pub added: Vec<PathBuf>,
+
+    ///Paths of the files that replaced an older file
+    pub replaced: Vec<PathBuf>,
+
+    ///Paths of the files that were added at another path, with that path
+    pub renamed: Vec<(PathBuf, PathBuf)>,
+
+    ///Paths of the files that were skipped, as the file that was already there was kept
+    pub skipped: Vec<PathBuf>,
+}
+
+///What is done with one file when merging
+enum Action {
+    Add,
+    Replace,
+    Rename(PathBuf),
+    Skip,
+}
+
+impl<S: Storage> Archive<S> {
+
+    ///Copy every file from each of `others` into the archive, in order, using `policy` when a path is already taken
+    ///
+    /// Files are copied with [`crate::builder::Builder::append_entry`], so their data is copied as it is stored where possible. A
+    /// file from one of `others` can conflict with a file in the archive, or with a file from an earlier archive in `others`. The
+    /// files of each archive are merged in order of path, and everything is merged as a single generation.
+    ///
+    /// If copying a file fails, the files copied before it are kept
+    pub fn merge<'o, T: Storage + 'o, I: IntoIterator<Item = & 'o Archive<T>>>(& mut self, others: I, policy: &ConflictPolicy) -> Result<MergeReport> {
+        self.merge_archives(others.into_iter().collect(), policy).map_err(|e| self.error_context(e))
+    }
+
+    fn merge_archives<T: Storage>(& mut self, others: Vec<&Archive<T>>, policy: &ConflictPolicy) -> Result<MergeReport> {
+
+        let mut entries = Vec::new();
+
+        for other in others {
+            let mut other_entries = other.iter().collect::<Result<Vec<_>>>()?;
+
+            other_entries.sort_by(|a, b| a.path().cmp(b.path()));
+
+            entries.extend(other_entries);
+        }
+
+        //Every conflict is resolved before anything is written, so a conflict fails without changing the archive
+        let actions = self.plan_merge(&entries, policy)?;
+
+        let mut report = MergeReport::default();
+
+        let mut builder = self.builder()?;
+
+        let mut result = Ok(());
+
+        for (entry, action) in entries.iter().zip(actions) {
+            let path = entry.path().to_path_buf();
+
+            result = match &action {
+                Action::Add => builder.copy_entry(entry, &path),
+                Action::Replace => builder.replace_with(&path, |builder| builder.copy_entry(entry, &path)),
+                Action::Rename(name) => builder.copy_entry(entry, name),
+                Action::Skip => Ok(()),
+            }.map_err(|e| e.with_entry(&path));
+
+            if result.is_err() {
+                break;
+            }
+
+            match action {
+                Action::Add => report.added.push(path),
+                Action::Replace => report.replaced.push(path),
+                Action::Rename(name) => report.renamed.push((path, name)),
+                Action::Skip => report.skipped.push(path),
+            }
+        }
+
+        //The toc is always written, so the archive matches the files that were copied
+        builder.finalise()?;
+
+        result.map(|()| report)
+    }
+
+    ///Decide what to do with each of `entries`
+    fn plan_merge<T: Storage>(&self, entries: &[Entry<'_, T>], policy: &ConflictPolicy) -> Result<Vec<Action>> {
+
+        //The modification time of every path added by the merge, or looked up in the archive
+        let mut taken: HashMap<PathBuf, Option<SystemTime>> = HashMap::new();
+
+        let mut actions = Vec::new();
+
+        for entry in entries {
+            let path = entry.path();
+
+            let existing = match taken.get(path) {
+                Some(modified) => Some(*modified),
+                None => self.get(path)?.map(|existing| existing.header().modified()),
+            };
+
+            let action = match (existing, policy) {
+                (None, _) => Action::Add,
+                (Some(_), ConflictPolicy::KeepExisting) => Action::Skip,
+                (Some(modified), ConflictPolicy::Newer) => match (modified, entry.header().modified()) {
+                    (Some(modified), Some(new)) if new > modified => Action::Replace,
+                    _ => Action::Skip,
+                },
+                (Some(_), ConflictPolicy::Rename(suffix)) => Action::Rename(self.free_path(path, suffix, &taken)),
+                (Some(_), ConflictPolicy::Error) => {
+                    return Err(Error::new(ErrorKind::PathConflict(PathBuf::from(path)), format!("Could not merge entry ({}), as path already exists", path.display())));
+                }
+            };
+
+            match &action {
+                Action::Add | Action::Replace => { taken.insert(path.to_path_buf(), entry.header().modified()); }
+                Action::Rename(name) => { taken.insert(name.clone(), entry.header().modified()); }
+                Action::Skip => { taken.insert(path.to_path_buf(), existing.flatten()); }
+            }
+
+            actions.push(action);
+        }
+
+        Ok(actions)
+    }
+
+    ///Find the first path made of `path` and `suffix`, followed by a number if needed, that is not in the archive or `taken`
+    fn free_path(&self, path: &Path, suffix: &str, taken: &HashMap<PathBuf, Option<SystemTime>>) -> PathBuf {
+
+        let mut number = 0;
+
+        loop {
+            let mut name = path.as_os_str().to_owned();
+
+            name.push(suffix);
+
+            if number > 0 {
+                name.push(number.to_string());
+            }
+
+            let name = PathBuf::from(name);
+
+            if !self.contains(&name) && !taken.contains_key(&name) {
+                return name;
+            }
+
+            number += 1;
+        }
+    }
+
+}
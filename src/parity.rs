@@ -0,0 +1,514 @@
+use std::io::{Read, Write, Seek, SeekFrom};
+use bincode::Options;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use crate::archive::Archive;
+use crate::crypto::HASH_SIZE;
+use crate::storage::Storage;
+use crate::error::{Result, Error, ErrorKind};
+
+///Written at the start of every parity set, whether it is embedded in an archive or stored in a sidecar file
+const PARITY_MAGIC_NUMBER: u64 = 0x3d9a62f1c8e4b705;
+
+///Identifies the trailer after parity embedded in an archive, see [`Archive::embed_parity`]
+const PARITY_TRAILER_MAGIC_NUMBER: u128 = 0xa41f6c2d9e8b3750c7d2e19f4b6a0853;
+
+///Size of the trailer after embedded parity (16 bytes of parity offset, 16 bytes of trailer magic number)
+const PARITY_TRAILER_SIZE: u64 = 32;
+
+///The most data shards in a stripe, so that a stripe and its parity shards never need more than the 256 shards Reed-Solomon over
+/// GF(2^8) supports
+const MAX_DATA_SHARDS: u64 = 128;
+
+///How much parity to compute, see [`Archive::write_parity`] and [`Archive::embed_parity`]
+///
+/// The archive is split into shards, and the shards are grouped into stripes that each get parity shards. Any damaged shards in a stripe
+/// can be reconstructed, as long as there are no more of them than the stripe has parity shards. Each stripe is made of shards spread
+/// evenly through the archive, so damage to a run of neighbouring bytes is spread between stripes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParityOptions {
+    overhead: u8,
+    shard_size: u32,
+}
+
+impl ParityOptions {
+    ///Create parity options, with the parity taking up `overhead` percent of the size of the archive, and shards of `shard_size` bytes
+    ///
+    /// Fails with [`ErrorKind::InvalidParityOptions`] unless `overhead` is between 1 and 100, and `shard_size` between 64 bytes and 1MiB
+    pub fn new(overhead: u8, shard_size: u32) -> Result<Self> {
+
+        if !(1..=100).contains(&overhead) || !(64..=1024 * 1024).contains(&shard_size) {
+            return Err(Error::new(ErrorKind::InvalidParityOptions(overhead, shard_size), format!("Parity options (overhead {}%, shard size {}) are out of range", overhead, shard_size)));
+        }
+
+        Ok(Self {
+            overhead,
+            shard_size,
+        })
+    }
+
+    ///The size of the parity as a percentage of the size of the archive, rounded up to whole shards
+    pub fn overhead(&self) -> u8 { self.overhead }
+
+    ///The size of each shard
+    pub fn shard_size(&self) -> u32 { self.shard_size }
+}
+
+///10% overhead in 4KiB shards
+impl Default for ParityOptions {
+    fn default() -> Self {
+        Self {
+            overhead: 10,
+            shard_size: 4096,
+        }
+    }
+}
+
+///The layout of a parity set, written after the parity magic number and before the record of each stripe
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+struct Layout {
+    shard_size: u32,
+
+    ///The number of bytes covered, from the start of the archive
+    length: u64,
+
+    ///The number of data shards and parity shards in each stripe
+    data_shards: u32,
+    parity_shards: u32,
+}
+
+impl Layout {
+    fn new(length: u64, options: &ParityOptions) -> Self {
+
+        let data_shards = length.div_ceil(options.shard_size as u64).clamp(1, MAX_DATA_SHARDS);
+
+        Self {
+            shard_size: options.shard_size,
+            length,
+            data_shards: data_shards as u32,
+            parity_shards: (data_shards * options.overhead as u64).div_ceil(100) as u32,
+        }
+    }
+
+    ///Check a layout that was read back, so a damaged parity set cannot index out of bounds, make the codec panic or allocate too much
+    fn is_consistent(&self) -> bool {
+        self.shard_size > 0
+            && self.data_shards as u64 == self.shards().clamp(1, MAX_DATA_SHARDS)
+            && self.parity_shards > 0 && self.parity_shards <= 256 - self.data_shards
+    }
+
+    fn codec(&self) -> Result<ReedSolomon> {
+        ReedSolomon::new(self.data_shards as usize, self.parity_shards as usize).map_err(bad_parity)
+    }
+
+    ///The number of shards covering the archive, the last of which is padded with zeros
+    fn shards(&self) -> u64 {
+        self.length.div_ceil(self.shard_size.max(1) as u64)
+    }
+
+    ///The number of stripes, each of which has [`Layout::data_shards`] shards (some of which may be past the end of the archive)
+    fn stripes(&self) -> u64 {
+        self.shards().div_ceil(self.data_shards as u64)
+    }
+
+    ///The size of the record of each stripe: the hash of each data shard, then each parity shard, then the hash of each parity shard
+    fn record_size(&self) -> u64 {
+        self.data_shards as u64 * HASH_SIZE as u64 + self.parity_shards as u64 * (self.shard_size as u64 + HASH_SIZE as u64)
+    }
+
+    ///The index of every shard in `stripe`, or `None` for positions past the last shard, which are treated as zeros
+    fn stripe(&self, stripe: u64) -> impl Iterator<Item = Option<u64>> {
+        let (stripes, shards) = (self.stripes(), self.shards());
+
+        (0..self.data_shards as u64).map(move |position| Some(stripe + position * stripes).filter(|index| *index < shards))
+    }
+
+    ///The start and end of shard `index`, where the last shard stops at the end of the parity
+    fn shard_range(&self, index: u64) -> (u64, u64) {
+        let start = index * self.shard_size as u64;
+
+        (start, (start + self.shard_size as u64).min(self.length))
+    }
+
+    ///Read shard `index`, padded with zeros if it is short (because it is the last shard, or the storage has been truncated)
+    fn read_shard<S: Storage>(&self, storage: & mut S, index: u64) -> Result<Vec<u8>> {
+
+        let (start, end) = self.shard_range(index);
+
+        let mut shard = Vec::with_capacity(self.shard_size as usize);
+
+        storage.seek(SeekFrom::Start(start))?;
+        storage.take(end - start).read_to_end(& mut shard)?;
+
+        shard.resize(self.shard_size as usize, 0);
+
+        Ok(shard)
+    }
+
+    ///Compute the record of `stripe` from the shards in `storage`
+    fn record<S: Storage>(&self, storage: & mut S, codec: &ReedSolomon, stripe: u64) -> Result<Vec<u8>> {
+
+        let mut shards = Vec::with_capacity(self.data_shards as usize + self.parity_shards as usize);
+
+        let mut record = Vec::with_capacity(self.record_size() as usize);
+
+        for index in self.stripe(stripe) {
+            let shard = match index {
+                Some(index) => self.read_shard(storage, index)?,
+                None => vec![0; self.shard_size as usize],
+            };
+
+            record.extend_from_slice(&hash(&shard));
+
+            shards.push(shard);
+        }
+
+        shards.resize(shards.len() + self.parity_shards as usize, vec![0; self.shard_size as usize]);
+
+        codec.encode(& mut shards).map_err(bad_parity)?;
+
+        for shard in &shards[self.data_shards as usize..] {
+            record.extend_from_slice(shard);
+        }
+
+        for shard in &shards[self.data_shards as usize..] {
+            record.extend_from_slice(&hash(shard));
+        }
+
+        Ok(record)
+    }
+
+    ///Write the parity magic number, the layout and the record of every stripe, computing one stripe at a time
+    fn write_parity<S: Storage, W: Write>(&self, storage: & mut S, mut writer: W) -> Result<()> {
+
+        bincode::serialize_into(& mut writer, &PARITY_MAGIC_NUMBER)?;
+        bincode::serialize_into(& mut writer, self)?;
+
+        let codec = self.codec()?;
+
+        for stripe in 0..self.stripes() {
+            writer.write_all(&self.record(storage, &codec, stripe)?)?;
+        }
+
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    ///Read the magic number and layout of a parity set from `reader`, whose records follow it in the next `limit` bytes
+    ///
+    /// The layout is checked against `limit`, so a damaged parity set cannot claim more records than there is space for
+    fn read_from<R: Read>(mut reader: R, limit: u64) -> Result<Self> {
+
+        let magic_number: u64 = bincode::deserialize_from(& mut reader).map_err(|_| bad_parity("Parity set could not be read"))?;
+
+        if magic_number != PARITY_MAGIC_NUMBER {
+            return Err(bad_parity(format!("Parity magic number ({:#x}) does not match", magic_number)));
+        }
+
+        let options = bincode::DefaultOptions::new().with_fixint_encoding().allow_trailing_bytes().with_limit(limit);
+
+        let layout: Layout = options.deserialize_from(reader).map_err(|e| bad_parity(format!("Could not deserialise parity set ({})", e)))?;
+
+        //The magic number, layout and records must all fit
+        let fits = match (layout.stripes().checked_mul(layout.record_size()), bincode::serialized_size(&layout)) {
+            (Some(records), Ok(header)) => records.saturating_add(header).saturating_add(8) <= limit,
+            _ => false,
+        };
+
+        if !layout.is_consistent() || !fits {
+            return Err(bad_parity("Parity set is damaged"));
+        }
+
+        Ok(layout)
+    }
+
+    ///Find the damaged shards of `stripe` in `storage`, and reconstruct them from the other shards and the parity in `record`
+    ///
+    /// `stored` is the length of the storage, as shards past the end are damaged even if the missing bytes would have been zeros, like
+    /// the padding of the last shard. Returns the byte range of every shard that was rewritten.
+    fn repair_stripe<S: Storage>(&self, storage: & mut S, codec: &ReedSolomon, stripe: u64, record: &[u8], stored: u64) -> Result<Vec<(u64, u64)>> {
+
+        let (hashes, rest) = record.split_at(self.data_shards as usize * HASH_SIZE);
+        let (parity, parity_hashes) = rest.split_at(self.parity_shards as usize * self.shard_size as usize);
+
+        let mut shards = Vec::with_capacity(self.data_shards as usize + self.parity_shards as usize);
+
+        let mut damaged = Vec::new();
+
+        for ((position, index), shard_hash) in self.stripe(stripe).enumerate().zip(hashes.chunks_exact(HASH_SIZE)) {
+            let shard = match index {
+                Some(index) => {
+                    let shard = self.read_shard(storage, index)?;
+
+                    if self.shard_range(index).1 <= stored && hash(&shard) == shard_hash {
+                        Some(shard)
+                    } else {
+                        damaged.push((position, index));
+
+                        None
+                    }
+                }
+                None => Some(vec![0; self.shard_size as usize]),
+            };
+
+            shards.push(shard);
+        }
+
+        if damaged.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        for (shard, shard_hash) in parity.chunks_exact(self.shard_size as usize).zip(parity_hashes.chunks_exact(HASH_SIZE)) {
+            shards.push(Some(shard.to_vec()).filter(|shard| hash(shard) == shard_hash));
+        }
+
+        let offset = damaged[0].1 * self.shard_size as u64;
+
+        codec.reconstruct_data(& mut shards)
+            .map_err(|e| Error::new(ErrorKind::Unrepairable(offset), format!("Could not reconstruct {} damaged shards ({})", damaged.len(), e)).with_offset(offset as u128))?;
+
+        let mut repaired = Vec::new();
+
+        for (position, index) in damaged {
+            let (start, end) = self.shard_range(index);
+
+            let shard = shards[position].as_ref().expect("damaged shards are reconstructed");
+
+            storage.seek(SeekFrom::Start(start))?;
+            storage.write_all(&shard[..(end - start) as usize])?;
+
+            repaired.push((start, end));
+        }
+
+        Ok(repaired)
+    }
+
+    ///Repair every stripe of `storage` in order, reading the record of each stripe with `read_record`
+    fn repair<S: Storage, F: FnMut(& mut S, & mut [u8]) -> Result<()>>(&self, storage: & mut S, mut read_record: F) -> Result<Vec<(u64, u64)>> {
+
+        let codec = self.codec()?;
+
+        let stored = storage.seek(SeekFrom::End(0))?;
+
+        let mut record = vec![0; self.record_size() as usize];
+
+        let mut repaired = Vec::new();
+
+        for stripe in 0..self.stripes() {
+            read_record(storage, & mut record)?;
+
+            repaired.extend(self.repair_stripe(storage, &codec, stripe, &record, stored)?);
+        }
+
+        storage.flush()?;
+
+        //Neighbouring shards are in different stripes, so join their ranges
+        repaired.sort_unstable();
+
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+
+        for (start, end) in repaired {
+            match ranges.last_mut() {
+                Some(last) if last.1 == start => last.1 = end,
+                _ => ranges.push((start, end)),
+            }
+        }
+
+        Ok(ranges)
+    }
+}
+
+///Reed-Solomon parity over the bytes of an archive, read from a sidecar file written by [`Archive::write_parity`] and used to reconstruct
+/// damaged byte ranges
+///
+/// The hash of every shard is stored with the parity, so damaged shards can be found and reconstructed from the rest of their stripe.
+/// Parity covers the archive as it was when the parity was computed, so it has to be computed again after the archive changes, or
+/// repairing the archive would undo the changes. The parity is read one stripe at a time while repairing, so only the layout is kept
+/// in memory.
+#[derive(Debug)]
+pub struct Parity<R: Read + Seek> {
+    layout: Layout,
+    reader: R,
+
+    //The position of the record of the first stripe in the reader
+    records: u64,
+}
+
+impl<R: Read + Seek> Parity<R> {
+    ///Read the layout of a parity set written by [`Archive::write_parity`], keeping `reader` to read the parity from while repairing
+    ///
+    /// Fails with [`ErrorKind::BadParity`] if the reader does not contain a parity set, or the parity set is damaged or truncated
+    pub fn read_from(mut reader: R) -> Result<Self> {
+
+        let start = reader.stream_position()?;
+        let end = reader.seek(SeekFrom::End(0))?;
+
+        reader.seek(SeekFrom::Start(start))?;
+
+        let layout = Layout::read_from(& mut reader, end.saturating_sub(start))?;
+
+        let records = reader.stream_position()?;
+
+        Ok(Self {
+            layout,
+            reader,
+            records,
+        })
+    }
+
+    ///The number of bytes covered, from the start of the archive
+    pub fn length(&self) -> u64 {
+        self.layout.length
+    }
+
+    ///The size of each shard
+    pub fn shard_size(&self) -> u32 {
+        self.layout.shard_size
+    }
+
+    ///Find the shards of the first [`Parity::length`] bytes of `storage` that have been damaged, and reconstruct them
+    ///
+    /// Returns the start and end of every byte range that was rewritten, in order. Anything missing from the end of the storage is
+    /// reconstructed as well. Fails with [`ErrorKind::Unrepairable`] if a stripe has more damaged shards than parity shards, after
+    /// repairing the stripes before it.
+    pub fn repair<S: Storage>(& mut self, storage: & mut S) -> Result<Vec<(u64, u64)>> {
+
+        self.reader.seek(SeekFrom::Start(self.records))?;
+
+        let reader = & mut self.reader;
+
+        self.layout.repair(storage, |_, record| Ok(reader.read_exact(record)?))
+    }
+}
+
+fn hash(shard: &[u8]) -> [u8; HASH_SIZE] {
+    Sha256::digest(shard).into()
+}
+
+fn bad_parity<E: ToString>(e: E) -> Error {
+    Error::new(ErrorKind::BadParity, e.to_string())
+}
+
+///Find the offset of the parity embedded at the end of storage `length` bytes long, if there is any
+pub (in crate) fn embedded_offset<S: Storage>(storage: & mut S, length: u64) -> Result<Option<u64>> {
+
+    if length < PARITY_TRAILER_SIZE {
+        return Ok(None);
+    }
+
+    storage.seek(SeekFrom::Start(length - PARITY_TRAILER_SIZE))?;
+
+    let offset: u128 = bincode::deserialize_from(& mut *storage)?;
+    let magic_number: u128 = bincode::deserialize_from(& mut *storage)?;
+
+    Ok(Some(offset as u64).filter(|offset| magic_number == PARITY_TRAILER_MAGIC_NUMBER && (*offset as u128) < (length - PARITY_TRAILER_SIZE) as u128))
+}
+
+///Repair `storage` with the parity embedded at its end, if there is any, returning the byte ranges that were rewritten
+pub (in crate) fn repair_embedded<S: Storage>(storage: & mut S) -> Result<Vec<(u64, u64)>> {
+
+    let length = storage.seek(SeekFrom::End(0))?;
+
+    let offset = match embedded_offset(storage, length)? {
+        Some(offset) => offset,
+        None => return Ok(Vec::new()),
+    };
+
+    storage.seek(SeekFrom::Start(offset))?;
+
+    let layout = Layout::read_from(& mut *storage, length - PARITY_TRAILER_SIZE - offset).map_err(|e| e.with_offset(offset as u128))?;
+
+    //The records are read from the same storage that is being repaired, so each is found by its position
+    let mut position = storage.stream_position()?;
+
+    layout.repair(storage, |storage, record| {
+        storage.seek(SeekFrom::Start(position))?;
+        storage.read_exact(record)?;
+
+        position += record.len() as u64;
+
+        Ok(())
+    })
+}
+
+impl<S: Storage> Archive<S> {
+
+    ///Compute Reed-Solomon parity over the whole archive, and write it to `writer`, such as a sidecar file
+    ///
+    /// The parity is computed and written one stripe at a time. A damaged archive can then be repaired with [`Parity::repair`], before
+    /// opening it or rebuilding its toc.
+    pub fn write_parity<W: Write>(&self, options: &ParityOptions, writer: W) -> Result<()> {
+        Layout::new(self.data_end as u64, options).write_parity(& mut *self.storage(), writer).map_err(|e| self.error_context(e))
+    }
+
+    ///Compute Reed-Solomon parity over the whole archive, and store it at the end of the archive after the toc
+    ///
+    /// [`Archive::repair`] uses the embedded parity to reconstruct damaged byte ranges before rebuilding the toc. As the parity covers
+    /// the archive as it is, it is removed by the next change to the archive (in the same way as the tocs of earlier generations are
+    /// kept), and has to be embedded again. Any parity that is already embedded is replaced.
+    pub fn embed_parity(& mut self, options: &ParityOptions) -> Result<()> {
+        self.append_parity(options).map_err(|e| self.error_context(e))
+    }
+
+    ///Remove any parity embedded after the toc, before the archive is changed in place, as repairing with it would undo the change
+    ///
+    /// Changes that write a toc remove the parity anyway, as everything after the toc is removed
+    pub (in crate) fn drop_parity(& mut self) -> Result<()> {
+
+        let data_end = self.data_end as u64;
+
+        let storage = self.storage_mut();
+
+        let length = storage.seek(SeekFrom::End(0))?;
+
+        if embedded_offset(storage, length)?.is_some() {
+            storage.set_len(data_end)?;
+        }
+
+        Ok(())
+    }
+
+    fn append_parity(& mut self, options: &ParityOptions) -> Result<()> {
+
+        //Remove any earlier parity (or the trailer of a streamed archive), so the parity covers the archive up to the end of the toc
+        self.rewrite_toc()?;
+
+        let offset = self.data_end as u64;
+
+        let layout = Layout::new(offset, options);
+
+        let codec = layout.codec()?;
+
+        let storage = self.storage_mut();
+
+        let mut header = Vec::new();
+
+        bincode::serialize_into(& mut header, &PARITY_MAGIC_NUMBER)?;
+        bincode::serialize_into(& mut header, &layout)?;
+
+        storage.seek(SeekFrom::Start(offset))?;
+        storage.write_all(&header)?;
+
+        //The shards are read from the same storage the records are written to, so each record is written at its position
+        let mut position = offset + header.len() as u64;
+
+        for stripe in 0..layout.stripes() {
+            let record = layout.record(storage, &codec, stripe)?;
+
+            storage.seek(SeekFrom::Start(position))?;
+            storage.write_all(&record)?;
+
+            position += record.len() as u64;
+        }
+
+        bincode::serialize_into(& mut *storage, &(offset as u128))?;
+        bincode::serialize_into(& mut *storage, &PARITY_TRAILER_MAGIC_NUMBER)?;
+
+        storage.flush()?;
+
+        Ok(())
+    }
+
+}
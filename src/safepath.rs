@@ -4,7 +4,7 @@ use serde::{Serialize, Deserialize, Deserializer, de};
 use std::borrow::Borrow;
 
 ///A wrapper around Path of PathBuf that prevents invalid or unsafe archive paths (like the infamous '../' component)
-#[derive(Debug, Serialize, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Serialize, PartialEq, Eq, Hash)]
 pub (in crate) struct SafePathBuf {
     path: PathBuf,
 }
@@ -16,7 +16,7 @@ impl<'de> Deserialize<'de> for SafePathBuf {
 
         match path {
             Ok(k) => {
-                SafePathBuf::try_from(k).map_err(|x| de::Error::custom(x))
+                SafePathBuf::try_from(k).map_err(de::Error::custom)
             }
             Err(e) => {
                 Err(e)
@@ -30,9 +30,7 @@ impl TryFrom<PathBuf> for SafePathBuf {
     type Error = crate::error::Error;
 
     fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
-        if let Err(e) = Self::verify(path.as_path()) {
-            return Err(e);
-        }
+        Self::verify(path.as_path())?;
 
         Ok(Self {
             path,
@@ -44,9 +42,7 @@ impl TryFrom<&Path> for SafePathBuf {
     type Error = crate::error::Error;
 
     fn try_from(path: &Path) -> Result<Self, Self::Error> {
-        if let Err(e) = Self::verify(path) {
-            return Err(e);
-        }
+        Self::verify(path)?;
 
         Ok(Self {
             path: PathBuf::from(path),
@@ -65,7 +61,7 @@ impl SafePathBuf {
 
         for component in path.components() {
             if let Component::ParentDir = component {
-                return Err(crate::error::Error::new(crate::error::ErrorKind::UnsafePath(PathBuf::from(path)), format!("Illegal parent directory (..) found in path ({}).", path.to_str().unwrap())))
+                return Err(crate::error::Error::new(crate::error::ErrorKind::UnsafePath(PathBuf::from(path)), format!("Illegal parent directory (..) found in path ({}).", path.display())))
             }
         }
 
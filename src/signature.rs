@@ -0,0 +1,163 @@
+use std::fmt::{Debug, Formatter};
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use ed25519_dalek::{Signer, Verifier};
+use crate::archive::Archive;
+use crate::crypto::{KEY_SIZE, random};
+use crate::storage::Storage;
+use crate::error::{Result, Error, ErrorKind};
+
+///Written at the start of every signed message, so a signature over an archive cannot be mistaken for a signature over anything else
+const SIGNATURE_CONTEXT: &[u8] = b"tarpdate signature";
+
+///The secret half of an Ed25519 key pair, used to sign archives with [`Archive::sign`]
+#[derive(Clone)]
+pub struct SigningKey {
+    key: ed25519_dalek::SigningKey,
+}
+
+impl SigningKey {
+    ///Generate a new random signing key
+    pub fn generate() -> Result<Self> {
+        Ok(Self::from_bytes(random()?))
+    }
+
+    ///Create a signing key from an Ed25519 secret key
+    pub fn from_bytes(secret: [u8; KEY_SIZE]) -> Self {
+        Self {
+            key: ed25519_dalek::SigningKey::from_bytes(&secret),
+        }
+    }
+
+    ///Get the Ed25519 secret key
+    pub fn to_bytes(&self) -> [u8; KEY_SIZE] {
+        self.key.to_bytes()
+    }
+
+    ///Get the key that signatures made with this key are verified with
+    pub fn verifying_key(&self) -> VerifyingKey {
+        VerifyingKey {
+            key: self.key.verifying_key(),
+        }
+    }
+}
+
+impl Debug for SigningKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        //Never print the secret itself
+        write!(f, "SigningKey({:?})", self.verifying_key())
+    }
+}
+
+///The public half of an Ed25519 key pair, used to check signatures with [`Archive::verify_signature`]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct VerifyingKey {
+    key: ed25519_dalek::VerifyingKey,
+}
+
+impl VerifyingKey {
+    ///Create a verifying key from an Ed25519 public key, failing with [`ErrorKind::InvalidKey`] if it is not a valid point
+    pub fn from_bytes(public: [u8; KEY_SIZE]) -> Result<Self> {
+        let key = ed25519_dalek::VerifyingKey::from_bytes(&public)
+            .map_err(|e| Error::new(ErrorKind::InvalidKey(public.iter().map(|byte| format!("{:02x}", byte)).collect()), format!("Not a valid Ed25519 public key ({})", e)))?;
+
+        Ok(Self {
+            key,
+        })
+    }
+
+    ///Get the Ed25519 public key
+    pub fn to_bytes(&self) -> [u8; KEY_SIZE] {
+        self.key.to_bytes()
+    }
+}
+
+impl Debug for VerifyingKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "VerifyingKey({})", self.key.to_bytes().iter().map(|byte| format!("{:02x}", byte)).collect::<String>())
+    }
+}
+
+///A signature stored in the toc, with the public key of the signer
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub (in crate) struct StoredSignature {
+    signer: [u8; KEY_SIZE],
+    signature: Vec<u8>,
+}
+
+impl<S: Storage> Archive<S> {
+
+    ///Sign the archive with `key`, replacing any previous signature
+    ///
+    /// The signature covers the path and metadata of every file in the toc, and a SHA-256 hash of its contents, so every file
+    /// is read. Adding, removing or renaming files removes the signature, so the archive must be signed again afterwards.
+    /// Defragmenting does not change the signed contents, so the signature is kept.
+    pub fn sign(& mut self, key: &SigningKey) -> Result<()> {
+
+        let message = self.signed_message().map_err(|e| self.error_context(e))?;
+
+        self.toc.signature = Some(StoredSignature {
+            signer: key.verifying_key().to_bytes(),
+            signature: key.key.sign(&message).to_bytes().to_vec(),
+        });
+
+        self.write_toc()
+    }
+
+    ///Check that the archive was signed by `key`, and that nothing it covers has changed since
+    ///
+    /// Fails with [`ErrorKind::NotSigned`] if the archive has no signature (for example, because files were added after it was
+    /// signed), or [`ErrorKind::BadSignature`] if it was signed by a different key or has been modified.
+    pub fn verify_signature(&self, key: &VerifyingKey) -> Result<()> {
+        self.verify_signed_message(key).map_err(|e| self.error_context(e))
+    }
+
+    ///Get the key that claims to have signed the archive, if it is signed. The signature is not checked, see [`Archive::verify_signature`]
+    pub fn signer(&self) -> Option<VerifyingKey> {
+        self.toc.signature.as_ref().and_then(|signature| VerifyingKey::from_bytes(signature.signer).ok())
+    }
+
+    fn verify_signed_message(&self, key: &VerifyingKey) -> Result<()> {
+
+        let stored = match &self.toc.signature {
+            Some(stored) => stored,
+            None => return Err(Error::new(ErrorKind::NotSigned, String::from("Archive is not signed"))),
+        };
+
+        if stored.signer != key.to_bytes() {
+            return Err(Error::new(ErrorKind::BadSignature, String::from("Archive was signed by a different key")));
+        }
+
+        let signature = ed25519_dalek::Signature::from_slice(&stored.signature)
+            .map_err(|_| Error::new(ErrorKind::BadSignature, String::from("Stored signature is malformed")))?;
+
+        key.key.verify(&self.signed_message()?, &signature)
+            .map_err(|_| Error::new(ErrorKind::BadSignature, String::from("Signature does not match, the archive has been modified since it was signed")))
+    }
+
+    ///Serialise the path, metadata and content hash of every file, sorted by path so the message does not depend on the order of the toc
+    fn signed_message(&self) -> Result<Vec<u8>> {
+
+        let mut paths = self.toc._table.keys().collect::<Vec<_>>();
+
+        paths.sort_by(|a, b| a.as_path().cmp(b.as_path()));
+
+        let mut message = SIGNATURE_CONTEXT.to_vec();
+
+        for path in paths {
+            let path: &Path = path.as_path();
+
+            let mut entry = self.get(path)?.ok_or_else(|| Error::new(ErrorKind::NotFound(path.to_path_buf()), format!("Path ({}) does not exist in TOC", path.display())))?;
+
+            let mut hasher = Sha256::new();
+
+            std::io::copy(& mut entry, & mut hasher).map_err(|e| Error::from(e).with_entry(path))?;
+
+            bincode::serialize_into(& mut message, &(path, entry.header(), hasher.finalize().as_slice()))?;
+        }
+
+        Ok(message)
+    }
+
+}
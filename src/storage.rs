@@ -0,0 +1,37 @@
+use std::fs::File;
+use std::io::{Read, Write, Seek, Cursor};
+
+///A backend that an archive can be stored in
+///
+/// Implemented for [`File`], [`crate::volume::Volumes`] and [`Cursor<Vec<u8>>`], so archives can be stored on disk (in one file or
+/// split into volumes) or entirely in memory.
+/// Any other `Read + Write + Seek` type (such as a block device or custom storage) can be used by implementing [`Storage::set_len`].
+pub trait Storage: Read + Write + Seek {
+    ///Truncate or extend the storage to `size` bytes
+    fn set_len(&mut self, size: u64) -> std::io::Result<()>;
+}
+
+impl Storage for File {
+    fn set_len(&mut self, size: u64) -> std::io::Result<()> {
+        File::set_len(self, size)
+    }
+}
+
+impl Storage for Cursor<Vec<u8>> {
+    fn set_len(&mut self, size: u64) -> std::io::Result<()> {
+        self.get_mut().resize(size as usize, 0);
+        Ok(())
+    }
+}
+
+impl<S: Storage + ?Sized> Storage for &mut S {
+    fn set_len(&mut self, size: u64) -> std::io::Result<()> {
+        (**self).set_len(size)
+    }
+}
+
+impl<S: Storage + ?Sized> Storage for Box<S> {
+    fn set_len(&mut self, size: u64) -> std::io::Result<()> {
+        (**self).set_len(size)
+    }
+}
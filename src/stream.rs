@@ -0,0 +1,353 @@
+use std::path::{Path, PathBuf};
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::convert::TryFrom;
+use crate::archive::{MAGIC_NUMBER, ENCRYPTED_MAGIC_NUMBER, STREAMED_TOC_OFFSET, TRAILER_MAGIC_NUMBER};
+use std::collections::HashMap;
+use crate::header::{Metadata, EntryHeader, Content, HEADER_MAGIC_NUMBER};
+use crate::crypto::HASH_SIZE;
+use crate::safepath::SafePathBuf;
+use crate::toc::{TOC, TOC_MAGIC_NUMBER};
+use crate::error::{Result, Error, ErrorKind, TocEntryNotFoundReason};
+
+///Counts the bytes written to a sink or read from a source, since a stream that cannot seek cannot tell us its position
+struct Counting<T> {
+    inner: T,
+    position: u64,
+}
+
+impl<R: Read> Read for Counting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<W: Write> Write for Counting<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+///Creates a new archive in a sink that cannot seek, such as a pipe, socket or stdout
+///
+/// Since the toc offset cannot be written to the start of the archive once the toc position is known, a placeholder is
+/// written instead, and the real toc offset is written to a fixed size trailer after the toc. [`crate::archive::Archive::open`]
+/// reads streamed archives transparently, and any modification to a streamed archive converts it back to the usual layout.
+pub struct StreamBuilder<W: Write> {
+    writer: Counting<W>,
+    toc: TOC,
+}
+
+impl<W: Write> StreamBuilder<W> {
+
+    ///Start a new archive in `writer`, writing the magic number and the toc offset placeholder
+    pub fn new(writer: W) -> Result<Self> {
+
+        let mut writer = Counting {
+            inner: writer,
+            position: 0,
+        };
+
+        bincode::serialize_into(& mut writer, &MAGIC_NUMBER)?;
+
+        bincode::serialize_into(& mut writer, &STREAMED_TOC_OFFSET)?;
+
+        Ok(Self {
+            writer,
+            toc: TOC::new(),
+        })
+    }
+
+    ///Add a new file at `path` to the archive. The path stored in the archive itself is specified by `name`
+    ///
+    /// A symbolic link is stored as a link to its target, rather than as the file it points to
+    pub fn append<P: AsRef<Path>, N: AsRef<Path>>(&mut self, path: P, name: N) -> Result<()> {
+        self.append_file(path.as_ref(), name.as_ref()).map_err(|e| e.with_entry(name))
+    }
+
+    fn append_file(&mut self, path: &Path, name: &Path) -> Result<()> {
+
+        //Check for naming conflicts in the toc
+        if self.toc._table.contains_key(name) {
+
+            return Err(Error::new(ErrorKind::PathConflict(PathBuf::from(name)), format!("Could not append file to TOC with the chosen path ({}), as path already exists in TOC", name.display())));
+
+        }
+
+        //Check the name before writing anything, as data cannot be taken back once it is written
+        let name = SafePathBuf::try_from(name)?;
+
+        let position = self.writer.position;
+
+        //Symbolic links are stored as links, with no data
+        if let Some(meta) = Metadata::symlink(path)? {
+            EntryHeader::new(name.clone(), meta, None, Content::Inline).write_to(& mut self.writer, None)?;
+
+            self.toc._table.insert(name, position as u128);
+
+            return Ok(());
+        }
+
+        //Take the metadata from the same handle the data is read from, so the length in the header is the length written
+        let mut file = OpenOptions::new().read(true).open(path)?;
+
+        let meta: Metadata = file.metadata()?.into();
+
+        let length = meta.len() as u64;
+
+        //Append the metadata
+        EntryHeader::new(name.clone(), meta, None, Content::Inline).write_to(& mut self.writer, None)?;
+
+        //Append the file, which may have changed size since it was opened
+        if std::io::copy(& mut (& mut file).take(length), & mut self.writer)? != length {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "file ended before the length given in the metadata").into());
+        }
+
+        //Add the (name, file_offset) pair to the toc
+        self.toc._table.insert(name, position as u128);
+
+        Ok(())
+    }
+
+    ///Write the toc and trailer, and return the sink
+    pub fn finish(mut self) -> Result<W> {
+
+        let toc_offset = self.writer.position as u128;
+
+        self.toc.write_to(& mut self.writer, None)?;
+
+        //Write the trailer
+        bincode::serialize_into(& mut self.writer, &toc_offset)?;
+
+        bincode::serialize_into(& mut self.writer, &TRAILER_MAGIC_NUMBER)?;
+
+        self.writer.flush()?;
+
+        Ok(self.writer.inner)
+    }
+}
+
+///Reads an archive sequentially from a source that cannot seek, such as a pipe, socket or stdin
+///
+/// Files are read in the order they were written, using the path stored in each header, so the toc is never needed.
+/// This means files that have been removed from the toc with [`crate::archive::Archive::remove`] (but not yet defragmented) will still be read.
+///
+/// A file that shares its data with an earlier file (see [`crate::builder::Builder`]) is read as a [`crate::header::FileType::HardLink`] to the path of
+/// that earlier file, with no data, as the shared data has already been read past. Files stored in chunks (see [`crate::builder::Chunking`])
+/// cannot be read from a stream, and fail with [`ErrorKind::UnsupportedEntry`]. Archives created by [`StreamBuilder`] never share data.
+pub struct StreamReader<R: Read> {
+    reader: Counting<R>,
+
+    //The toc offset, or None if the archive was streamed and the offset is in the trailer
+    toc_offset: Option<u128>,
+
+    //Bytes of the current file that have not been read yet
+    remaining: u64,
+
+    //The path of the file each blob was stored with, so files sharing the blob can link to it
+    blobs: HashMap<[u8; HASH_SIZE], PathBuf>,
+
+    finished: bool,
+}
+
+impl<R: Read> StreamReader<R> {
+
+    ///Start reading an archive from `reader`, reading the magic number and toc offset
+    pub fn new(reader: R) -> Result<Self> {
+
+        let mut reader = Counting {
+            inner: reader,
+            position: 0,
+        };
+
+        let magic_number: u128 = bincode::deserialize_from(& mut reader)?;
+
+        if magic_number == ENCRYPTED_MAGIC_NUMBER {
+            return Err(Error::new(ErrorKind::KeyRequired, String::from("Encrypted archives cannot be read as a stream")));
+        }
+
+        if magic_number != MAGIC_NUMBER {
+            return Err(Error::new(ErrorKind::BadMagicNumber(magic_number), format!("Magic number ({:#x}) does not match, stream is not a tarpdate archive", magic_number)));
+        }
+
+        let toc_offset: u128 = bincode::deserialize_from(& mut reader)?;
+
+        Ok(Self {
+            reader,
+            toc_offset: if toc_offset == STREAMED_TOC_OFFSET { None } else { Some(toc_offset) },
+            remaining: 0,
+            blobs: HashMap::new(),
+            finished: false,
+        })
+    }
+
+    ///Read the next file in the archive, skipping any unread data from the previous file
+    ///
+    /// Returns `Ok(None)` once the toc is reached
+    pub fn next_entry(&mut self) -> Result<Option<StreamEntry<'_, R>>> {
+
+        let offset = self.reader.position;
+
+        self.read_header().map_err(|e| e.with_offset(offset as u128))
+    }
+
+    fn read_header(&mut self) -> Result<Option<StreamEntry<'_, R>>> {
+
+        if self.finished {
+            return Ok(None);
+        }
+
+        //Chunks of chunked files are not files themselves, so they are skipped
+        let header = loop {
+            //Skip the rest of the previous file or chunk
+            let skipped = std::io::copy(&mut (& mut self.reader).take(self.remaining), & mut std::io::sink())?;
+
+            if skipped < self.remaining {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+
+            self.remaining = 0;
+
+            if self.toc_offset == Some(self.reader.position as u128) {
+                self.finished = true;
+                return Ok(None);
+            }
+
+            let start = self.reader.position;
+
+            let magic_number: u64 = bincode::deserialize_from(& mut self.reader)?;
+
+            //The tocs of earlier generations are skipped, as the toc offset shows where the current toc is
+            if let Some(toc_offset) = self.toc_offset.filter(|_| magic_number == TOC_MAGIC_NUMBER) {
+                let limit = (toc_offset as u64).saturating_sub(self.reader.position);
+
+                TOC::read_after_magic_number(& mut self.reader, limit)?;
+
+                continue;
+            }
+
+            //The toc offset of a streamed archive is not known until the trailer, so the toc ends the stream
+            if self.toc_offset.is_none() && magic_number == TOC_MAGIC_NUMBER {
+                self.read_toc_and_trailer(start)?;
+
+                self.finished = true;
+                return Ok(None);
+            }
+
+            if magic_number != HEADER_MAGIC_NUMBER {
+                return Err(Error::new(ErrorKind::BadHeaderMagicNumber(magic_number), format!("Header magic number ({:#x}) does not match", magic_number)));
+            }
+
+            let header = EntryHeader::read_after_magic_number(& mut self.reader)?;
+
+            if let Content::Chunk(_) = header.content {
+                self.remaining = header.data_len() as u64;
+
+                continue;
+            }
+
+            break header;
+        };
+
+        self.remaining = header.data_len() as u64;
+
+        let path = header.path.as_path().to_path_buf();
+
+        let metadata = match header.content {
+            Content::Blob(hash) => {
+                self.blobs.entry(hash).or_insert_with(|| path.clone());
+
+                header.metadata
+            }
+            //The data was stored with an earlier file, and has already been read past
+            Content::Shared(hash) => match self.blobs.get(&hash) {
+                Some(target) => header.metadata.into_hard_link(target),
+                None => return Err(Error::new(ErrorKind::UnsupportedEntry(String::from("shared data")), format!("File ({}) shares its data with a chunk of another file, so it cannot be read from a stream", path.display()))),
+            },
+            Content::Chunked(_) => return Err(Error::new(ErrorKind::UnsupportedEntry(String::from("chunked data")), format!("File ({}) is stored in chunks, so it cannot be read from a stream", path.display()))),
+            _ => header.metadata,
+        };
+
+        Ok(Some(StreamEntry {
+            path,
+            header: metadata,
+            stream: self,
+        }))
+    }
+
+    ///Read the toc of a streamed archive after its magic number, and check that the trailer after it points back at `toc_offset`
+    ///
+    /// Fails with [`crate::error::TocEntryNotFoundReason::BadTrailer`] if the trailer is missing or points elsewhere
+    fn read_toc_and_trailer(& mut self, toc_offset: u64) -> Result<()> {
+
+        //The length of a stream is not known, so the toc is only limited by the data that arrives
+        TOC::read_after_magic_number(& mut self.reader, u64::MAX)?;
+
+        let trailer_offset = self.reader.position;
+
+        let offset: u128 = bincode::deserialize_from(& mut self.reader)?;
+
+        let trailer_magic_number: u128 = bincode::deserialize_from(& mut self.reader)?;
+
+        if trailer_magic_number != TRAILER_MAGIC_NUMBER {
+            return Err(Error::new(ErrorKind::TocEntryNotFound(TocEntryNotFoundReason::BadTrailer(trailer_magic_number)), format!("Trailer magic number ({:#x}) does not match, streamed archive may be incomplete", trailer_magic_number)).with_offset(trailer_offset as u128 + 16));
+        }
+
+        if offset != toc_offset as u128 {
+            return Err(Error::new(ErrorKind::TocEntryNotFound(TocEntryNotFoundReason::BadTrailer(trailer_magic_number)), format!("Trailer toc offset ({}) does not match the position of the toc ({})", offset, toc_offset)).with_offset(trailer_offset as u128));
+        }
+
+        Ok(())
+    }
+}
+
+///A file read sequentially from an archive by [`StreamReader`]
+///
+/// The file data must be read before the next file, as it is read straight from the underlying stream.
+pub struct StreamEntry<'a, R: Read> {
+    path: PathBuf,
+    header: Metadata,
+    stream: & 'a mut StreamReader<R>,
+}
+
+impl<'a, R: Read> StreamEntry<'a, R> {
+    ///Get the path of the file within the archive
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    ///Get the metadata for the archived file
+    pub fn header(&self) -> &Metadata {
+        &self.header
+    }
+}
+
+///Used to access the archive file data
+impl<'a, R: Read> Read for StreamEntry<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+
+        let length = self.stream.remaining.min(buf.len() as u64) as usize;
+
+        if length == 0 {
+            return Ok(0);
+        }
+
+        let read = self.stream.reader.read(& mut buf[..length])?;
+
+        if read == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "stream ended in the middle of a file"));
+        }
+
+        self.stream.remaining -= read as u64;
+
+        Ok(read)
+    }
+}
@@ -0,0 +1,128 @@
+use std::fmt::{Debug, Formatter};
+use std::path::{Path, PathBuf};
+use glob::{Pattern, MatchOptions};
+use crate::archive::Archive;
+use std::collections::{BTreeSet, HashSet};
+use crate::header::{Metadata, FileType};
+use crate::storage::Storage;
+use crate::error::{Result, Error, ErrorKind};
+
+///Selects the files copied by [`Archive::extract_subset`]
+pub struct Filter {
+    kind: FilterKind,
+}
+
+///Decides whether a file is selected, given its path and metadata
+type Predicate = Box<dyn Fn(&Path, &Metadata) -> bool>;
+
+enum FilterKind {
+    Glob(Pattern),
+    Prefix(PathBuf),
+    Predicate(Predicate),
+}
+
+impl Filter {
+    ///Select files whose path matches the glob `pattern`. `*` and `?` do not match `/`, but `**` matches any number of directories
+    ///
+    /// Fails with [`ErrorKind::InvalidPattern`] if the pattern cannot be parsed
+    pub fn glob(pattern: &str) -> Result<Self> {
+        let pattern = Pattern::new(pattern).map_err(|e| Error::new(ErrorKind::InvalidPattern(String::from(pattern)), format!("Could not parse glob pattern ({}): {}", pattern, e)))?;
+
+        Ok(Self {
+            kind: FilterKind::Glob(pattern),
+        })
+    }
+
+    ///Select the file or directory at `prefix`, and every file below it
+    pub fn prefix<P: AsRef<Path>>(prefix: P) -> Self {
+        Self {
+            kind: FilterKind::Prefix(prefix.as_ref().to_path_buf()),
+        }
+    }
+
+    ///Select files for which `predicate` returns true, given the path and metadata of each file
+    pub fn predicate<F: Fn(&Path, &Metadata) -> bool + 'static>(predicate: F) -> Self {
+        Self {
+            kind: FilterKind::Predicate(Box::new(predicate)),
+        }
+    }
+
+    ///Returns true if the file at `path`, with `metadata`, is selected
+    pub fn matches(&self, path: &Path, metadata: &Metadata) -> bool {
+        match &self.kind {
+            FilterKind::Glob(pattern) => pattern.matches_path_with(path, MatchOptions {
+                require_literal_separator: true,
+                ..MatchOptions::new()
+            }),
+            FilterKind::Prefix(prefix) => path.starts_with(prefix),
+            FilterKind::Predicate(predicate) => predicate(path, metadata),
+        }
+    }
+}
+
+impl Debug for Filter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            FilterKind::Glob(pattern) => f.debug_tuple("Glob").field(&pattern.as_str()).finish(),
+            FilterKind::Prefix(prefix) => f.debug_tuple("Prefix").field(prefix).finish(),
+            FilterKind::Predicate(_) => f.debug_tuple("Predicate").finish_non_exhaustive(),
+        }
+    }
+}
+
+impl<S: Storage> Archive<S> {
+
+    ///Copy the files selected by `filter` into `destination`, usually a new archive, and return their paths in order
+    ///
+    /// Files are copied with [`crate::builder::Builder::append_entry`], so if both archives are plain or use the same data key, the
+    /// stored data is copied as it is, without extracting and adding the files again. Only the data the selected files use is
+    /// copied, once, so a new archive is as compact as if it had been defragmented. The files are added as a single generation.
+    ///
+    /// The target of a selected hard link is copied too, even if it is not selected, so the links in `destination` point at files it contains.
+    ///
+    /// If copying a file fails, the files copied before it are kept in `destination`
+    pub fn extract_subset<T: Storage>(&self, filter: &Filter, destination: & mut Archive<T>) -> Result<Vec<PathBuf>> {
+        self.extract_entries(filter, destination).map_err(|e| self.error_context(e))
+    }
+
+    fn extract_entries<T: Storage>(&self, filter: &Filter, destination: & mut Archive<T>) -> Result<Vec<PathBuf>> {
+
+        let mut entries = self.iter().filter(|entry| entry.as_ref().map_or(true, |entry| filter.matches(entry.path(), entry.header()))).collect::<Result<Vec<_>>>()?;
+
+        //A hard link needs its target, so targets that were not selected are copied as well
+        let selected = entries.iter().map(|entry| entry.path().to_path_buf()).collect::<HashSet<_>>();
+
+        let targets = entries.iter()
+            .filter(|entry| entry.header().file_type() == FileType::HardLink)
+            .filter_map(|entry| entry.header().link_target())
+            .filter(|target| !selected.contains(*target))
+            .map(Path::to_path_buf)
+            .collect::<BTreeSet<_>>();
+
+        for target in targets {
+            if let Some(entry) = self.get(&target)? {
+                entries.push(entry);
+            }
+        }
+
+        entries.sort_by(|a, b| a.path().cmp(b.path()));
+
+        let mut copied = Vec::new();
+
+        let mut builder = destination.builder()?;
+
+        let result = entries.iter().try_for_each(|entry| {
+            builder.append_entry(entry)?;
+
+            copied.push(entry.path().to_path_buf());
+
+            Ok(())
+        });
+
+        //The toc is always written, so the destination matches the files that were copied
+        builder.finalise()?;
+
+        result.map(|()| copied)
+    }
+
+}
@@ -0,0 +1,393 @@
+use std::io::{Read, Write};
+use std::path::Path;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::archive::Archive;
+use crate::builder::Builder;
+use crate::entry::Entry;
+use crate::convert::{ImportReport, ExportOptions};
+use crate::header::{Metadata, FileType};
+use crate::storage::Storage;
+use crate::error::{Result, Error, ErrorKind};
+
+impl<S: Storage> Archive<S> {
+
+    ///Import every entry of a tar archive (ustar, GNU or PAX) read from `reader`
+    ///
+    /// Regular files, directories, symbolic links and hard links are imported with their mode, owner and modification time.
+    /// Entries with unsafe paths, paths that already exist in the archive and unsupported entry types (such as devices) are
+    /// skipped and listed in the returned report. If the tar contains the same path more than once, the last one is kept.
+    ///
+    /// The reader must not be compressed, wrap it in a decoder first if it is.
+    pub fn import_tar<R: Read>(& mut self, reader: R) -> Result<ImportReport> {
+
+        let mut report = ImportReport::default();
+
+        let mut builder = self.builder()?;
+
+        let result = import_tar_entries(& mut builder, reader, & mut report);
+
+        //Always replace the toc, even if the tar could not be read in full
+        builder.finalise()?;
+
+        result.map_err(|e| self.error_context(e))?;
+
+        Ok(report)
+    }
+
+    ///Export every file in the archive as a POSIX (PAX) tar written to `writer`, and return the writer
+    ///
+    /// Files are written in the order they are stored in the archive. PAX extended headers are used for anything the
+    /// ustar header cannot hold, such as paths and link targets longer than 100 bytes, large sizes, large owner ids and
+    /// times with sub-second precision. Only the writer is written to, so it can be a pipe or stdout.
+    ///
+    /// The tar is not compressed, wrap the writer in an encoder first if it should be.
+    pub fn export_tar<W: Write>(&self, writer: W, options: &ExportOptions) -> Result<W> {
+        self.export_tar_entries(writer, options).map_err(|e| self.error_context(e))
+    }
+
+    fn export_tar_entries<W: Write>(&self, writer: W, options: &ExportOptions) -> Result<W> {
+
+        let mut tar = tar::Builder::new(writer);
+
+        //Keep the order the files were added in, so hard links come after their targets
+        let mut entries = self.table()._table.iter().collect::<Vec<_>>();
+
+        entries.sort_by_key(|(_, offset)| **offset);
+
+        for (path, offset) in entries {
+            let mut entry = Entry::new(path.as_path(), *offset, self)?;
+
+            let header = entry.header().clone();
+
+            write_tar_entry(& mut tar, path.as_path(), &header, & mut entry, options).map_err(|e| e.with_entry(path.as_path()))?;
+        }
+
+        Ok(tar.into_inner()?)
+    }
+
+}
+
+fn import_tar_entries<S: Storage, R: Read>(builder: & mut Builder<'_, S>, reader: R, report: & mut ImportReport) -> Result<()> {
+
+    //Paths that were added during this import, which are replaced by later entries with the same path
+    let mut imported = HashSet::new();
+
+    let mut tar = tar::Archive::new(reader);
+
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+
+        let path = entry.path()?.into_owned();
+
+        let metadata = match tar_metadata(& mut entry) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                report.skipped.push((path, e));
+                continue;
+            }
+        };
+
+        let replacing = imported.contains(&path);
+
+        if replacing {
+            builder.replace(&path);
+        }
+
+        match builder.append_data(metadata, & mut entry, &path) {
+            Ok(()) => {
+                if !replacing {
+                    imported.insert(path.clone());
+                    report.imported.push(path);
+                }
+            }
+            Err(e) => match e.kind() {
+                ErrorKind::PathConflict(_) | ErrorKind::UnsafePath(_) => report.skipped.push((path, e)),
+                _ => return Err(e),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+///Convert the header of a tar entry into metadata
+fn tar_metadata<R: Read>(entry: & mut tar::Entry<'_, R>) -> Result<Metadata> {
+
+    let header = entry.header();
+
+    let file_type = header.entry_type();
+
+    let metadata = if file_type.is_file() {
+        Metadata::new(FileType::File, entry.size() as u128)
+    } else if file_type.is_dir() {
+        Metadata::new(FileType::Dir, 0)
+    } else if file_type.is_symlink() || file_type.is_hard_link() {
+        let target = entry.link_name()?.ok_or_else(|| Error::new(ErrorKind::UnsupportedEntry(format!("{:?}", file_type)), String::from("Link has no target")))?;
+
+        Metadata::new(if file_type.is_symlink() { FileType::SystemLink } else { FileType::HardLink }, 0).with_link_target(target)
+    } else {
+        return Err(Error::new(ErrorKind::UnsupportedEntry(format!("{:?}", file_type)), format!("Tar entry type ({:?}) is not supported", file_type)));
+    };
+
+    let header = entry.header();
+
+    let mut metadata = metadata;
+
+    if let Ok(mode) = header.mode() {
+        metadata = metadata.with_mode(mode);
+    }
+
+    if let (Ok(uid), Ok(gid)) = (header.uid(), header.gid()) {
+        metadata = metadata.with_owner(uid, gid);
+    }
+
+    if let Ok(mtime) = header.mtime() {
+        metadata = metadata.with_modified(UNIX_EPOCH + Duration::from_secs(mtime));
+    }
+
+    //PAX headers can store times with sub-second precision
+    if let Some(extensions) = entry.pax_extensions()? {
+        for extension in extensions {
+            let extension = extension?;
+
+            match (extension.key(), extension.value().ok().and_then(parse_pax_time)) {
+                (Ok("mtime"), Some(time)) => metadata = metadata.with_modified(time),
+                (Ok("atime"), Some(time)) => metadata = metadata.with_accessed(time),
+                _ => {}
+            }
+        }
+    }
+
+    Ok(metadata)
+}
+
+///Write a single file to a tar, preceded by a PAX extended header if the ustar header cannot hold all of the metadata
+fn write_tar_entry<W: Write, R: Read>(tar: & mut tar::Builder<W>, path: &Path, metadata: &Metadata, data: R, options: &ExportOptions) -> Result<()> {
+
+    let path = options.export_path(path);
+
+    let mut header = tar::Header::new_ustar();
+
+    //PAX records, in the order they are written
+    let mut extensions: Vec<(&str, Vec<u8>)> = Vec::new();
+
+    let (entry_type, mode) = match metadata.file_type() {
+        FileType::Dir => (tar::EntryType::Directory, 0o755),
+        FileType::File => (tar::EntryType::Regular, 0o644),
+        FileType::SystemLink => (tar::EntryType::Symlink, 0o777),
+        FileType::HardLink => (tar::EntryType::Link, 0o644),
+    };
+
+    header.set_entry_type(entry_type);
+
+    let mode = match metadata.mode() {
+        Some(mode) => mode,
+        None if metadata.readonly() => mode & !0o222,
+        None => mode,
+    };
+
+    header.set_mode(mode & 0o7777);
+
+    if header.set_path(&path).is_err() {
+        set_truncated(& mut header.as_old_mut().name, &path);
+        extensions.push(("path", path_bytes(&path).into_owned()));
+    }
+
+    if let Some(target) = metadata.link_target() {
+        //Hard links point at another path in the archive, which has been moved below the prefix too
+        let target = match metadata.file_type() {
+            FileType::HardLink => options.export_path(target),
+            _ => target.to_path_buf(),
+        };
+
+        if header.set_link_name(&target).is_err() {
+            set_truncated(& mut header.as_old_mut().linkname, &target);
+            extensions.push(("linkpath", path_bytes(&target).into_owned()));
+        }
+    }
+
+    let size = match metadata.file_type() {
+        FileType::File => u64::try_from(metadata.len()).map_err(|_| Error::new(ErrorKind::UnsupportedEntry(String::from("File")), format!("File size ({}) is too large for a tar", metadata.len())))?,
+        _ => 0,
+    };
+
+    header.set_size(size);
+
+    if size > USTAR_MAX_SIZE {
+        extensions.push(("size", size.to_string().into_bytes()));
+    }
+
+    if let (true, Some(uid), Some(gid)) = (options.owner(), metadata.uid(), metadata.gid()) {
+        header.set_uid(uid);
+        header.set_gid(gid);
+
+        if uid > USTAR_MAX_ID {
+            extensions.push(("uid", uid.to_string().into_bytes()));
+        }
+
+        if gid > USTAR_MAX_ID {
+            extensions.push(("gid", gid.to_string().into_bytes()));
+        }
+    } else {
+        header.set_uid(0);
+        header.set_gid(0);
+    }
+
+    let mut mtime = 0;
+
+    if let Some(modified) = metadata.modified() {
+        let (seconds, precise) = pax_time(modified);
+
+        mtime = seconds;
+
+        if let Some(precise) = precise {
+            extensions.push(("mtime", precise.into_bytes()));
+        }
+    }
+
+    header.set_mtime(mtime);
+
+    //Access times only fit in a PAX header, so always write them there
+    if let Some(accessed) = metadata.accessed() {
+        let (seconds, precise) = pax_time(accessed);
+
+        extensions.push(("atime", precise.unwrap_or_else(|| seconds.to_string()).into_bytes()));
+    }
+
+    if !extensions.is_empty() {
+        let records = extensions.iter().flat_map(|(key, value)| pax_record(key, value)).collect::<Vec<u8>>();
+
+        let mut pax = tar::Header::new_ustar();
+
+        pax.set_entry_type(tar::EntryType::XHeader);
+
+        let name = Path::new("PaxHeaders").join(path.file_name().unwrap_or_default());
+
+        if pax.set_path(&name).is_err() {
+            set_truncated(& mut pax.as_old_mut().name, &name);
+        }
+
+        pax.set_mode(0o644);
+        pax.set_mtime(mtime);
+        pax.set_size(records.len() as u64);
+        pax.set_cksum();
+
+        tar.append(&pax, records.as_slice())?;
+    }
+
+    header.set_cksum();
+
+    tar.append(&header, data.take(size))?;
+
+    Ok(())
+}
+
+///The largest size that fits in the 11 octal digits of a ustar header
+const USTAR_MAX_SIZE: u64 = 0o77777777777;
+
+///The largest owner id that fits in the 7 octal digits of a ustar header
+const USTAR_MAX_ID: u64 = 0o7777777;
+
+///Copy as much of `path` as fits into a ustar name field, for readers that ignore the PAX header
+fn set_truncated(field: & mut [u8], path: &Path) {
+    let bytes = path_bytes(path);
+
+    let length = bytes.len().min(field.len());
+
+    field.fill(0);
+    field[..length].copy_from_slice(&bytes[..length]);
+}
+
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Cow::Borrowed(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    match path.to_string_lossy() {
+        Cow::Borrowed(path) => Cow::Borrowed(path.as_bytes()),
+        Cow::Owned(path) => Cow::Owned(path.into_bytes()),
+    }
+}
+
+///Encode a PAX record (such as `30 mtime=1642502400.25\n`), which starts with its own length in bytes
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+
+    let base = key.len() + value.len() + 3;
+
+    //Adding the length can make the length longer, so repeat until it settles
+    let mut length = base;
+
+    loop {
+        let next = base + length.to_string().len();
+
+        if next == length {
+            break;
+        }
+
+        length = next;
+    }
+
+    let mut record = format!("{} {}=", length, key).into_bytes();
+
+    record.extend_from_slice(value);
+    record.push(b'\n');
+
+    record
+}
+
+///Split a time into the whole seconds for a ustar header, and a PAX time if the ustar header cannot hold it exactly
+fn pax_time(time: SystemTime) -> (u64, Option<String>) {
+    let (negative, duration) = match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => (false, duration),
+        Err(e) => (true, e.duration()),
+    };
+
+    if !negative && duration.subsec_nanos() == 0 {
+        return (duration.as_secs(), None);
+    }
+
+    let mut precise = format!("{}{}.{:09}", if negative { "-" } else { "" }, duration.as_secs(), duration.subsec_nanos());
+
+    while precise.ends_with('0') {
+        precise.pop();
+    }
+
+    if precise.ends_with('.') {
+        precise.pop();
+    }
+
+    //The ustar header cannot hold times before the epoch
+    (if negative { 0 } else { duration.as_secs() }, Some(precise))
+}
+
+///Parse a PAX time, which is a decimal number of seconds since the epoch (such as `1642502400.25`)
+fn parse_pax_time(value: &str) -> Option<SystemTime> {
+    let (seconds, fraction) = match value.split_once('.') {
+        Some((seconds, fraction)) => (seconds, fraction),
+        None => (value, ""),
+    };
+
+    let negative = seconds.starts_with('-');
+
+    let seconds: u64 = seconds.trim_start_matches('-').parse().ok()?;
+
+    //Only nanosecond precision is kept
+    let fraction = &fraction[..fraction.len().min(9)];
+
+    let nanos = if fraction.is_empty() { 0 } else { fraction.parse::<u32>().ok()? * 10u32.pow(9 - fraction.len() as u32) };
+
+    let duration = Duration::new(seconds, nanos);
+
+    if negative {
+        UNIX_EPOCH.checked_sub(duration)
+    } else {
+        UNIX_EPOCH.checked_add(duration)
+    }
+}
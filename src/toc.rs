@@ -1,16 +1,123 @@
 use std::collections::HashMap;
+use std::io::{Read, Write};
 use serde::{Serialize, Deserialize};
+use bincode::Options;
 use crate::safepath::SafePathBuf;
+use crate::signature::StoredSignature;
+use crate::generation::Generation;
+use crate::crypto::{DataKey, Sealed, HASH_SIZE, TOC_AAD};
+use crate::error::{Result, Error, ErrorKind, TocEntryNotFoundReason};
 
-#[derive(Serialize, Deserialize, Debug)]
+///Written at the start of every toc that is not sealed, so the tocs of earlier generations can be told apart from headers when reading an archive sequentially
+pub (in crate) const TOC_MAGIC_NUMBER: u64 = 0x4c7b2e90d16a38f5;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub struct TOC {
    pub (in crate) _table: HashMap<SafePathBuf, u128>,
+   pub (in crate) blobs: HashMap<[u8; HASH_SIZE], Blob>,
+   pub (in crate) signature: Option<StoredSignature>,
+   pub (in crate) generation: Generation,
+
+   ///The offset of the toc of the previous generation, if it is still in the archive
+   pub (in crate) previous: Option<u128>,
+
+   ///The size of every volume but the last, if the archive is split into volumes, so the volume and offset of every entry follow from its offset
+   pub (in crate) volume_size: Option<u64>,
+
+   ///The volume and offset within that volume of the header of each entry, if the archive is split into volumes
+   pub (in crate) locations: HashMap<SafePathBuf, (u64, u128)>,
 }
 
 impl TOC {
    pub fn new() -> Self {
       TOC {
          _table: HashMap::new(),
+         blobs: HashMap::new(),
+         signature: None,
+         generation: Generation::first(),
+         previous: None,
+         volume_size: None,
+         locations: HashMap::new(),
+      }
+   }
+
+   ///The volume (counting from 0) that `offset` is in, and the offset within that volume
+   pub (in crate) fn locate(&self, offset: u128) -> (u64, u128) {
+      match self.volume_size {
+         Some(size) => ((offset / size as u128) as u64, offset % size as u128),
+         None => (0, offset),
+      }
+   }
+
+   ///Record the volume and offset of every entry, before the toc is written
+   pub (in crate) fn record_locations(& mut self) {
+      self.locations = match self.volume_size {
+         Some(_) => self._table.iter().map(|(path, offset)| (path.clone(), self.locate(*offset))).collect(),
+         None => HashMap::new(),
+      };
+   }
+
+   ///Write the toc magic number and toc
+   ///
+   /// If the archive encrypts its metadata, `key` is the archive data key and the toc is sealed instead, with no magic number
+   pub (in crate) fn write_to<W: Write>(&self, mut writer: W, key: Option<&DataKey>) -> Result<()> {
+      if let Some(key) = key {
+         let sealed = key.seal(TOC_AAD, &bincode::serialize(self)?)?;
+
+         bincode::serialize_into(& mut writer, &sealed)?;
+
+         return Ok(());
+      }
+
+      bincode::serialize_into(& mut writer, &TOC_MAGIC_NUMBER)?;
+      bincode::serialize_into(& mut writer, self)?;
+      Ok(())
+   }
+
+   ///Read a toc of at most `limit` bytes, opening it with `key` if it is sealed
+   ///
+   /// Fails with [`ErrorKind::TocEntryNotFound`] if there is no toc at the current position, or [`ErrorKind::AuthenticationFailed`]
+   /// if a sealed toc cannot be opened
+   pub (in crate) fn read_from<R: Read>(mut reader: R, key: Option<&DataKey>, limit: u64) -> Result<Self> {
+      if let Some(key) = key {
+         let sealed: Sealed = options(limit).deserialize_from(& mut reader).map_err(not_deserialised)?;
+
+         return options(limit).deserialize(&key.open(TOC_AAD, &sealed)?).map_err(not_deserialised);
+      }
+
+      let magic_number: u64 = bincode::deserialize_from(& mut reader).map_err(not_deserialised)?;
+
+      if magic_number != TOC_MAGIC_NUMBER {
+         return Err(Error::new(ErrorKind::TocEntryNotFound(TocEntryNotFoundReason::BadTocMagicNumber(magic_number)), format!("Toc magic number ({:#x}) does not match", magic_number)));
       }
+
+      Self::read_after_magic_number(reader, limit.saturating_sub(8))
+   }
+
+   ///Read the rest of a toc that is not sealed, after the magic number has already been read and checked
+   pub (in crate) fn read_after_magic_number<R: Read>(reader: R, limit: u64) -> Result<Self> {
+      options(limit).deserialize_from(reader).map_err(not_deserialised)
    }
-}
\ No newline at end of file
+}
+
+///Bound the toc by the bytes left in the archive, so a corrupt length cannot cause a huge allocation
+fn options(limit: u64) -> impl Options {
+   bincode::DefaultOptions::new().with_fixint_encoding().allow_trailing_bytes().with_limit(limit)
+}
+
+fn not_deserialised(e: bincode::Error) -> Error {
+   let error = format!("Could not deserialise toc ({})", e);
+
+   Error::new(ErrorKind::TocEntryNotFound(TocEntryNotFoundReason::CouldNotDeserialiseToc(e)), error)
+}
+
+///Data shared by every file (or chunk of a chunked file) with the same content hash
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub (in crate) struct Blob {
+   ///The offset of the header the data follows, which is either a file or a chunk
+   pub (in crate) header: u128,
+
+   ///The number of times files in the toc use the data, including the file the data follows
+   pub (in crate) refs: u64,
+}
@@ -0,0 +1,366 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use crate::archive::Archive;
+use crate::storage::Storage;
+use crate::error::{Result, Error, ErrorKind};
+
+///An archive stored in one or more files on disk, which are read and written as if they were a single file
+///
+/// The first volume is stored at the archive path, and the volumes after it at the same path followed by `.001`, `.002` and so on.
+/// Once a volume size is set, anything written past the end of a volume goes into the next volume, so entries (and the toc) can span
+/// volumes. Every volume except the last is exactly the volume size, so the volume and offset of any position in the archive follow
+/// from the volume size, which is recorded in the toc along with the volume and offset of every entry, see [`Archive::location`].
+///
+/// An archive without a volume size, or that has not yet grown past it, is a single file.
+#[derive(Debug)]
+pub struct Volumes {
+    path: PathBuf,
+    files: Vec<File>,
+    volume_size: Option<u64>,
+    position: u64,
+}
+
+///The path of volume `index` of the archive at `path`, counting from 0
+pub fn volume_path<P: AsRef<Path>>(path: P, index: u64) -> PathBuf {
+    let mut name = path.as_ref().as_os_str().to_owned();
+
+    if index > 0 {
+        name.push(format!(".{:03}", index));
+    }
+
+    PathBuf::from(name)
+}
+
+impl Volumes {
+    ///Create an empty archive file at `path`, deleting any volumes left after it by an earlier archive
+    pub fn create<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path)?;
+
+        let mut volumes = Volumes {
+            path: path.as_ref().to_path_buf(),
+            files: vec![file],
+            volume_size: None,
+            position: 0,
+        };
+
+        volumes.remove_from(1)?;
+
+        Ok(volumes)
+    }
+
+    ///Open the archive file at `path`, and every volume after it
+    ///
+    /// The volumes are opened for reading and writing if possible, otherwise they are opened read only. If there is more than one
+    /// volume, the volume size is the size of the first volume.
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+
+        let mut files = vec![open_file(path.as_ref())?];
+
+        loop {
+            match open_file(&volume_path(&path, files.len() as u64)) {
+                Ok(file) => files.push(file),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let volume_size = match files.len() {
+            1 => None,
+            _ => Some(files[0].metadata()?.len()),
+        };
+
+        Ok(Volumes {
+            path: path.as_ref().to_path_buf(),
+            files,
+            volume_size,
+            position: 0,
+        })
+    }
+
+    ///The path of the first volume
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    ///The size of every volume except the last, if a volume size has been set
+    pub fn volume_size(&self) -> Option<u64> {
+        self.volume_size
+    }
+
+    ///The number of volumes, which is at least 1
+    pub fn volume_count(&self) -> usize {
+        self.files.len()
+    }
+
+    ///Set the volume size, moving anything past the end of the first volume into new volumes
+    ///
+    /// Fails with [`ErrorKind::InvalidVolumeSize`] if `volume_size` is zero, or there is already more than one volume of a different size
+    pub (in crate) fn split(& mut self, volume_size: u64) -> Result<()> {
+
+        if self.volume_size == Some(volume_size) {
+            return Ok(());
+        }
+
+        if volume_size == 0 {
+            return Err(Error::new(ErrorKind::InvalidVolumeSize(volume_size), String::from("Volume size must be greater than zero")));
+        }
+
+        if self.files.len() > 1 {
+            return Err(Error::new(ErrorKind::InvalidVolumeSize(volume_size), format!("Could not change volume size to {} bytes, as archive is already split into {} volumes", volume_size, self.files.len())));
+        }
+
+        let length = self.files[0].metadata()?.len();
+
+        self.volume_size = Some(volume_size);
+
+        if length > volume_size {
+            //Copy the end of the first volume into the volumes after it, then cut the first volume down to size
+            let mut first = self.files[0].try_clone()?;
+
+            first.seek(SeekFrom::Start(volume_size))?;
+
+            self.seek(SeekFrom::Start(volume_size))?;
+
+            std::io::copy(& mut first.take(length - volume_size), self)?;
+
+            self.files[0].set_len(volume_size)?;
+        }
+
+        Ok(())
+    }
+
+    ///The volume that `position` is in, and the offset within that volume
+    fn locate(&self, position: u64) -> (usize, u64) {
+        match self.volume_size {
+            Some(size) => ((position / size) as usize, position % size),
+            None => (0, position),
+        }
+    }
+
+    ///The number of bytes that can be read or written at `offset` within a volume, up to `len`
+    fn limit(&self, offset: u64, len: usize) -> usize {
+        match self.volume_size {
+            Some(size) => (size - offset).min(len as u64) as usize,
+            None => len,
+        }
+    }
+
+    ///The total size of every volume
+    pub (in crate) fn len(&self) -> std::io::Result<u64> {
+
+        let last = self.files[self.files.len() - 1].metadata()?.len();
+
+        Ok(self.volume_size.map_or(0, |size| size * (self.files.len() as u64 - 1)) + last)
+    }
+
+    ///Create the next volume, filling the current last volume up to the volume size first
+    fn add_volume(& mut self) -> std::io::Result<()> {
+
+        if let Some(size) = self.volume_size {
+            let last = &self.files[self.files.len() - 1];
+
+            if last.metadata()?.len() < size {
+                last.set_len(size)?;
+            }
+        }
+
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(volume_path(&self.path, self.files.len() as u64))?;
+
+        self.files.push(file);
+
+        Ok(())
+    }
+
+    ///Delete the volumes from `index` on, stopping at the first that does not exist
+    fn remove_from(& mut self, index: usize) -> std::io::Result<()> {
+
+        self.files.truncate(index);
+
+        let mut index = index as u64;
+
+        loop {
+            match std::fs::remove_file(volume_path(&self.path, index)) {
+                Ok(()) => index += 1,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+///Open a volume for reading and writing if possible, otherwise read only
+fn open_file(path: &Path) -> std::io::Result<File> {
+    match OpenOptions::new().read(true).write(true).open(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => OpenOptions::new().read(true).open(path),
+        result => result,
+    }
+}
+
+impl Read for Volumes {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+
+        let (index, offset) = self.locate(self.position);
+
+        let limit = self.limit(offset, buf.len());
+
+        let file = match self.files.get_mut(index) {
+            Some(file) => file,
+            None => return Ok(0),
+        };
+
+        file.seek(SeekFrom::Start(offset))?;
+
+        let read = file.read(& mut buf[..limit])?;
+
+        self.position += read as u64;
+
+        Ok(read)
+    }
+}
+
+impl Write for Volumes {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+
+        let (index, offset) = self.locate(self.position);
+
+        while self.files.len() <= index {
+            self.add_volume()?;
+        }
+
+        let limit = self.limit(offset, buf.len());
+
+        let file = & mut self.files[index];
+
+        file.seek(SeekFrom::Start(offset))?;
+
+        let written = file.write(&buf[..limit])?;
+
+        self.position += written as u64;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.files.iter_mut().try_for_each(File::flush)
+    }
+}
+
+impl Seek for Volumes {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.len()?.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        self.position = new_position.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position"))?;
+
+        Ok(self.position)
+    }
+}
+
+impl Storage for Volumes {
+    ///Truncate or extend the archive to `size` bytes, deleting or creating volumes so that every volume except the last is full
+    fn set_len(&mut self, size: u64) -> std::io::Result<()> {
+
+        let volume_size = match self.volume_size {
+            Some(volume_size) => volume_size,
+            None => return self.files[0].set_len(size),
+        };
+
+        //The first volume is kept even if the archive is empty
+        let count = size.div_ceil(volume_size).max(1) as usize;
+
+        if count < self.files.len() {
+            self.remove_from(count)?;
+        }
+
+        while self.files.len() < count {
+            self.add_volume()?;
+        }
+
+        self.files[count - 1].set_len(size - volume_size * (count as u64 - 1))
+    }
+}
+
+impl Archive<Volumes> {
+
+    ///Check the volumes that were found against the volume size recorded in the toc, after opening the archive
+    ///
+    /// Nothing is written, so opening an archive never changes it. Fails with [`ErrorKind::VolumeSizeMismatch`] if there is more
+    /// than one volume and the first is not the recorded size, or there is one volume that is larger than the recorded size
+    pub (in crate) fn check_volumes(& mut self) -> Result<()> {
+
+        let recorded = self.toc.volume_size;
+
+        let storage = self.storage_mut();
+
+        match (storage.volume_size(), recorded) {
+            (Some(size), Some(recorded)) if size != recorded => {
+                Err(Error::new(ErrorKind::VolumeSizeMismatch(size, Some(recorded)), format!("First volume ({} bytes) does not match the volume size recorded in the toc ({} bytes)", size, recorded)))
+            }
+            //Split by a builder that was not finalised, so the size of the first volume is the volume size
+            (Some(size), None) => {
+                self.toc.volume_size = Some(size);
+
+                Ok(())
+            }
+            //Not yet larger than one volume, so later writes go into new volumes once the first is full
+            (None, Some(recorded)) => {
+                let length = storage.len()?;
+
+                if length > recorded {
+                    return Err(Error::new(ErrorKind::VolumeSizeMismatch(length, Some(recorded)), format!("Archive is a single file of {} bytes, which is larger than the volume size recorded in the toc ({} bytes)", length, recorded)));
+                }
+
+                storage.volume_size = Some(recorded);
+
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    ///Record the size of the volumes that were found in the toc, after the toc has been rebuilt by [`Archive::repair`]
+    pub (in crate) fn recover_volumes(& mut self) -> Result<()> {
+
+        let found = self.storage_mut().volume_size();
+
+        if found.is_none() || found == self.toc.volume_size {
+            return Ok(());
+        }
+
+        self.toc.volume_size = found;
+
+        self.rewrite_toc()
+    }
+
+}
+
+impl<S: Storage> Archive<S> {
+
+    ///The size of every volume except the last, if the archive is split into volumes, see [`crate::builder::Builder::with_volume_size`]
+    pub fn volume_size(&self) -> Option<u64> {
+        self.toc.volume_size
+    }
+
+    ///The volume (counting from 0) that `offset` is in, and the offset within that volume
+    ///
+    /// Used with [`crate::handle::EntryHandle::header_offset`] or [`crate::handle::EntryHandle::file_offset`], this gives where an
+    /// entry is stored. An entry whose data is longer than the space left in its volume continues at the start of the next volume.
+    pub fn locate(&self, offset: u128) -> (u64, u128) {
+        self.toc.locate(offset)
+    }
+
+    ///The volume (counting from 0) that the header of the entry at `path` is in, and its offset within that volume, as recorded in the toc
+    ///
+    /// Returns `None` if no entry exists with the given path. Entries that have been added since the toc was last written are located from their offset
+    pub fn location<P: AsRef<Path>>(&self, path: P) -> Option<(u64, u128)> {
+        let offset = self.toc._table.get(path.as_ref())?;
+
+        Some(self.toc.locations.get(path.as_ref()).copied().unwrap_or_else(|| self.locate(*offset)))
+    }
+
+}
@@ -0,0 +1,306 @@
+use std::io::{Read, Write, Seek};
+use std::path::{Path, PathBuf};
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use zip::{ZipArchive, ZipWriter, CompressionMethod, DateTime};
+use zip::write::FullFileOptions;
+use zip::extra_fields::ExtraField;
+use zip::result::ZipError;
+use crate::archive::Archive;
+use crate::builder::Builder;
+use crate::entry::Entry;
+use crate::convert::{ImportReport, ExportOptions};
+use crate::header::{Metadata, FileType};
+use crate::storage::Storage;
+use crate::error::{Result, Error, ErrorKind};
+
+///Header id of the extended timestamp extra field, which stores times as seconds since the unix epoch
+const EXTENDED_TIMESTAMP: u16 = 0x5455;
+
+impl<S: Storage> Archive<S> {
+
+    ///Import every member of a ZIP archive read from `reader`
+    ///
+    /// Stored and deflated members are decompressed, as tarpdate archives do not compress file data. Directories and
+    /// symbolic links are imported along with the unix mode (from the external attributes) and the modification and
+    /// access times. Times are taken from the extended timestamp field if there is one, otherwise the MS-DOS time is
+    /// read as UTC. ZIP64 archives are supported.
+    ///
+    /// Members with unsafe paths, paths that already exist in the archive, encryption or unsupported compression methods
+    /// are skipped and listed in the returned report. If the ZIP contains the same path more than once, the last one is kept.
+    ///
+    /// The central directory is at the end of a ZIP, so the reader must be able to seek.
+    pub fn import_zip<R: Read + Seek>(& mut self, reader: R) -> Result<ImportReport> {
+
+        let mut zip = ZipArchive::new(reader).map_err(|e| self.error_context(e.into()))?;
+
+        let mut report = ImportReport::default();
+
+        let mut builder = self.builder()?;
+
+        let result = import_zip_entries(& mut builder, & mut zip, & mut report);
+
+        //Always replace the toc, even if the ZIP could not be read in full
+        builder.finalise()?;
+
+        result.map_err(|e| self.error_context(e))?;
+
+        Ok(report)
+    }
+
+    ///Export every file in the archive as a ZIP written to `writer`, and return the writer
+    ///
+    /// Files are deflated unless [`ExportOptions::without_compression`] is set, and are written in the order they are
+    /// stored in the archive, followed by a central directory built from the toc. The unix mode is stored in the external
+    /// attributes, and the modification time is stored both as an MS-DOS time (in UTC) and an extended timestamp. ZIP64
+    /// is used for files larger than 4GiB. ZIP has no hard links, so they are exported as copies of their target.
+    ///
+    /// Only the writer is written to, so it can be a pipe or stdout. Owners are not exported.
+    pub fn export_zip<W: Write>(&self, writer: W, options: &ExportOptions) -> Result<W> {
+        self.export_zip_entries(writer, options).map_err(|e| self.error_context(e))
+    }
+
+    fn export_zip_entries<W: Write>(&self, writer: W, options: &ExportOptions) -> Result<W> {
+
+        let mut zip = ZipWriter::new_stream(writer);
+
+        let mut entries = self.table()._table.iter().collect::<Vec<_>>();
+
+        entries.sort_by_key(|(_, offset)| **offset);
+
+        for (path, offset) in entries {
+            let entry = Entry::new(path.as_path(), *offset, self)?;
+
+            self.write_zip_entry(& mut zip, entry, options).map_err(|e| e.with_entry(path.as_path()))?;
+        }
+
+        Ok(zip.finish()?.into_inner())
+    }
+
+    ///Write a single file to a ZIP
+    fn write_zip_entry<W: Write + Seek>(&self, zip: & mut ZipWriter<W>, mut entry: Entry<'_, S>, options: &ExportOptions) -> Result<()> {
+
+        let metadata = entry.header().clone();
+
+        let name = zip_name(&options.export_path(entry.path()));
+
+        let mut file_options = FullFileOptions::default();
+
+        if let Some(mode) = metadata.mode() {
+            file_options = file_options.unix_permissions(mode & 0o7777);
+        } else if metadata.readonly() {
+            file_options = file_options.unix_permissions(if metadata.file_type() == FileType::Dir { 0o555 } else { 0o444 });
+        }
+
+        if let Some(modified) = metadata.modified() {
+            file_options = file_options.last_modified_time(dos_time(modified));
+
+            //The extended timestamp holds a signed 32 bit time, so later times are only kept as an MS-DOS time
+            if let Some(seconds) = modified.duration_since(UNIX_EPOCH).ok().and_then(|duration| i32::try_from(duration.as_secs()).ok()) {
+                let mut field = vec![1];
+
+                field.extend_from_slice(&seconds.to_le_bytes());
+
+                file_options.add_extra_data(EXTENDED_TIMESTAMP, field, false)?;
+            }
+        }
+
+        match metadata.file_type() {
+            FileType::Dir => zip.add_directory(name, file_options)?,
+            FileType::SystemLink => {
+                let target = metadata.link_target().map(|target| target.to_string_lossy().into_owned()).unwrap_or_default();
+
+                zip.add_symlink(name, target, file_options)?;
+            }
+            FileType::File => {
+                zip.start_file(name, compressed(file_options, metadata.len(), options))?;
+
+                std::io::copy(& mut entry, zip)?;
+            }
+            FileType::HardLink => {
+                let target = metadata.link_target().unwrap_or_else(|| Path::new(""));
+
+                let mut target = self.get(target)?.ok_or_else(|| Error::new(ErrorKind::NotFound(target.to_path_buf()), format!("Hard link target ({}) does not exist in TOC", target.display())))?;
+
+                zip.start_file(name, compressed(file_options, target.header().len(), options))?;
+
+                std::io::copy(& mut target, zip)?;
+            }
+        }
+
+        Ok(())
+    }
+
+}
+
+fn import_zip_entries<S: Storage, R: Read + Seek>(builder: & mut Builder<'_, S>, zip: & mut ZipArchive<R>, report: & mut ImportReport) -> Result<()> {
+
+    //Paths that were added during this import, which are replaced by later entries with the same path
+    let mut imported = HashSet::new();
+
+    for index in 0..zip.len() {
+
+        let name = PathBuf::from(zip.name_for_index(index).unwrap_or_default());
+
+        let mut file = match zip.by_index(index) {
+            Ok(file) => file,
+            Err(ZipError::UnsupportedArchive(reason)) => {
+                report.skipped.push((name, Error::new(ErrorKind::UnsupportedEntry(String::from(reason)), format!("ZIP member is not supported ({})", reason))));
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        //Rejects absolute paths as well as parent directories
+        let path = match file.enclosed_name() {
+            Some(path) => path,
+            None => {
+                report.skipped.push((name.clone(), Error::new(ErrorKind::UnsafePath(name.clone()), format!("Unsafe path ({}) found in ZIP", name.display()))));
+                continue;
+            }
+        };
+
+        let metadata = zip_metadata(& mut file)?;
+
+        let replacing = imported.contains(&path);
+
+        if replacing {
+            builder.replace(&path);
+        }
+
+        //Symbolic links were read to find the target, and have no data
+        let result = match metadata.file_type() {
+            FileType::File => builder.append_data(metadata, & mut file, &path),
+            _ => builder.append_data(metadata, std::io::empty(), &path),
+        };
+
+        match result {
+            Ok(()) => {
+                if !replacing {
+                    imported.insert(path.clone());
+                    report.imported.push(path);
+                }
+            }
+            Err(e) => match e.kind() {
+                ErrorKind::PathConflict(_) | ErrorKind::UnsafePath(_) => report.skipped.push((path, e)),
+                _ => return Err(e),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+///Convert the header of a ZIP member into metadata, reading the target of symbolic links
+fn zip_metadata<R: Read>(file: & mut zip::read::ZipFile<'_, R>) -> Result<Metadata> {
+
+    let mut metadata = if file.is_dir() {
+        Metadata::new(FileType::Dir, 0)
+    } else if file.is_symlink() {
+        let mut target = Vec::new();
+
+        file.read_to_end(& mut target)?;
+
+        Metadata::new(FileType::SystemLink, 0).with_link_target(bytes_path(target))
+    } else {
+        Metadata::new(FileType::File, file.size() as u128)
+    };
+
+    if let Some(mode) = file.unix_mode() {
+        metadata = metadata.with_mode(mode & 0o7777);
+    }
+
+    if let Some(modified) = file.last_modified().and_then(|time| system_time(&time)) {
+        metadata = metadata.with_modified(modified);
+    }
+
+    //The extended timestamp is more precise than the MS-DOS time, and is not affected by time zones
+    for field in file.extra_data_fields() {
+        if let ExtraField::ExtendedTimestamp(timestamp) = field {
+            if let Some(modified) = timestamp.mod_time() {
+                metadata = metadata.with_modified(UNIX_EPOCH + Duration::from_secs(modified as u64));
+            }
+
+            if let Some(accessed) = timestamp.ac_time() {
+                metadata = metadata.with_accessed(UNIX_EPOCH + Duration::from_secs(accessed as u64));
+            }
+        }
+    }
+
+    Ok(metadata)
+}
+
+///Use ZIP64 for large files, and deflate unless compression is turned off
+fn compressed<'k>(file_options: FullFileOptions<'k>, size: u128, options: &ExportOptions) -> FullFileOptions<'k> {
+    let method = if options.compression() { CompressionMethod::Deflated } else { CompressionMethod::Stored };
+
+    file_options.compression_method(method).large_file(size >= u32::MAX as u128)
+}
+
+///ZIP names are always separated by `/`
+fn zip_name(path: &Path) -> String {
+    path.components().map(|component| component.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/")
+}
+
+#[cfg(unix)]
+fn bytes_path(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+
+    PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn bytes_path(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+///Convert an MS-DOS time to a system time, assuming it is in UTC
+fn system_time(time: &DateTime) -> Option<SystemTime> {
+    if !time.is_valid() {
+        return None;
+    }
+
+    let days = days_from_civil(time.year() as i64, time.month() as i64, time.day() as i64);
+
+    let seconds = days * 86400 + time.hour() as i64 * 3600 + time.minute() as i64 * 60 + time.second() as i64;
+
+    UNIX_EPOCH.checked_add(Duration::from_secs(u64::try_from(seconds).ok()?))
+}
+
+///Convert a system time to an MS-DOS time in UTC, which only has two second precision and covers 1980 to 2107
+fn dos_time(time: SystemTime) -> DateTime {
+    let seconds = time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+
+    let time = seconds % 86400;
+
+    u16::try_from(year).ok()
+        .and_then(|year| DateTime::from_date_and_time(year, month as u8, day as u8, (time / 3600) as u8, (time / 60 % 60) as u8, (time % 60) as u8).ok())
+        .unwrap_or_default()
+}
+
+//See http://howardhinnant.github.io/date_algorithms.html for the calendar conversions
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+
+    (yoe + era * 400 + if month <= 2 { 1 } else { 0 }, month, day)
+}
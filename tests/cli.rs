@@ -0,0 +1,365 @@
+use std::path::{Path, PathBuf};
+use assert_cmd::Command;
+
+///A directory for one test, containing `files/a` and `files/sub/b`, which is removed when dropped
+struct TestDir(PathBuf);
+
+impl TestDir {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("tarpdate-cli-{}-{}", name, std::process::id()));
+
+        let _ = std::fs::remove_dir_all(&dir);
+
+        std::fs::create_dir_all(dir.join("files/sub")).unwrap();
+
+        std::fs::write(dir.join("files/a"), b"first file").unwrap();
+        std::fs::write(dir.join("files/sub/b"), b"second file").unwrap();
+
+        TestDir(dir)
+    }
+
+    fn path(&self, path: &str) -> PathBuf {
+        self.0.join(path)
+    }
+
+    ///Run tarpdate in this directory
+    fn tarpdate(&self, args: &[&str]) -> assert_cmd::assert::Assert {
+        Command::new(env!("CARGO_BIN_EXE_tarpdate")).current_dir(&self.0).args(args).assert()
+    }
+
+    ///Run tarpdate in this directory, check that it succeeds, and return its stdout
+    fn stdout(&self, args: &[&str]) -> String {
+        String::from_utf8(self.tarpdate(args).success().get_output().stdout.clone()).unwrap()
+    }
+
+    ///The paths listed in an archive, sorted
+    fn list(&self, archive: &str) -> Vec<String> {
+        let mut paths = self.stdout(&["list", archive]).lines().map(String::from).collect::<Vec<_>>();
+
+        paths.sort();
+
+        paths
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(& mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn read(path: &Path) -> Vec<u8> {
+    std::fs::read(path).unwrap()
+}
+
+#[test]
+fn create_add_list_cat() {
+    let dir = TestDir::new("create");
+
+    dir.tarpdate(&["create", "archive.t", "files/a"]).success();
+
+    assert_eq!(dir.list("archive.t"), ["files/a"]);
+
+    dir.tarpdate(&["add", "archive.t", "files/sub"]).success();
+
+    assert_eq!(dir.list("archive.t"), ["files/a", "files/sub/b"]);
+
+    assert!(dir.stdout(&["list", "--long", "archive.t"]).contains("files/sub/b"));
+    assert!(dir.stdout(&["list", "--json", "archive.t"]).contains("\"files/a\""));
+
+    assert_eq!(dir.stdout(&["cat", "archive.t", "files/sub/b"]), "second file");
+
+    //Adding a path that is already in the archive is a path error
+    dir.tarpdate(&["add", "archive.t", "files/a"]).code(4);
+
+    //Streamed archives are written to stdout and read from stdin
+    let streamed = dir.tarpdate(&["create", "-", "files"]).success().get_output().stdout.clone();
+
+    let listed = Command::new(env!("CARGO_BIN_EXE_tarpdate")).current_dir(&dir.0).args(["list", "-"]).write_stdin(streamed).assert().success();
+
+    assert_eq!(String::from_utf8_lossy(&listed.get_output().stdout).lines().count(), 2);
+
+    //procfs files report a size of 0, so the length of a file must come from the same read as its data. The same entries are
+    //then read from the stream and through the toc, and the files after it are intact
+    #[cfg(target_os = "linux")]
+    {
+        let streamed = dir.tarpdate(&["create", "-", "/proc/self/status", "files"]).success().get_output().stdout.clone();
+
+        std::fs::write(dir.path("streamed.t"), &streamed).unwrap();
+
+        let listed = Command::new(env!("CARGO_BIN_EXE_tarpdate")).current_dir(&dir.0).args(["list", "-"]).write_stdin(streamed).assert().success();
+
+        let mut from_stream = String::from_utf8_lossy(&listed.get_output().stdout).lines().map(String::from).collect::<Vec<_>>();
+
+        from_stream.sort();
+
+        assert_eq!(from_stream, dir.list("streamed.t"));
+        assert_eq!(from_stream, ["files/a", "files/sub/b", "proc/self/status"]);
+
+        assert_eq!(dir.stdout(&["cat", "streamed.t", "files/a"]), "first file");
+        assert_eq!(dir.stdout(&["cat", "streamed.t", "files/sub/b"]), "second file");
+    }
+
+    //Volumes are found from the first
+    dir.tarpdate(&["create", "--volume-size", "100", "split.t", "files"]).success();
+
+    assert!(dir.path("split.t.001").exists());
+    assert!(dir.stdout(&["info", "split.t"]).contains("Volumes:"));
+    assert_eq!(dir.stdout(&["cat", "split.t", "files/sub/b"]), "second file");
+
+    //Options that cannot be used together are usage errors
+    dir.tarpdate(&["create", "--volume-size", "100", "-", "files"]).code(2);
+    dir.tarpdate(&["create", "archive.t"]).code(2);
+}
+
+#[test]
+fn extract() {
+    let dir = TestDir::new("extract");
+
+    dir.tarpdate(&["create", "archive.t", "files"]).success();
+
+    dir.tarpdate(&["extract", "archive.t", "-C", "out"]).success();
+
+    assert_eq!(read(&dir.path("out/files/a")), b"first file");
+    assert_eq!(read(&dir.path("out/files/sub/b")), b"second file");
+
+    dir.tarpdate(&["extract", "archive.t", "files/sub", "-C", "some"]).success();
+
+    assert!(dir.path("some/files/sub/b").exists());
+    assert!(!dir.path("some/files/a").exists());
+
+    //Extracting below a file that is in the way is an I/O error
+    dir.tarpdate(&["extract", "archive.t", "-C", "files/a"]).code(1);
+}
+
+#[test]
+fn rm_mv() {
+    let dir = TestDir::new("rm");
+
+    dir.tarpdate(&["create", "archive.t", "files"]).success();
+
+    dir.tarpdate(&["mv", "archive.t", "files/a", "files/c"]).success();
+
+    assert_eq!(dir.list("archive.t"), ["files/c", "files/sub/b"]);
+
+    dir.tarpdate(&["mv", "archive.t", "files/c", "files/sub/b"]).code(4);
+    dir.tarpdate(&["mv", "archive.t", "files/a", "files/d"]).code(3);
+
+    //Nothing is removed if any path does not exist
+    dir.tarpdate(&["rm", "archive.t", "files/c", "files/a"]).code(3);
+
+    assert_eq!(dir.list("archive.t"), ["files/c", "files/sub/b"]);
+
+    dir.tarpdate(&["rm", "archive.t", "files/c", "files/sub/b"]).success();
+
+    assert!(dir.list("archive.t").is_empty());
+}
+
+#[test]
+fn info_verify_defrag() {
+    let dir = TestDir::new("info");
+
+    dir.tarpdate(&["create", "archive.t", "files"]).success();
+
+    let info = dir.stdout(&["info", "archive.t"]);
+
+    assert!(info.contains("Files:       2"));
+    assert!(info.contains("Encrypted:   no"));
+
+    assert_eq!(dir.stdout(&["verify", "archive.t"]), "2 files verified\n");
+
+    dir.tarpdate(&["rm", "archive.t", "files/a"]).success();
+
+    let reclaimed = dir.stdout(&["defrag", "archive.t"]);
+
+    assert!(reclaimed.starts_with("Reclaimed ") && reclaimed != "Reclaimed 0 bytes\n");
+
+    assert_eq!(dir.stdout(&["verify", "archive.t"]), "1 files verified\n");
+}
+
+#[test]
+fn sync_diff() {
+    let dir = TestDir::new("sync");
+
+    dir.tarpdate(&["create", "archive.t", "files/a"]).success();
+
+    dir.tarpdate(&["diff", "archive.t", "files", "--prefix", "files"]).code(2);
+
+    //Comparing with a directory treats it as the place the archive was extracted
+    dir.tarpdate(&["sync", "archive.t", "files", "--prefix", "files", "--delete"]).success();
+
+    dir.tarpdate(&["extract", "archive.t", "-C", "out"]).success();
+
+    dir.tarpdate(&["diff", "archive.t", "out"]).success();
+
+    std::fs::write(dir.path("out/files/a"), b"changed").unwrap();
+
+    dir.tarpdate(&["diff", "archive.t", "out"]).code(7);
+
+    //The current directory is compared when no other is given
+    std::fs::write(dir.path("files/a"), b"changed").unwrap();
+
+    dir.tarpdate(&["diff", "archive.t"]).code(7);
+
+    assert!(dir.stdout(&["sync", "archive.t", "files", "--prefix", "files", "--hash"]).starts_with("Added 0, updated 1"));
+
+    dir.tarpdate(&["extract", "archive.t", "-C", "new"]).success();
+
+    assert!(dir.stdout(&["diff", "archive.t", "new", "--json"]).contains("\"changed\": []"));
+
+    //Comparing two archives
+    dir.tarpdate(&["create", "other.t", "files/a"]).success();
+
+    dir.tarpdate(&["diff", "other.t", "archive.t", "--prefix", "files/a"]).success();
+    dir.tarpdate(&["diff", "other.t", "archive.t"]).code(7);
+}
+
+#[test]
+fn merge_subset() {
+    let dir = TestDir::new("merge");
+
+    dir.tarpdate(&["create", "first.t", "files/a"]).success();
+    dir.tarpdate(&["create", "second.t", "files"]).success();
+
+    dir.tarpdate(&["merge", "first.t", "second.t"]).code(4);
+
+    assert_eq!(dir.stdout(&["merge", "first.t", "second.t", "--on-conflict", "rename"]), "files/a renamed to files/a~\nAdded 1, replaced 0, renamed 1, skipped 0\n");
+
+    assert_eq!(dir.list("first.t"), ["files/a", "files/a~", "files/sub/b"]);
+
+    assert_eq!(dir.stdout(&["subset", "first.t", "sub.t", "--prefix", "files/sub"]), "Copied 1 files\n");
+    assert_eq!(dir.stdout(&["subset", "first.t", "glob.t", "--glob", "files/a*"]), "Copied 2 files\n");
+
+    assert_eq!(dir.list("sub.t"), ["files/sub/b"]);
+
+    dir.tarpdate(&["subset", "first.t", "none.t"]).code(2);
+}
+
+#[test]
+fn prune() {
+    let dir = TestDir::new("prune");
+
+    dir.tarpdate(&["create", "archive.t", "files/a"]).success();
+    dir.tarpdate(&["add", "archive.t", "files/sub"]).success();
+    dir.tarpdate(&["rm", "archive.t", "files/a"]).success();
+
+    let report = dir.stdout(&["prune", "archive.t", "--last", "1", "--dry-run"]);
+
+    assert!(report.contains("Would discard generation 1"));
+
+    assert_eq!(dir.stdout(&["prune", "archive.t", "--last", "1", "--dry-run"]), report);
+
+    assert!(dir.stdout(&["prune", "archive.t", "--last", "1"]).contains("Discarded generation 1"));
+
+    assert_eq!(dir.list("archive.t"), ["files/sub/b"]);
+}
+
+#[test]
+fn parity_repair() {
+    let dir = TestDir::new("parity");
+
+    dir.tarpdate(&["create", "archive.t", "files"]).success();
+
+    dir.tarpdate(&["parity", "archive.t", "--shard-size", "64", "-o", "archive.par"]).success();
+
+    //Damage a byte of file data, which the sidecar parity reconstructs
+    let mut bytes = read(&dir.path("archive.t"));
+    let original = bytes.clone();
+
+    let position = bytes.windows(10).position(|window| window == b"first file").unwrap();
+
+    bytes[position] ^= 0xff;
+
+    std::fs::write(dir.path("archive.t"), &bytes).unwrap();
+
+    let repaired = dir.stdout(&["repair", "archive.t", "--parity", "archive.par"]);
+
+    assert!(repaired.starts_with("Reconstructed 1 damaged byte ranges"));
+    assert!(repaired.ends_with("Recovered 2 files\n"));
+
+    assert_eq!(dir.stdout(&["cat", "archive.t", "files/a"]), "first file");
+
+    //Embedded parity is used without being given
+    std::fs::write(dir.path("embedded.t"), &original).unwrap();
+
+    dir.tarpdate(&["parity", "embedded.t", "--shard-size", "64"]).success();
+
+    dir.tarpdate(&["repair", "embedded.t"]).success();
+
+    dir.tarpdate(&["parity", "archive.t", "--overhead", "0"]).code(5);
+}
+
+#[test]
+fn convert_export() {
+    let dir = TestDir::new("convert");
+
+    dir.tarpdate(&["create", "archive.t", "files"]).success();
+
+    dir.tarpdate(&["export", "archive.t", "archive.tar"]).success();
+    dir.tarpdate(&["export", "-f", "zip", "archive.t", "archive.zip"]).success();
+
+    let tar = dir.tarpdate(&["export", "archive.t"]).success().get_output().stdout.clone();
+
+    assert_eq!(tar, read(&dir.path("archive.tar")));
+
+    dir.tarpdate(&["convert", "archive.tar", "from-tar.t"]).success();
+    dir.tarpdate(&["convert", "archive.zip", "from-zip.t"]).success();
+
+    assert_eq!(dir.stdout(&["cat", "from-tar.t", "files/a"]), "first file");
+    assert_eq!(dir.stdout(&["cat", "from-zip.t", "files/sub/b"]), "second file");
+
+    dir.tarpdate(&["convert", "files/a", "bad.t"]).code(1);
+}
+
+#[test]
+fn keygen_recipients() {
+    let dir = TestDir::new("keygen");
+
+    let recipient = |key: &str| {
+        let output = dir.tarpdate(&["keygen", key]).success().get_output().stderr.clone();
+
+        String::from_utf8(output).unwrap().trim().trim_start_matches("Recipient: ").to_string()
+    };
+
+    let alice = recipient("alice.key");
+    let bob = recipient("bob.key");
+
+    assert!(dir.stdout(&["keygen"]).contains("TARPDATE-IDENTITY-"));
+
+    //An existing identity is never overwritten
+    dir.tarpdate(&["keygen", "alice.key"]).code(1);
+
+    dir.tarpdate(&["create", "-r", &alice, "secret.t", "files"]).success();
+
+    dir.tarpdate(&["list", "secret.t"]).code(6);
+    dir.tarpdate(&["list", "-i", "bob.key", "secret.t"]).code(6);
+
+    assert_eq!(dir.stdout(&["cat", "-i", "alice.key", "secret.t", "files/a"]), "first file");
+
+    assert_eq!(dir.stdout(&["recipients", "-i", "alice.key", "secret.t", "--add", &bob]).lines().count(), 2);
+
+    assert_eq!(dir.stdout(&["recipients", "-i", "bob.key", "secret.t", "--remove", &alice]), format!("{}\n", bob));
+
+    dir.tarpdate(&["list", "-i", "alice.key", "secret.t"]).code(6);
+
+    //The last recipient cannot be removed
+    dir.tarpdate(&["recipients", "-i", "bob.key", "secret.t", "--remove", &bob]).code(6);
+
+    dir.tarpdate(&["create", "-r", &alice, "-", "files"]).code(2);
+}
+
+#[test]
+fn exit_codes() {
+    let dir = TestDir::new("exit");
+
+    dir.tarpdate(&["create", "archive.t", "files"]).success();
+
+    dir.tarpdate(&[]).code(2);
+    dir.tarpdate(&["list", "archive.t", "--unknown"]).code(2);
+
+    dir.tarpdate(&["list", "missing.t"]).code(3);
+    dir.tarpdate(&["cat", "archive.t", "files/missing"]).code(3);
+
+    dir.tarpdate(&["list", "files/a"]).code(5);
+    dir.tarpdate(&["verify", "files/a"]).code(5);
+}